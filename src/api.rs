@@ -0,0 +1,64 @@
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::messages::{DetectionResult, SubgroupResult};
+
+// The JSON API's request and response types, shared by the server and the client so the two can't drift apart. The
+// OpenAPI document is generated from them too.
+
+/// Accepted both as a form, from the index page, and as JSON by the API.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct CreateSessionRequest {
+    /// One or more addresses to send the result to, separated by commas, semicolons or whitespace.
+    pub email: String,
+    /// The language for emails and pages, e.g. "de". Negotiated from Accept-Language if missing.
+    pub locale: Option<String>,
+    /// An IANA time zone such as "Europe/Berlin", used for times in emails.
+    pub time_zone: Option<String>,
+    /// Keep the incoming messages in the archive for later debugging.
+    #[serde(default)]
+    pub archive: bool,
+    /// Add the result to each recipient's test history.
+    #[serde(default)]
+    pub history: bool,
+}
+
+/// What the API reports about a session. Anyone holding the webhook URL can read it.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct ApiSession {
+    pub id: Uuid,
+    /// Where the device should post its data. Includes the session's secret ingest token.
+    pub webhook_url: String,
+    /// The creator's read-only link to the live graph, unless it has been revoked.
+    pub viewer_url: Option<String>,
+    pub state: ApiSessionState,
+    /// How many readings have been received so far, or over the whole run once the test has finished.
+    pub samples: usize,
+    /// Only present once the test has finished.
+    pub result: Option<ApiResult>,
+    /// When the session will be removed unless more data arrives.
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires: Option<Timestamp>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiSessionState {
+    /// No recipient has confirmed their address yet, so data is rejected.
+    AwaitingVerification,
+    WaitingForData,
+    Running,
+    Completed,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct ApiResult {
+    pub overall: DetectionResult,
+    pub subgroups: Vec<SubgroupResult>,
+    #[schema(value_type = String, format = DateTime)]
+    pub completed: Timestamp,
+    /// Whether the result failed quality control or was invalid, so the test should be repeated.
+    pub needs_retest: bool,
+}
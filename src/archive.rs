@@ -0,0 +1,146 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use jiff::{Timestamp, civil::Date, tz::TimeZone};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::sessions::RawMessage;
+
+// Sessions which opt in have every incoming message appended to <dir>/<YYYY-MM-DD>/<session id>.jsonl, one record per
+// line in the same shape as LogWrapper (and /dump output), so a capture can be fed straight back into a replay.
+// Directories are per UTC day so that retention can be applied by deleting whole days.
+
+pub struct Archive {
+    dir: PathBuf,
+    retention: Duration,
+}
+
+impl Archive {
+    pub fn new(dir: &Path, retention: Duration) -> Result<Archive, io::Error> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Archive {
+            dir: dir.to_owned(),
+            retention,
+        })
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    pub fn append(&self, id: &Uuid, message: &RawMessage) -> Result<(), io::Error> {
+        let day_dir = self.dir.join(day(message.timestamp).to_string());
+        std::fs::create_dir_all(&day_dir)?;
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(day_dir.join(format!("{}.jsonl", id)))?
+            .write_all(&line)
+    }
+
+    /// Returns everything archived for the session, oldest first, or None if nothing was.
+    pub fn read(&self, id: &Uuid) -> Result<Option<Vec<u8>>, io::Error> {
+        let mut capture = None;
+        for (_, day_dir) in self.days()? {
+            match File::open(day_dir.join(format!("{}.jsonl", id))) {
+                Ok(mut file) => {
+                    file.read_to_end(capture.get_or_insert_with(Vec::new))?;
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(capture)
+    }
+
+    /// Deletes every day which ended more than the retention period before `now`.
+    pub fn prune(&self, now: Timestamp) -> Result<usize, io::Error> {
+        let cutoff = day(now.checked_sub(self.retention).unwrap_or(Timestamp::MIN));
+        let mut pruned = 0;
+        for (date, day_dir) in self.days()? {
+            if date < cutoff {
+                std::fs::remove_dir_all(&day_dir)?;
+                info!(dir = %day_dir.display(), "Pruned archived messages");
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    fn days(&self) -> Result<Vec<(Date, PathBuf)>, io::Error> {
+        let mut days = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            // Ignore anything which isn't one of our day directories, rather than risk deleting it.
+            if let Some(date) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<Date>().ok())
+                && entry.file_type()?.is_dir()
+            {
+                days.push((date, entry.path()));
+            }
+        }
+        days.sort();
+        Ok(days)
+    }
+}
+
+fn day(timestamp: Timestamp) -> Date {
+    timestamp.to_zoned(TimeZone::UTC).date()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use jiff::Timestamp;
+    use uuid::Uuid;
+
+    use super::Archive;
+    use crate::sessions::RawMessage;
+
+    #[test]
+    fn appends_reads_and_prunes_by_day() {
+        let dir = std::env::temp_dir().join(format!("archive-{}", Uuid::new_v4()));
+        let archive = Archive::new(&dir, Duration::from_secs(7 * 24 * 60 * 60)).unwrap();
+        let id = Uuid::new_v4();
+        let first: Timestamp = "2025-03-01T23:59:00Z".parse().unwrap();
+        let second: Timestamp = "2025-03-02T00:01:00Z".parse().unwrap();
+        for (timestamp, event) in [(first, "NEW_DATA"), (second, "TEST_FINISHED")] {
+            let message = RawMessage::new(timestamp, serde_json::json!({ "event": event }), 0);
+            archive.append(&id, &message).unwrap();
+        }
+        assert_eq!(None, archive.read(&Uuid::new_v4()).unwrap());
+        let capture = String::from_utf8(archive.read(&id).unwrap().unwrap()).unwrap();
+        assert_eq!(
+            vec![
+                r#"{"timestamp":"2025-03-01T23:59:00Z","message":{"event":"NEW_DATA"}}"#,
+                r#"{"timestamp":"2025-03-02T00:01:00Z","message":{"event":"TEST_FINISHED"}}"#,
+            ],
+            capture.lines().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            1,
+            archive
+                .prune("2025-03-09T12:00:00Z".parse().unwrap())
+                .unwrap()
+        );
+        assert!(
+            archive
+                .read(&id)
+                .unwrap()
+                .unwrap()
+                .ends_with(b"\"TEST_FINISHED\"}}\n")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use email_address::EmailAddress;
+use jiff::Timestamp;
+use uuid::Uuid;
+
+use crate::{
+    i18n::UserLocale,
+    messages::DetectionResult,
+    tokens::{random_token, tokens_match},
+};
+
+// A batch is a set of sessions created together, e.g. for a class or a lab running many tests at once. Each test is an
+// ordinary session with its own recipient; the batch just remembers which sessions belong to it, so a coordinator can
+// watch them all on one dashboard, print a sheet of webhook QR codes, and get one summary email once every test has
+// finished or expired.
+//
+// Batches are only kept in memory. Sessions restored after a restart carry on as usual, but are no longer part of a
+// batch.
+
+pub const MAX_BATCH_SIZE: usize = 50;
+
+#[derive(Clone)]
+pub struct Batch {
+    pub id: Uuid,
+    /// Grants access to the dashboard and the sheet, which includes every test's ingest token.
+    pub token: String,
+    pub coordinator: EmailAddress,
+    pub locale: UserLocale,
+    pub created: Timestamp,
+    pub tests: Vec<BatchTest>,
+}
+
+#[derive(Clone)]
+pub struct BatchTest {
+    pub label: String,
+    pub recipient: EmailAddress,
+    pub session_id: Uuid,
+    pub ingest_token: String,
+    pub viewer_token: String,
+    /// None until the test finishes or expires.
+    pub outcome: Option<BatchOutcome>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchOutcome {
+    Completed(DetectionResult),
+    Retest,
+    Expired,
+}
+
+impl Batch {
+    pub fn new(coordinator: EmailAddress, locale: UserLocale, tests: Vec<BatchTest>) -> Batch {
+        Batch {
+            id: Uuid::new_v4(),
+            token: random_token(),
+            coordinator,
+            locale,
+            created: Timestamp::now(),
+            tests,
+        }
+    }
+
+    pub fn token_matches(&self, token: &str) -> bool {
+        tokens_match(token, &self.token)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.tests.iter().all(|test| test.outcome.is_some())
+    }
+}
+
+#[derive(Default)]
+pub struct Batches {
+    batches: HashMap<Uuid, Batch>,
+}
+
+impl Batches {
+    pub fn get(&self, id: &Uuid) -> Option<&Batch> {
+        self.batches.get(id)
+    }
+
+    pub fn insert(&mut self, batch: Batch) {
+        self.batches.insert(batch.id, batch);
+    }
+
+    pub fn remove(&mut self, id: &Uuid) -> Option<Batch> {
+        self.batches.remove(id)
+    }
+
+    /// Records how one of a batch's sessions ended, returning the batch if that was the last test it was waiting for.
+    pub fn finish(&mut self, session_id: &Uuid, outcome: BatchOutcome) -> Option<&Batch> {
+        let batch = self.batches.values_mut().find(|batch| {
+            batch
+                .tests
+                .iter()
+                .any(|test| &test.session_id == session_id)
+        })?;
+        let test = batch
+            .tests
+            .iter_mut()
+            .find(|test| &test.session_id == session_id)?;
+        if test.outcome.is_some() {
+            return None;
+        }
+        test.outcome = Some(outcome);
+        batch.is_finished().then_some(&*batch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use email_address::EmailAddress;
+    use uuid::Uuid;
+
+    use super::{Batch, BatchOutcome, BatchTest, Batches};
+    use crate::{i18n::UserLocale, messages::DetectionResult};
+
+    fn test(label: &str) -> BatchTest {
+        BatchTest {
+            label: label.to_owned(),
+            recipient: "a@example.com".parse::<EmailAddress>().unwrap(),
+            session_id: Uuid::new_v4(),
+            ingest_token: "ingest".to_owned(),
+            viewer_token: "viewer".to_owned(),
+            outcome: None,
+        }
+    }
+
+    #[test]
+    fn batches_finish_once_every_test_has_an_outcome() {
+        let batch = Batch::new(
+            "coordinator@example.com".parse().unwrap(),
+            UserLocale::new("en".to_owned(), None),
+            vec![test("Alice"), test("Bob")],
+        );
+        let (first, second) = (batch.tests[0].session_id, batch.tests[1].session_id);
+        let mut batches = Batches::default();
+        batches.insert(batch);
+
+        assert!(
+            batches
+                .finish(&first, BatchOutcome::Completed(DetectionResult::Negative))
+                .is_none()
+        );
+        assert!(
+            batches
+                .finish(&Uuid::new_v4(), BatchOutcome::Expired)
+                .is_none()
+        );
+        let finished = batches.finish(&second, BatchOutcome::Expired).unwrap();
+        assert_eq!(
+            vec![
+                Some(BatchOutcome::Completed(DetectionResult::Negative)),
+                Some(BatchOutcome::Expired)
+            ],
+            finished
+                .tests
+                .iter()
+                .map(|test| test.outcome)
+                .collect::<Vec<_>>()
+        );
+        // A late expiry of an already finished test mustn't send the summary again.
+        assert!(batches.finish(&second, BatchOutcome::Expired).is_none());
+    }
+}
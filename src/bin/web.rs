@@ -1,29 +1,55 @@
-use std::{collections::BTreeMap, net::Ipv4Addr};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
 
 use askama::Template;
 use axum::{
     Form, Json, Router,
-    extract::{Path, WebSocketUpgrade},
-    http::StatusCode,
-    response::{Html, IntoResponse},
-    routing::{any, get, post},
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, Path, Query, WebSocketUpgrade},
+    http::{
+        HeaderMap, StatusCode,
+        header::{ACCEPT_LANGUAGE, AUTHORIZATION, RETRY_AFTER},
+    },
+    response::{Html, IntoResponse, Response},
+    routing::{any, delete, get, post},
 };
 use axum_embed::ServeEmbed;
+use clap::Parser;
 use dotenv::dotenv;
 use email_address::EmailAddress;
-use jiff::Timestamp;
+use jiff::{Timestamp, tz::TimeZone};
 use pluslife_notifier::{
+    Error,
+    api::{ApiResult, ApiSession, ApiSessionState, CreateSessionRequest},
+    archive::Archive,
+    batches::{Batch, BatchOutcome, MAX_BATCH_SIZE},
+    config::{Config, ConfigArgs},
     graph,
-    messages::Message,
-    notifier::{notify, notify_error},
-    sessions::{ServerState, Session},
-    state::State,
+    history::{self, History, HistoryRecord},
+    i18n::{DEFAULT_LANGUAGE, Messages, UserLocale},
+    messages::{Device, Message, SUPPORTED_VERSIONS},
+    qr,
+    sessions::{
+        DumpMode, NewSession, RawMessage, ServerState, Session, StateSummary, Viewer,
+        parse_recipients,
+    },
+    state::{CompletedTest, State},
+    telemetry::Telemetry,
+    tokens::tokens_match,
 };
 use rust_embed::RustEmbed;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tower_http::cors::{Any, CorsLayer};
-use tracing::{error, info, trace};
-use tracing_subscriber::{Layer, layer::SubscriberExt, util::SubscriberInitExt};
+use tower_http::trace::TraceLayer;
+use tracing::{error, info, info_span, trace, warn};
+use utoipa::{
+    Modify, OpenApi,
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
 use uuid::Uuid;
 
 #[derive(RustEmbed, Clone)]
@@ -38,19 +64,96 @@ async fn main() {
         panic!("Error loading .env file: {}", err);
     }
 
-    let stderr_log_level = tracing_subscriber::filter::LevelFilter::INFO;
-    let stderr_layer = tracing_subscriber::fmt::layer()
-        .pretty()
-        .with_writer(std::io::stderr);
+    let args = ConfigArgs::parse();
+    let config = Config::load(&args).unwrap_or_else(|errors| exit_with_config_errors(errors));
+    // Before anything else, so messages logged while loading panels and other files aren't lost.
+    let telemetry = Telemetry::init(&config.telemetry).expect("Failed to configure logging");
+    if args.check_config {
+        // Only check, as building the server state would create the files it's configured to use.
+        if let Err(errors) = ServerState::check_config(&config) {
+            exit_with_config_errors(errors);
+        }
+        println!("Configuration OK");
+        telemetry.shutdown();
+        return;
+    }
+    let server_state =
+        ServerState::from_config(&config).unwrap_or_else(|err| exit_with_config_errors(vec![err]));
+
+    info!(
+        base_url = server_state.base_url,
+        bind_address = %config.bind_address,
+        port = config.port,
+        "Starting server"
+    );
+
+    match server_state.restore_sessions() {
+        Ok(0) => {}
+        Ok(count) => info!(count, "Restored sessions"),
+        Err(err) => error!(?err, "Error restoring sessions"),
+    }
+    server_state.spawn_expiry();
+    server_state.spawn_archive_pruning();
+    server_state.spawn_history_pruning();
+    let app = app(server_state.clone());
+
+    let listener = tokio::net::TcpListener::bind((config.bind_address, config.port))
+        .await
+        .unwrap();
+    let shutdown_state = server_state.clone();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        info!("Shutting down");
+        shutdown_state.begin_shutdown().await;
+    })
+    .await
+    .unwrap();
+    server_state.finish_shutdown(config.shutdown_timeout).await;
+
+    telemetry.shutdown();
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
 
-    tracing_subscriber::registry()
-        .with(stderr_layer.with_filter(stderr_log_level))
-        .try_init()
-        .expect("Failed to configure logging");
+fn exit_with_config_errors(errors: Vec<Error>) -> ! {
+    eprintln!("Invalid configuration:");
+    for err in errors {
+        eprintln!("  {}", describe_config_error(&err));
+    }
+    std::process::exit(1);
+}
 
-    let port = std::env::var("PORT").expect("Expected PORT to be set");
-    let port = port.parse::<u16>().expect("Expected PORT to be a number");
+fn describe_config_error(err: &Error) -> String {
+    match err {
+        Error::InvalidConfig { name, cause } => format!("{}: {}", name, cause),
+        err => format!("{:?}", err),
+    }
+}
 
+fn app(server_state: ServerState) -> Router {
     let static_assets = ServeEmbed::<StaticAssets>::new();
 
     let cors = CorsLayer::new()
@@ -58,263 +161,1832 @@ async fn main() {
         .allow_headers(Any)
         .allow_origin(Any);
 
-    let server_state = ServerState::try_from_env().expect("Failed to read config from env");
+    let body_limit = DefaultBodyLimit::max(server_state.max_data_body_bytes);
 
-    info!(base_url = server_state.base_url, port, "Starting server");
-
-    let app = Router::new()
+    Router::new()
         .route("/health", get(|| async { "ok" }))
+        .route("/", get(index))
+        .route("/index.html", get(index))
+        .route("/privacy.html", get(privacy))
         .route("/session/create", post(create_session))
-        .route("/session/{id}/data", post(receive_data))
-        .route("/session/{id}/data", get(get_data_dummy))
-        .route("/session/{id}/graph.png", get(generate_graph_image))
-        .route("/session/{id}/graph", get(live_graph))
-        .route("/session/{id}/updates", any(handle_websocket_request))
-        .route("/dump", post(print_json_data))
+        .route("/api/v1/sessions", post(api_create_session))
+        .route("/api/v1/sessions/{id}", get(api_session_status))
+        .route("/api/v1/openapi.json", get(openapi))
+        .route("/batch/create", post(create_batch))
+        .route("/batch/{id}/view/{token}", get(view_batch))
+        .route("/batch/{id}/view/{token}/sheet", get(batch_sheet))
+        .route("/history", get(history_request))
+        .route("/history", post(send_history_link))
+        .route("/history/{token}", get(view_history))
+        .route("/history/{token}/export.csv", get(export_history))
+        .route("/history/{token}/delete", post(delete_history))
+        .route(
+            "/session/{id}/data/{token}",
+            post(receive_data).layer(body_limit),
+        )
+        .route("/session/{id}/data/{token}", get(get_data_dummy))
+        .route("/session/{id}/data", post(missing_ingest_token))
+        .route("/session/{id}/recipients", get(list_recipients))
+        .route("/session/{id}/recipients", post(add_recipient))
+        .route("/session/{id}/recipients/{email}", delete(remove_recipient))
+        .route("/session/{id}/verify/{token}", get(verify_recipient))
+        .route("/session/{id}/viewers", get(list_viewers))
+        .route("/session/{id}/viewers", post(add_viewer))
+        .route("/session/{id}/viewers/{token}", delete(revoke_viewer))
+        .route("/session/{id}/view/{token}", get(live_graph))
+        .route(
+            "/session/{id}/view/{token}/graph.png",
+            get(generate_graph_image),
+        )
+        .route(
+            "/session/{id}/view/{token}/updates",
+            any(handle_websocket_request),
+        )
+        .route("/dump", post(print_json_data).layer(body_limit))
         .route("/sessions/count", get(count_sessions))
+        .route("/metrics", get(metrics))
+        .route("/admin", get(admin))
+        .route("/admin/api/sessions", get(admin_list_sessions))
+        .route(
+            "/admin/api/sessions/{id}/expire",
+            post(admin_expire_session),
+        )
+        .route("/admin/api/sessions/{id}/resend", post(admin_resend_result))
+        .route(
+            "/admin/api/sessions/{id}/messages",
+            get(admin_download_messages),
+        )
+        .route("/admin/api/archive/{id}", get(admin_download_archive))
+        .route("/admin/api/devices", get(admin_list_devices))
         .layer(cors)
+        .layer(TraceLayer::new_for_http())
         .with_state(server_state)
-        .fallback_service(static_assets);
+        .fallback_service(static_assets)
+}
 
-    let listener = tokio::net::TcpListener::bind((Ipv4Addr::from_octets([0, 0, 0, 0]), port))
-        .await
-        .unwrap();
-    axum::serve(listener, app).await.unwrap();
+fn client_ip(
+    server_state: &ServerState,
+    connect_info: &ConnectInfo<SocketAddr>,
+    headers: &HeaderMap,
+) -> IpAddr {
+    // Behind a reverse proxy every connection comes from the proxy, which appends the real client to X-Forwarded-For.
+    if server_state.trust_forwarded_for
+        && let Some(forwarded_ip) = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse().ok())
+            .next_back()
+    {
+        return forwarded_ip;
+    }
+    // On a dual-stack IPv6 listener, IPv4 clients appear as IPv4-mapped IPv6 addresses.
+    connect_info.0.ip().to_canonical()
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn is_admin(server_state: &ServerState, headers: &HeaderMap) -> bool {
+    let Some(admin_token) = &server_state.admin_token else {
+        return false;
+    };
+    bearer_token(headers).is_some_and(|token| tokens_match(token, admin_token))
+}
+
+/// The admin area only exists if an admin token has been configured.
+fn check_admin(
+    server_state: &ServerState,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, &'static str)> {
+    if server_state.admin_token.is_none() {
+        Err((StatusCode::NOT_FOUND, "Not found"))
+    } else if is_admin(server_state, headers) {
+        Ok(())
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Unauthorized"))
+    }
 }
 
 #[derive(Deserialize)]
-struct CreateSessionRequest {
-    email: EmailAddress,
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Checks that the caller holds the session's ingest token, either as a bearer token or a `token` query parameter.
+fn check_session_token(
+    server_state: &ServerState,
+    id: &Uuid,
+    headers: &HeaderMap,
+    query: &TokenQuery,
+) -> Result<(), (StatusCode, &'static str)> {
+    let sessions = server_state.sessions.lock().unwrap();
+    let Some(session) = sessions.get(id) else {
+        return Err((StatusCode::NOT_FOUND, "Unknown ID"));
+    };
+    let token = bearer_token(headers).or(query.token.as_deref());
+    if token.is_some_and(|token| session.ingest_token_matches(token)) {
+        Ok(())
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Missing or invalid session token"))
+    }
+}
+
+/// Checks that the viewer token grants access to the session's live graph.
+/// Unknown sessions and invalid, expired or revoked tokens are indistinguishable to the caller.
+fn check_viewer_token(
+    server_state: &ServerState,
+    id: &Uuid,
+    token: &str,
+) -> Result<(), (StatusCode, &'static str)> {
+    let sessions = server_state.sessions.lock().unwrap();
+    if sessions
+        .get(id)
+        .is_some_and(|session| session.viewer_token_matches(token))
+    {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            "This link was not recognised. It may have expired or been revoked, or the test may have already finished.",
+        ))
+    }
+}
+
+fn rejected_session_creation(err: Error) -> Response {
+    match err {
+        Error::InvalidEmail(_) | Error::NoRecipients | Error::TooManyRecipients(_) => (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid email addresses: {:?}", err),
+        )
+            .into_response(),
+        Error::RateLimited(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, retry_after.as_secs().max(1).to_string())],
+            "Too many sessions have been created recently. Please try again later.",
+        )
+            .into_response(),
+        Error::OverRateLimitCapacity(capacity) => (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "At most {} sessions can be created at once from one address",
+                capacity
+            ),
+        )
+            .into_response(),
+        Error::TooManyActiveSessions => (
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many tests are already running. Please wait for one to finish.",
+        )
+            .into_response(),
+        Error::ShuttingDown => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The server is restarting. Please try again in a minute.",
+        )
+            .into_response(),
+        err => {
+            error!(?err, "Error checking session creation");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Sorry, an error occurred",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LanguageQuery {
+    lang: Option<String>,
+}
+
+struct LanguageOption {
+    code: String,
+    name: String,
+    selected: bool,
+}
+
+#[derive(Template)]
+#[template(path = "index.html")]
+struct IndexResponse<'a> {
+    pub language: String,
+    pub languages: Vec<LanguageOption>,
+    pub archive_label: Option<String>,
+    pub history_label: Option<String>,
+    pub t: &'a Messages,
+}
+
+async fn index(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<LanguageQuery>,
+    headers: HeaderMap,
+) -> Html<String> {
+    let language = negotiate_language(&server_state, query.lang.as_deref(), &headers);
+    let t = server_state.catalogs.get(&language);
+    Html(
+        IndexResponse {
+            languages: server_state
+                .catalogs
+                .languages()
+                .map(|(code, name)| LanguageOption {
+                    code: code.to_owned(),
+                    name: name.to_owned(),
+                    selected: code == language,
+                })
+                .collect(),
+            archive_label: archive_days(&server_state)
+                .map(|days| t.index.archive_label.replace("{days}", &days)),
+            history_label: history_days(&server_state)
+                .map(|days| t.index.history_label.replace("{days}", &days)),
+            t,
+            language,
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+#[derive(Template)]
+#[template(path = "privacy.html")]
+struct PrivacyResponse<'a> {
+    pub language: String,
+    pub email_retention: String,
+    pub test_data_retention: String,
+    pub archive_retention: Option<String>,
+    pub history_retention: Option<String>,
+    pub t: &'a Messages,
+}
+
+async fn privacy(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<LanguageQuery>,
+    headers: HeaderMap,
+) -> Html<String> {
+    let language = negotiate_language(&server_state, query.lang.as_deref(), &headers);
+    let t = server_state.catalogs.get(&language);
+    Html(
+        PrivacyResponse {
+            email_retention: t
+                .privacy
+                .email_retention
+                .replace(
+                    "{completed}",
+                    &t.format_duration(server_state.completed_retention),
+                )
+                .replace(
+                    "{incomplete}",
+                    &t.format_duration(server_state.cleanup_period),
+                )
+                .replace(
+                    "{verification}",
+                    &t.format_duration(server_state.verification_memory),
+                ),
+            test_data_retention: t
+                .privacy
+                .test_data_retention
+                .replace(
+                    "{completed}",
+                    &t.format_duration(server_state.completed_retention),
+                )
+                .replace(
+                    "{incomplete}",
+                    &t.format_duration(server_state.cleanup_period),
+                ),
+            archive_retention: archive_days(&server_state)
+                .map(|days| t.privacy.archive_retention.replace("{days}", &days)),
+            history_retention: history_days(&server_state)
+                .map(|days| t.privacy.history_retention.replace("{days}", &days)),
+            t,
+            language,
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+/// How many days archived messages are kept for, or None if archiving isn't enabled.
+fn archive_days(server_state: &ServerState) -> Option<String> {
+    server_state
+        .archive
+        .as_ref()
+        .map(|archive| (archive.retention().as_secs().div_ceil(24 * 60 * 60)).to_string())
+}
+
+/// How many days test history is kept for, or None if history isn't enabled.
+fn history_days(server_state: &ServerState) -> Option<String> {
+    server_state.history.as_ref().map(|history| {
+        let retention = history.lock().unwrap().retention();
+        retention.as_secs().div_ceil(24 * 60 * 60).to_string()
+    })
+}
+
+fn negotiate_language(
+    server_state: &ServerState,
+    requested: Option<&str>,
+    headers: &HeaderMap,
+) -> String {
+    let accept_language = headers
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok());
+    server_state.catalogs.negotiate(requested, accept_language)
 }
 
 #[derive(Template)]
 #[template(path = "session-created.html")]
-struct CreateSessionResponse {
+struct CreateSessionResponse<'a> {
     pub base_url: String,
     pub id: Uuid,
-    pub email_to_notify: EmailAddress,
+    pub ingest_token: String,
+    pub recipients: String,
+    pub verification_pending: bool,
+    pub expires: String,
+    pub live_graph_link: String,
+    pub language: String,
+    pub t: &'a Messages,
+}
+
+struct StartedSession {
+    new_session: NewSession,
+    recipients: String,
+    locale: UserLocale,
+    verification_pending: bool,
+}
+
+/// Validates a request to create a session and creates it, for both the form and the API.
+fn start_session(
+    server_state: &ServerState,
+    connect_info: &ConnectInfo<SocketAddr>,
+    headers: &HeaderMap,
+    params: CreateSessionRequest,
+) -> Result<StartedSession, Error> {
+    let recipients = parse_recipients(&params.email)?;
+    let creator_ip = client_ip(server_state, connect_info, headers);
+    let language = negotiate_language(server_state, params.locale.as_deref(), headers);
+    let locale = UserLocale::new(language.clone(), params.time_zone.as_deref());
+    let recipient_list = recipients
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let new_session = server_state
+        .create_session(
+            recipients,
+            locale.clone(),
+            Some(creator_ip),
+            params.archive,
+            params.history,
+        )
+        .inspect_err(|err| {
+            server_state.metrics.error(err);
+            info!(%creator_ip, ?err, "Rejected session creation");
+        })?;
+    let id = new_session.id;
+    info!(%id, recipients = %recipient_list, %language, "Created session");
+    let verification_pending = server_state
+        .sessions
+        .lock()
+        .unwrap()
+        .get(&id)
+        .is_some_and(|session| !session.is_active());
+    Ok(StartedSession {
+        new_session,
+        recipients: recipient_list,
+        locale,
+        verification_pending,
+    })
 }
 
 async fn create_session(
     axum::extract::State(server_state): axum::extract::State<ServerState>,
+    connect_info: ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Form(params): Form<CreateSessionRequest>,
-) -> Html<String> {
-    let id = server_state.create_session(params.email.clone());
-    info!(%id, email = %params.email, "Created session");
+) -> Response {
+    let started = match start_session(&server_state, &connect_info, &headers, params) {
+        Ok(started) => started,
+        Err(err) => return rejected_session_creation(err),
+    };
+    let id = started.new_session.id;
+    let language = started.locale.language;
+    let t = server_state.catalogs.get(&language);
+    let expires = server_state
+        .expiry
+        .deadline(&id)
+        .unwrap_or_else(Timestamp::now);
     Html(
         CreateSessionResponse {
             id,
+            ingest_token: started.new_session.ingest_token,
             base_url: server_state.base_url.clone(),
-            email_to_notify: params.email,
+            recipients: started.recipients,
+            verification_pending: started.verification_pending,
+            expires: t.format_timestamp(expires, &started.locale.time_zone),
+            live_graph_link: t.session_created.live_graph_link.replace(
+                "{url}",
+                &format!(
+                    "{}/session/{}/view/{}",
+                    server_state.base_url, id, started.new_session.viewer_token
+                ),
+            ),
+            language,
+            t,
         }
         .render()
         .unwrap(),
     )
+    .into_response()
 }
 
-async fn receive_data(
-    Path(id): Path<Uuid>,
-    axum::extract::State(server_state): axum::extract::State<ServerState>,
-    Json(message): Json<Message>,
-) -> impl IntoResponse + Send {
-    let mut sessions = server_state.sessions.lock().unwrap();
-    if let Some(session) = sessions.remove(&id) {
-        let Session {
-            state,
-            created,
-            email_to_notify,
-            id,
-            websockets,
-        } = session;
-        let event = message.event;
-        let state = state.update(message, &websockets);
-        match state {
-            Ok(State::CompletedTest(completed_test)) => {
-                info!(%id, "Received results");
-                tokio::spawn(async move {
-                    let notify_result = notify(
-                        &server_state.sender_email,
-                        &server_state.mailgun_domain,
-                        &server_state.mailgun_api_key,
-                        completed_test,
-                        email_to_notify.clone(),
-                    )
-                    .await;
-                    if let Err(err) = notify_result {
-                        error!(?err, "Error notifying of result");
-                        let _ = notify_error(
-                            &server_state.sender_email,
-                            &server_state.mailgun_domain,
-                            &server_state.mailgun_api_key,
-                            &id,
-                            &format!("Error notifying of result: {:?}", err),
-                            email_to_notify,
-                        )
-                        .await;
-                    }
-                });
-                (StatusCode::OK, "Received")
-            }
-            Ok(state) => {
-                trace!(%id, %event, "Received updated data");
-                sessions.insert(
-                    id,
-                    Session {
-                        state,
-                        created,
-                        email_to_notify,
-                        id,
-                        websockets,
-                    },
-                );
-                (StatusCode::OK, "Received")
-            }
-            Err(err) => {
-                if let Some(state) = err.get_state() {
-                    error!(%id, ?err, recoverable = true, "Error processing data");
-                    sessions.insert(
-                        id,
-                        Session {
-                            state: state.clone(),
-                            created,
-                            email_to_notify,
-                            id,
-                            websockets,
-                        },
-                    );
-                } else {
-                    error!(%id, ?err, recoverable = false, "Error processing data");
-                    tokio::spawn(async move {
-                        let _ = notify_error(
-                            &server_state.sender_email,
-                            &server_state.mailgun_domain,
-                            &server_state.mailgun_api_key,
-                            &id,
-                            &format!("Irrecoverable error processing data: {:?}", err),
-                            email_to_notify,
-                        )
-                        .await;
-                    });
-                }
-                (StatusCode::BAD_REQUEST, "Failed to process data")
-            }
+fn api_session(server_state: &ServerState, session: &Session) -> ApiSession {
+    let (state, samples, result) = match &session.state {
+        State::CompletedTest(test) => (
+            ApiSessionState::Completed,
+            test.samples,
+            Some(ApiResult {
+                overall: test.overall,
+                subgroups: test.subgroup_results.clone(),
+                completed: test.completed,
+                needs_retest: test.needs_retest(),
+            }),
+        ),
+        State::IncompleteTest(test) => {
+            let samples = test.data.samples.len();
+            let state = if !session.is_active() {
+                ApiSessionState::AwaitingVerification
+            } else if samples == 0 {
+                ApiSessionState::WaitingForData
+            } else {
+                ApiSessionState::Running
+            };
+            (state, samples, None)
         }
-    } else {
-        error!(%id, "Received data for unknown ID");
-        (StatusCode::NOT_FOUND, "Unknown ID")
+    };
+    ApiSession {
+        id: session.id,
+        webhook_url: format!(
+            "{}/session/{}/data/{}",
+            server_state.base_url, session.id, session.ingest_token
+        ),
+        viewer_url: session.viewers.first().map(|viewer| {
+            format!(
+                "{}/session/{}/view/{}",
+                server_state.base_url, session.id, viewer.token
+            )
+        }),
+        state,
+        samples,
+        result,
+        expires: server_state.expiry.deadline(&session.id),
     }
 }
 
-async fn print_json_data(Json(payload): Json<serde_json::Value>) -> String {
-    let mut map = BTreeMap::new();
-    let timestamp = Timestamp::now();
-    map.insert(
-        "timestamp",
-        serde_json::Value::String(timestamp.to_string()),
-    );
-    map.insert("message", payload);
-    println!("{}", serde_json::to_string(&map).unwrap());
-    "Received".to_owned()
-}
-
-async fn generate_graph_image(
-    Path(id): Path<Uuid>,
+/// Creates a session, taking the same fields as the index page's form.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions",
+    request_body = CreateSessionRequest,
+    responses(
+        (status = 201, description = "The session was created", body = ApiSession),
+        (status = 400, description = "The email addresses were invalid", body = String),
+        (status = 429, description = "Too many sessions were created or are running", body = String),
+        (status = 503, description = "The server is shutting down", body = String),
+    )
+)]
+async fn api_create_session(
     axum::extract::State(server_state): axum::extract::State<ServerState>,
-) -> impl IntoResponse + Send {
+    connect_info: ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(params): Json<CreateSessionRequest>,
+) -> Response {
+    let started = match start_session(&server_state, &connect_info, &headers, params) {
+        Ok(started) => started,
+        Err(err) => return rejected_session_creation(err),
+    };
     let sessions = server_state.sessions.lock().unwrap();
-    if let Some(session) = sessions.get(&id) {
-        match session.state.current_graph_png() {
-            Ok(Some(bytes)) => (
-                StatusCode::OK,
-                [(axum::http::header::CONTENT_TYPE, "image/png")],
-                bytes,
-            ),
-            Ok(None) => {
-                (
-                    StatusCode::NOT_FOUND,
-                    [(axum::http::header::CONTENT_TYPE, "text/plain")],
-                    "No data has been received for this test yet. Please refresh the page when you expect there to be data.".as_bytes().to_owned(),
-                )
-            },
-            Err(err) => {
-                error!(?err, "Error generating graph for display");
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    [(axum::http::header::CONTENT_TYPE, "text/plain")],
-                    "Sorry, an error occurred".as_bytes().to_owned(),
-                )
-            }
-        }
-    } else {
-        (
-            StatusCode::NOT_FOUND,
-            [(axum::http::header::CONTENT_TYPE, "text/plain")],
-            "This test ID was not recognised. Either it has not been registered, or the test has already finished.".as_bytes().to_owned(),
+    match sessions.get(&started.new_session.id) {
+        Some(session) => (
+            StatusCode::CREATED,
+            Json(api_session(&server_state, session)),
         )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, "Unknown ID").into_response(),
     }
 }
 
-async fn get_data_dummy(
-    Path(id): Path<Uuid>,
+/// Reports a session's progress and, once it has finished, its result.
+#[utoipa::path(
+    get,
+    path = "/api/v1/sessions/{id}",
+    params(
+        ("id" = Uuid, Path, description = "The session ID"),
+        ("token" = Option<String>, Query, description = "The ingest token, if not sent as a bearer token"),
+    ),
+    security(("ingest_token" = [])),
+    responses(
+        (status = 200, description = "The session's status", body = ApiSession),
+        (status = 401, description = "The ingest token was missing or wrong", body = String),
+        (status = 404, description = "There is no such session, or it has expired", body = String),
+    )
+)]
+async fn api_session_status(
     axum::extract::State(server_state): axum::extract::State<ServerState>,
-) -> impl IntoResponse {
-    if server_state.sessions.lock().unwrap().get(&id).is_some() {
-        (
-            StatusCode::OK,
-            "This link is only intended to be used as a webhook. In the virus.sucks app, open 'Settings' and put it in the 'Webhook URL' field.",
-        )
-    } else {
-        (StatusCode::NOT_FOUND, "Unknown ID")
+    Path(id): Path<Uuid>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = check_session_token(&server_state, &id, &headers, &query) {
+        return rejection.into_response();
+    }
+    let sessions = server_state.sessions.lock().unwrap();
+    match sessions.get(&id) {
+        Some(session) => Json(api_session(&server_state, session)).into_response(),
+        None => (StatusCode::NOT_FOUND, "Unknown ID").into_response(),
     }
 }
 
-async fn count_sessions(
-    axum::extract::State(server_state): axum::extract::State<ServerState>,
-) -> impl IntoResponse + Send {
-    let sessions = server_state.sessions.lock().unwrap();
-    format!("{}", sessions.len())
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "PlusLife notifier API", version = "1"),
+    paths(api_create_session, api_session_status),
+    modifiers(&IngestTokenSecurity)
+)]
+struct ApiDoc;
+
+struct IngestTokenSecurity;
+
+impl Modify for IngestTokenSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "ingest_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
 }
 
-async fn handle_websocket_request(
-    ws: WebSocketUpgrade,
-    Path(id): Path<Uuid>,
+async fn openapi() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
+
+#[derive(Deserialize)]
+struct CreateBatchRequest {
+    coordinator: String,
+    locale: Option<String>,
+    time_zone: Option<String>,
+    tests: Vec<BatchTestRequest>,
+}
+
+#[derive(Deserialize)]
+struct BatchTestRequest {
+    label: String,
+    email: String,
+}
+
+#[derive(Serialize)]
+struct CreateBatchResponse {
+    id: Uuid,
+    dashboard_url: String,
+    sheet_url: String,
+    tests: Vec<CreatedBatchTest>,
+}
+
+#[derive(Serialize)]
+struct CreatedBatchTest {
+    label: String,
+    session_id: Uuid,
+    webhook_url: String,
+    live_graph_url: String,
+}
+
+async fn create_batch(
     axum::extract::State(server_state): axum::extract::State<ServerState>,
-) -> impl IntoResponse {
-    let (websockets, state) = {
-        let sessions = server_state.sessions.lock().unwrap();
-        if let Some(session) = sessions.get(&id) {
-            (session.websockets.clone(), session.state.clone())
-        } else {
-            return (StatusCode::NOT_FOUND, "Unknown ID").into_response();
+    connect_info: ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(params): Json<CreateBatchRequest>,
+) -> Response {
+    if params.tests.is_empty() || params.tests.len() > MAX_BATCH_SIZE {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("A batch must have between 1 and {} tests", MAX_BATCH_SIZE),
+        )
+            .into_response();
+    }
+    let Ok(coordinator) = params.coordinator.trim().parse::<EmailAddress>() else {
+        return (StatusCode::BAD_REQUEST, "Invalid coordinator email address").into_response();
+    };
+    let mut tests = Vec::with_capacity(params.tests.len());
+    for test in params.tests {
+        let label = test.label.trim();
+        if label.is_empty() {
+            return (StatusCode::BAD_REQUEST, "Every test needs a label").into_response();
+        }
+        match test.email.trim().parse::<EmailAddress>() {
+            Ok(email) => tests.push((label.to_owned(), email)),
+            Err(err) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid email address for {}: {:?}", label, err),
+                )
+                    .into_response();
+            }
+        }
+    }
+    let creator_ip = client_ip(&server_state, &connect_info, &headers);
+    let language = negotiate_language(&server_state, params.locale.as_deref(), &headers);
+    let locale = UserLocale::new(language, params.time_zone.as_deref());
+    let batch = match server_state.create_batch(coordinator, tests, locale, Some(creator_ip)) {
+        Ok(batch) => batch,
+        Err(err) => {
+            server_state.metrics.error(&err);
+            info!(%creator_ip, ?err, "Rejected batch creation");
+            return rejected_session_creation(err);
         }
     };
-    ws.on_upgrade(move |websocket| async move {
-        let (socket, websocket_count) = websockets.push(websocket);
-        info!(%id, websocket_count, "New websocket connected");
-        socket.notify(&state);
+    let dashboard_url = format!(
+        "{}/batch/{}/view/{}",
+        server_state.base_url, batch.id, batch.token
+    );
+    Json(CreateBatchResponse {
+        id: batch.id,
+        sheet_url: format!("{}/sheet", dashboard_url),
+        dashboard_url,
+        tests: batch
+            .tests
+            .iter()
+            .map(|test| CreatedBatchTest {
+                label: test.label.clone(),
+                session_id: test.session_id,
+                webhook_url: format!(
+                    "{}/session/{}/data/{}",
+                    server_state.base_url, test.session_id, test.ingest_token
+                ),
+                live_graph_url: format!(
+                    "{}/session/{}/view/{}",
+                    server_state.base_url, test.session_id, test.viewer_token
+                ),
+            })
+            .collect(),
     })
+    .into_response()
+}
+
+/// A copy of the batch, if the token grants access to it. Unknown batches and wrong tokens look the same.
+fn find_batch(server_state: &ServerState, id: &Uuid, token: &str) -> Option<Batch> {
+    server_state
+        .batches
+        .lock()
+        .unwrap()
+        .get(id)
+        .filter(|batch| batch.token_matches(token))
+        .cloned()
+}
+
+struct BatchRow {
+    label: String,
+    state: String,
+    live_graph_url: Option<String>,
 }
 
 #[derive(Template)]
-#[template(path = "graph.html")]
-struct LiveGraphResponse {
-    pub base_url: String,
-    pub id: Uuid,
-    pub graph_width: u32,
-    pub graph_height: u32,
+#[template(path = "batch.html")]
+struct BatchResponse<'a> {
+    pub heading: String,
+    pub rows: Vec<BatchRow>,
+    pub finished: Option<String>,
+    pub sheet_url: String,
+    pub language: String,
+    pub t: &'a Messages,
 }
 
-async fn live_graph(
+async fn view_batch(
     axum::extract::State(server_state): axum::extract::State<ServerState>,
-    Path(id): Path<Uuid>,
-) -> impl IntoResponse {
-    if server_state.sessions.lock().unwrap().get(&id).is_some() {
-        Html(
+    Path((id, token)): Path<(Uuid, String)>,
+) -> Response {
+    let Some(batch) = find_batch(&server_state, &id, &token) else {
+        return (StatusCode::NOT_FOUND, "Unknown batch").into_response();
+    };
+    let language = batch.locale.language.clone();
+    let t = server_state.catalogs.get(&language);
+    let now = Timestamp::now();
+    let sessions = server_state.sessions.lock().unwrap();
+    let rows = batch
+        .tests
+        .iter()
+        .map(|test| {
+            let summary = sessions
+                .get(&test.session_id)
+                .map(|session| session.summary(now).state);
+            let state = match (test.outcome, summary) {
+                (Some(BatchOutcome::Completed(result)), _)
+                | (
+                    None,
+                    Some(StateSummary::Completed {
+                        overall: result, ..
+                    }),
+                ) => t.result(result).to_owned(),
+                (Some(BatchOutcome::Retest), _) => t.batch.retest.clone(),
+                (Some(BatchOutcome::Expired), _) | (None, None) => t.batch.expired.clone(),
+                (None, Some(StateSummary::Incomplete { samples: 0 })) => t.batch.waiting.clone(),
+                (None, Some(StateSummary::Incomplete { samples })) => {
+                    t.batch.running.replace("{samples}", &samples.to_string())
+                }
+            };
+            BatchRow {
+                label: test.label.clone(),
+                state,
+                live_graph_url: test
+                    .outcome
+                    .is_none()
+                    .then(|| format!("/session/{}/view/{}", test.session_id, test.viewer_token)),
+            }
+        })
+        .collect();
+    drop(sessions);
+    Html(
+        BatchResponse {
+            heading: t
+                .batch
+                .heading
+                .replace("{count}", &batch.tests.len().to_string()),
+            rows,
+            finished: batch.is_finished().then(|| {
+                t.batch
+                    .finished
+                    .replace("{email}", batch.coordinator.as_str())
+            }),
+            sheet_url: format!("/batch/{}/view/{}/sheet", batch.id, batch.token),
+            language,
+            t,
+        }
+        .render()
+        .unwrap(),
+    )
+    .into_response()
+}
+
+struct BatchSheetEntry {
+    label: String,
+    webhook_url: String,
+    qr_svg: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "batch-sheet.html")]
+struct BatchSheetResponse<'a> {
+    pub entries: Vec<BatchSheetEntry>,
+    pub language: String,
+    pub t: &'a Messages,
+}
+
+async fn batch_sheet(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Path((id, token)): Path<(Uuid, String)>,
+) -> Response {
+    let Some(batch) = find_batch(&server_state, &id, &token) else {
+        return (StatusCode::NOT_FOUND, "Unknown batch").into_response();
+    };
+    let language = batch.locale.language.clone();
+    let entries = batch
+        .tests
+        .iter()
+        .map(|test| {
+            let webhook_url = format!(
+                "{}/session/{}/data/{}",
+                server_state.base_url, test.session_id, test.ingest_token
+            );
+            BatchSheetEntry {
+                label: test.label.clone(),
+                qr_svg: qr::to_svg(webhook_url.as_bytes()),
+                webhook_url,
+            }
+        })
+        .collect();
+    Html(
+        BatchSheetResponse {
+            entries,
+            t: server_state.catalogs.get(&language),
+            language,
+        }
+        .render()
+        .unwrap(),
+    )
+    .into_response()
+}
+
+#[derive(Template)]
+#[template(path = "history-request.html")]
+struct HistoryRequestResponse<'a> {
+    pub sent: bool,
+    pub language: String,
+    pub t: &'a Messages,
+}
+
+async fn history_request(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<LanguageQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if server_state.history.is_none() {
+        return (StatusCode::NOT_FOUND, "Test history is not enabled").into_response();
+    }
+    let language = negotiate_language(&server_state, query.lang.as_deref(), &headers);
+    Html(
+        HistoryRequestResponse {
+            sent: false,
+            t: server_state.catalogs.get(&language),
+            language,
+        }
+        .render()
+        .unwrap(),
+    )
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct HistoryLinkRequest {
+    email: EmailAddress,
+    locale: Option<String>,
+}
+
+/// Responds the same way whether or not the address has a history, so the form can't be used to find out.
+async fn send_history_link(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    headers: HeaderMap,
+    Form(params): Form<HistoryLinkRequest>,
+) -> Response {
+    if server_state.history.is_none() {
+        return (StatusCode::NOT_FOUND, "Test history is not enabled").into_response();
+    }
+    let language = negotiate_language(&server_state, params.locale.as_deref(), &headers);
+    let locale = UserLocale::new(language.clone(), None);
+    match server_state.send_history_link(params.email.clone(), locale) {
+        Ok(()) => info!(email = %params.email, "Requested history link"),
+        Err(Error::RateLimited(retry_after)) => {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.as_secs().max(1).to_string())],
+                "Too many links have been requested for this address recently. Please try again later.",
+            )
+                .into_response();
+        }
+        Err(err) => {
+            server_state.metrics.error(&err);
+            error!(email = %params.email, ?err, "Error sending history link");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Sorry, an error occurred",
+            )
+                .into_response();
+        }
+    }
+    Html(
+        HistoryRequestResponse {
+            sent: true,
+            t: server_state.catalogs.get(&language),
+            language,
+        }
+        .render()
+        .unwrap(),
+    )
+    .into_response()
+}
+
+struct HistoryRow {
+    pub completed: String,
+    pub serial_number: u64,
+    pub panel: String,
+    pub overall: String,
+    pub subgroups: String,
+}
+
+#[derive(Template)]
+#[template(path = "history.html")]
+struct HistoryResponse<'a> {
+    pub token: String,
+    pub showing: String,
+    pub rows: Vec<HistoryRow>,
+    pub deleted: bool,
+    pub language: String,
+    pub t: &'a Messages,
+}
+
+/// The history and address a magic link grants access to, or a response explaining the link is no good.
+fn redeem_history_link<'a>(
+    server_state: &'a ServerState,
+    token: &str,
+    t: &Messages,
+) -> Result<(&'a Mutex<History>, EmailAddress), (StatusCode, String)> {
+    let Some(history) = &server_state.history else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Test history is not enabled".to_owned(),
+        ));
+    };
+    let email = history.lock().unwrap().redeem_link(token).cloned();
+    match email {
+        Some(email) => Ok((history, email)),
+        None => {
+            info!("Invalid history link");
+            Err((StatusCode::NOT_FOUND, t.history.invalid_link.clone()))
+        }
+    }
+}
+
+async fn view_history(
+    Path(token): Path<String>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<LanguageQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let language = negotiate_language(&server_state, query.lang.as_deref(), &headers);
+    let t = server_state.catalogs.get(&language);
+    let (history, email) = match redeem_history_link(&server_state, &token, t) {
+        Ok(redeemed) => redeemed,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let rows = history
+        .lock()
+        .unwrap()
+        .records(&email)
+        .iter()
+        .rev()
+        .map(|record| HistoryRow {
+            completed: t.format_timestamp(
+                record.completed,
+                &record
+                    .time_zone
+                    .as_deref()
+                    .and_then(|name| TimeZone::get(name).ok())
+                    .unwrap_or(TimeZone::UTC),
+            ),
+            serial_number: record.serial_number,
+            panel: record.panel.clone().unwrap_or_default(),
+            overall: t.result(record.overall).to_owned(),
+            subgroups: record
+                .subgroup_results
+                .iter()
+                .map(|subgroup| {
+                    format!(
+                        "{}: {}",
+                        t.subgroup_name(&subgroup.name),
+                        t.result(subgroup.result)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        })
+        .collect();
+    Html(
+        HistoryResponse {
+            showing: t.history.showing.replace("{email}", email.as_str()),
+            token,
+            rows,
+            deleted: false,
+            language,
+            t,
+        }
+        .render()
+        .unwrap(),
+    )
+    .into_response()
+}
+
+async fn export_history(
+    Path(token): Path<String>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+) -> Response {
+    let t = server_state.catalogs.get(DEFAULT_LANGUAGE);
+    let (history, email) = match redeem_history_link(&server_state, &token, t) {
+        Ok(redeemed) => redeemed,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let csv = history::to_csv(history.lock().unwrap().records(&email));
+    (
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                "text/csv; charset=utf-8".to_owned(),
+            ),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                "attachment; filename=\"history.csv\"".to_owned(),
+            ),
+        ],
+        csv,
+    )
+        .into_response()
+}
+
+async fn delete_history(
+    Path(token): Path<String>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<LanguageQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let language = negotiate_language(&server_state, query.lang.as_deref(), &headers);
+    let t = server_state.catalogs.get(&language);
+    let email = match redeem_history_link(&server_state, &token, t) {
+        Ok((_, email)) => email,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let deleted = {
+        let server_state = server_state.clone();
+        let email = email.clone();
+        tokio::task::spawn_blocking(move || server_state.delete_history(&email))
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err).into()))
+    };
+    match deleted {
+        Ok(count) => info!(count, "Deleted history"),
+        Err(err) => {
+            server_state.metrics.error(&err);
+            error!(?err, "Error deleting history");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Sorry, an error occurred",
+            )
+                .into_response();
+        }
+    }
+    Html(
+        HistoryResponse {
+            showing: t.history.showing.replace("{email}", email.as_str()),
+            token,
+            rows: Vec::new(),
+            deleted: true,
+            language,
+            t,
+        }
+        .render()
+        .unwrap(),
+    )
+    .into_response()
+}
+
+async fn missing_ingest_token(Path(id): Path<Uuid>) -> impl IntoResponse {
+    info!(%id, "Received data without an ingest token");
+    (
+        StatusCode::UNAUTHORIZED,
+        "This webhook URL is missing its token. Please copy the full link from the page shown when you created it.",
+    )
+}
+
+async fn receive_data(
+    Path((id, token)): Path<(Uuid, String)>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    body: Bytes,
+) -> Response {
+    let mut follow_up = FollowUp::default();
+    let response = ingest(&server_state, id, &token, body, &mut follow_up);
+    follow_up.run(&server_state, id).await;
+    response
+}
+
+/// Work which follows from a message but does file IO, so is done once the sessions lock has been released rather
+/// than holding up every other session.
+#[derive(Default)]
+struct FollowUp {
+    archive: Option<(Arc<Archive>, RawMessage)>,
+    history: Option<(Vec<EmailAddress>, HistoryRecord)>,
+    device: Option<(Device, Timestamp)>,
+    completed_test: Option<CompletedTest>,
+}
+
+impl FollowUp {
+    async fn run(self, server_state: &ServerState, id: Uuid) {
+        // Awaited before responding, so a device's messages are archived in the order it sent them.
+        if let Some((archive, raw_message)) = self.archive {
+            let result = tokio::task::spawn_blocking(move || archive.append(&id, &raw_message))
+                .await
+                .unwrap_or_else(|err| Err(io::Error::other(err)));
+            if let Err(err) = result {
+                let err = Error::from(err);
+                server_state.metrics.error(&err);
+                error!(%id, ?err, "Failed to archive message");
+            }
+        }
+        if let Some((device, seen)) = &self.device {
+            server_state.devices.lock().unwrap().observe(device, *seen);
+        }
+        if let Some((emails, record)) = self.history {
+            let server_state = server_state.clone();
+            let recorded =
+                tokio::task::spawn_blocking(move || server_state.record_history(&emails, record))
+                    .await;
+            if let Err(err) = recorded {
+                error!(%id, ?err, "Failed to record history");
+            }
+        }
+        if let Some(completed_test) = self.completed_test {
+            let devices = server_state.devices.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                devices.lock().unwrap().record_run(&completed_test)
+            })
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err).into()));
+            if let Err(err) = result {
+                server_state.metrics.error(&err);
+                error!(%id, ?err, "Failed to record run in device registry");
+            }
+        }
+    }
+}
+
+/// Applies a message to its session, under the sessions lock.
+fn ingest(
+    server_state: &ServerState,
+    id: Uuid,
+    token: &str,
+    body: Bytes,
+    follow_up: &mut FollowUp,
+) -> Response {
+    let mut sessions = server_state.sessions.lock().unwrap();
+    if let Some(session) = sessions.get_mut(&id) {
+        if !session.ingest_token_matches(token) {
+            error!(%id, "Received data with an invalid ingest token");
+            return (StatusCode::UNAUTHORIZED, "Invalid token").into_response();
+        }
+        if !session.is_active() {
+            info!(%id, "Received data before any recipient was verified");
+            return (
+                StatusCode::FORBIDDEN,
+                "Waiting for an email address to be confirmed",
+            )
+                .into_response();
+        }
+        let Ok(raw_message) = serde_json::from_slice::<serde_json::Value>(&body) else {
+            return (StatusCode::BAD_REQUEST, "Expected a JSON body").into_response();
+        };
+        // Keep the message even if we can't make sense of it, as that's exactly when it's needed for debugging.
+        let message = Message::parse(&raw_message, server_state.parse_mode);
+        let schema_drift = match &message {
+            Ok((_, drift)) | Err(Error::SchemaDrift(drift)) if !drift.is_empty() => {
+                server_state.metrics.schema_drift(drift);
+                warn!(%id, ?drift, "Received data which doesn't match the expected schema");
+                drift.describe()
+            }
+            _ => Vec::new(),
+        };
+        // Rejected before it's recorded, as recorded messages are replayed when the session is restored.
+        if let Ok((message, _)) = &message
+            && let Err(err) = session.check_device(message.device.serial_number)
+        {
+            server_state.metrics.error(&err);
+            session
+                .span
+                .in_scope(|| warn!(%id, ?err, "Rejected data from a second device"));
+            return (
+                StatusCode::CONFLICT,
+                "This link is already being used by another device. Please create a separate link for each device.",
+            )
+                .into_response();
+        }
+        let archive = server_state.archive.clone().filter(|_| session.archive);
+        let raw_message = session.record_raw_message(raw_message, body.len(), schema_drift);
+        follow_up.archive = archive.map(|archive| (archive, raw_message.clone()));
+        let message = match message {
+            Ok((message, _)) => message,
+            Err(err) => {
+                server_state.metrics.error(&err);
+                error!(%id, ?err, "Received data which could not be parsed");
+                if let Error::UnsupportedVersion(version) = err {
+                    let version = version.map_or("(missing)".to_owned(), |v| v.to_string());
+                    return (
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        format!(
+                            "Unsupported message version {}, expected one of {:?}",
+                            version, SUPPORTED_VERSIONS
+                        ),
+                    )
+                        .into_response();
+                }
+                return (StatusCode::UNPROCESSABLE_ENTITY, "Failed to parse data").into_response();
+            }
+        };
+        follow_up.device = Some((message.device.clone(), Timestamp::now()));
+        let event = message.event;
+        let _ingest = info_span!(parent: &session.span, "ingest", %event).entered();
+        server_state.metrics.message_received(event);
+        session.record_event(event);
+        let state = std::mem::replace(&mut session.state, State::started());
+        let update = info_span!("state_transition", from = state.name()).in_scope(|| {
+            state.update(
+                message,
+                &session.websockets,
+                &server_state.metrics,
+                &server_state.panels,
+            )
+        });
+        match update {
+            Ok(State::CompletedTest(completed_test)) => {
+                info!(%id, qc = %completed_test.qc.outcome(), "Received results");
+                for check in completed_test.qc.failures() {
+                    let reason = server_state.catalogs.get(DEFAULT_LANGUAGE).qc_reason(check);
+                    warn!(%id, check = check.name, reason, "Failed quality control");
+                }
+                server_state.metrics.session_completed();
+                server_state.metrics.qc(&completed_test.qc);
+                follow_up.history = server_state.history_record(session, &completed_test);
+                follow_up.completed_test = Some(completed_test.clone());
+                server_state.notify_result(
+                    id,
+                    &completed_test,
+                    session.recipient_emails(),
+                    &session.locale,
+                );
+                server_state.finish_batch_test(
+                    &id,
+                    if completed_test.needs_retest() {
+                        BatchOutcome::Retest
+                    } else {
+                        BatchOutcome::Completed(completed_test.overall)
+                    },
+                );
+                session.state = State::CompletedTest(completed_test);
+                server_state.remove_completed_after_retention(id);
+                (StatusCode::OK, "Received").into_response()
+            }
+            Ok(state) => {
+                trace!(%id, %event, "Received updated data");
+                session.state = state;
+                server_state.extend_session(session);
+                (StatusCode::OK, "Received").into_response()
+            }
+            Err(err) => {
+                server_state.metrics.error(&err);
+                if let Some(state) = err.get_state() {
+                    error!(%id, ?err, recoverable = true, "Error processing data");
+                    session.state = state.clone();
+                } else {
+                    error!(%id, ?err, recoverable = false, "Error processing data");
+                    server_state.notify_error(
+                        id,
+                        format!("Irrecoverable error processing data: {:?}", err),
+                        session.recipient_emails(),
+                        &session.locale,
+                    );
+                    sessions.remove(&id);
+                    server_state.finish_batch_test(&id, BatchOutcome::Expired);
+                }
+                (StatusCode::BAD_REQUEST, "Failed to process data").into_response()
+            }
+        }
+    } else {
+        error!(%id, "Received data for unknown ID");
+        (StatusCode::NOT_FOUND, "Unknown ID").into_response()
+    }
+}
+
+async fn list_recipients(
+    Path(id): Path<Uuid>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = check_session_token(&server_state, &id, &headers, &query) {
+        return rejection.into_response();
+    }
+    let sessions = server_state.sessions.lock().unwrap();
+    if let Some(session) = sessions.get(&id) {
+        Json(session.recipients.clone()).into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Unknown ID").into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct AddRecipientRequest {
+    email: EmailAddress,
+}
+
+async fn add_recipient(
+    Path(id): Path<Uuid>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+    Form(params): Form<AddRecipientRequest>,
+) -> impl IntoResponse {
+    if let Err(rejection) = check_session_token(&server_state, &id, &headers, &query) {
+        return rejection;
+    }
+    match server_state.add_recipient(&id, params.email.clone()) {
+        Ok(()) => {
+            info!(%id, email = %params.email, "Added recipient");
+            (StatusCode::OK, "Added")
+        }
+        Err(Error::UnknownSession) => (StatusCode::NOT_FOUND, "Unknown ID"),
+        Err(Error::TooManyRecipients(_)) => (StatusCode::CONFLICT, "Too many recipients"),
+        Err(Error::RateLimited(_)) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many sessions have been created for this address recently",
+        ),
+        Err(err) => {
+            error!(%id, ?err, "Error adding recipient");
+            (StatusCode::BAD_REQUEST, "Failed to add recipient")
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "verified.html")]
+struct VerifiedResponse<'a> {
+    pub email: EmailAddress,
+    pub language: String,
+    pub t: &'a Messages,
+}
+
+async fn verify_recipient(
+    Path((id, token)): Path<(Uuid, String)>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+) -> Response {
+    match server_state.verify_recipient(&id, &token) {
+        Ok(email) => {
+            info!(%id, %email, "Verified recipient");
+            let language = server_state
+                .sessions
+                .lock()
+                .unwrap()
+                .get(&id)
+                .map(|session| session.locale.language.clone())
+                .unwrap_or_default();
+            Html(
+                VerifiedResponse {
+                    email,
+                    t: server_state.catalogs.get(&language),
+                    language,
+                }
+                .render()
+                .unwrap(),
+            )
+            .into_response()
+        }
+        Err(err) => {
+            info!(%id, ?err, "Invalid verification link");
+            (
+                StatusCode::NOT_FOUND,
+                "This confirmation link was not recognised. It may have already been used, or the test may have finished.",
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn remove_recipient(
+    Path((id, email)): Path<(Uuid, EmailAddress)>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(rejection) = check_session_token(&server_state, &id, &headers, &query) {
+        return rejection;
+    }
+    let mut sessions = server_state.sessions.lock().unwrap();
+    let Some(session) = sessions.get_mut(&id) else {
+        return (StatusCode::NOT_FOUND, "Unknown ID");
+    };
+    match session.remove_recipient(&email) {
+        Ok(()) => {
+            info!(%id, %email, "Removed recipient");
+            (StatusCode::OK, "Removed")
+        }
+        Err(Error::UnknownRecipient) => (StatusCode::NOT_FOUND, "Unknown recipient"),
+        Err(Error::LastRecipient) => (
+            StatusCode::CONFLICT,
+            "A session must have at least one recipient",
+        ),
+        Err(err) => {
+            error!(%id, ?err, "Error removing recipient");
+            (StatusCode::BAD_REQUEST, "Failed to remove recipient")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AddViewerRequest {
+    label: Option<String>,
+    /// How long the link should work for, e.g. "24h". Links without an expiry last as long as the session.
+    expires_in: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AddViewerResponse {
+    url: String,
+    #[serde(flatten)]
+    viewer: Viewer,
+}
+
+async fn list_viewers(
+    Path(id): Path<Uuid>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = check_session_token(&server_state, &id, &headers, &query) {
+        return rejection.into_response();
+    }
+    let sessions = server_state.sessions.lock().unwrap();
+    if let Some(session) = sessions.get(&id) {
+        Json(session.viewers.clone()).into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Unknown ID").into_response()
+    }
+}
+
+async fn add_viewer(
+    Path(id): Path<Uuid>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+    Form(params): Form<AddViewerRequest>,
+) -> Response {
+    if let Err(rejection) = check_session_token(&server_state, &id, &headers, &query) {
+        return rejection.into_response();
+    }
+    let expires = match params.expires_in.as_deref().map(duration_str::parse) {
+        None => None,
+        Some(Ok(expires_in)) => Timestamp::now().checked_add(expires_in).ok(),
+        Some(Err(_)) => {
+            return (StatusCode::BAD_REQUEST, "Invalid expires_in duration").into_response();
+        }
+    };
+    let mut sessions = server_state.sessions.lock().unwrap();
+    let Some(session) = sessions.get_mut(&id) else {
+        return (StatusCode::NOT_FOUND, "Unknown ID").into_response();
+    };
+    match session.add_viewer(params.label, expires) {
+        Ok(viewer) => {
+            info!(%id, expires = ?viewer.expires, "Added viewer");
+            Json(AddViewerResponse {
+                url: format!(
+                    "{}/session/{}/view/{}",
+                    server_state.base_url, id, viewer.token
+                ),
+                viewer,
+            })
+            .into_response()
+        }
+        Err(Error::TooManyViewers(_)) => {
+            (StatusCode::CONFLICT, "Too many viewer links").into_response()
+        }
+        Err(err) => {
+            error!(%id, ?err, "Error adding viewer");
+            (StatusCode::BAD_REQUEST, "Failed to add viewer").into_response()
+        }
+    }
+}
+
+async fn revoke_viewer(
+    Path((id, token)): Path<(Uuid, String)>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(rejection) = check_session_token(&server_state, &id, &headers, &query) {
+        return rejection;
+    }
+    let mut sessions = server_state.sessions.lock().unwrap();
+    let Some(session) = sessions.get_mut(&id) else {
+        return (StatusCode::NOT_FOUND, "Unknown ID");
+    };
+    match session.revoke_viewer(&token) {
+        Ok(()) => {
+            info!(%id, "Revoked viewer");
+            (StatusCode::OK, "Revoked")
+        }
+        Err(_) => (StatusCode::NOT_FOUND, "Unknown viewer"),
+    }
+}
+
+async fn print_json_data(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    match server_state.dump_mode {
+        DumpMode::Open => {}
+        DumpMode::Admin => {
+            if !is_admin(&server_state, &headers) {
+                return (StatusCode::UNAUTHORIZED, "Unauthorized");
+            }
+        }
+        DumpMode::Disabled => return (StatusCode::NOT_FOUND, "Not found"),
+    }
+    let Ok(payload) = serde_json::from_slice::<serde_json::Value>(&body) else {
+        return (StatusCode::BAD_REQUEST, "Expected a JSON body");
+    };
+    let mut map = BTreeMap::new();
+    let timestamp = Timestamp::now();
+    map.insert(
+        "timestamp",
+        serde_json::Value::String(timestamp.to_string()),
+    );
+    map.insert("message", payload);
+    println!("{}", serde_json::to_string(&map).unwrap());
+    (StatusCode::OK, "Received")
+}
+
+async fn generate_graph_image(
+    Path((id, token)): Path<(Uuid, String)>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+) -> Response {
+    if let Err(rejection) = check_viewer_token(&server_state, &id, &token) {
+        return rejection.into_response();
+    }
+    let sessions = server_state.sessions.lock().unwrap();
+    let response = if let Some(session) = sessions.get(&id) {
+        match session.state.current_graph_png(&server_state.metrics) {
+            Ok(Some(bytes)) => (
+                StatusCode::OK,
+                [(axum::http::header::CONTENT_TYPE, "image/png")],
+                bytes,
+            ),
+            Ok(None) => {
+                (
+                    StatusCode::NOT_FOUND,
+                    [(axum::http::header::CONTENT_TYPE, "text/plain")],
+                    "No data has been received for this test yet. Please refresh the page when you expect there to be data.".as_bytes().to_owned(),
+                )
+            },
+            Err(err) => {
+                server_state.metrics.error(&err);
+                error!(?err, "Error generating graph for display");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    [(axum::http::header::CONTENT_TYPE, "text/plain")],
+                    "Sorry, an error occurred".as_bytes().to_owned(),
+                )
+            }
+        }
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            [(axum::http::header::CONTENT_TYPE, "text/plain")],
+            "This test ID was not recognised. Either it has not been registered, or the test has already finished.".as_bytes().to_owned(),
+        )
+    };
+    response.into_response()
+}
+
+async fn get_data_dummy(
+    Path((id, token)): Path<(Uuid, String)>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+) -> impl IntoResponse {
+    if server_state
+        .sessions
+        .lock()
+        .unwrap()
+        .get(&id)
+        .is_some_and(|session| session.ingest_token_matches(&token))
+    {
+        (
+            StatusCode::OK,
+            "This link is only intended to be used as a webhook. In the virus.sucks app, open 'Settings' and put it in the 'Webhook URL' field.",
+        )
+    } else {
+        (StatusCode::NOT_FOUND, "Unknown ID")
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+struct AdminResponse;
+
+// The page itself holds no data: it asks for the admin token and uses it to call the JSON API below, so the token is
+// only ever sent in an Authorization header rather than ending up in URLs and access logs.
+async fn admin(axum::extract::State(server_state): axum::extract::State<ServerState>) -> Response {
+    if server_state.admin_token.is_none() {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    Html(AdminResponse.render().unwrap()).into_response()
+}
+
+async fn admin_list_sessions(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = check_admin(&server_state, &headers) {
+        return rejection.into_response();
+    }
+    Json(server_state.sessions.lock().unwrap().summaries()).into_response()
+}
+
+async fn admin_list_devices(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = check_admin(&server_state, &headers) {
+        return rejection.into_response();
+    }
+    Json(server_state.devices.lock().unwrap().summaries()).into_response()
+}
+
+async fn admin_expire_session(
+    Path(id): Path<Uuid>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(rejection) = check_admin(&server_state, &headers) {
+        return rejection;
+    }
+    match server_state.expire_session(&id) {
+        Ok(()) => (StatusCode::OK, "Expired"),
+        Err(_) => (StatusCode::NOT_FOUND, "Unknown ID"),
+    }
+}
+
+async fn admin_resend_result(
+    Path(id): Path<Uuid>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(rejection) = check_admin(&server_state, &headers) {
+        return rejection;
+    }
+    match server_state.resend_result(&id) {
+        Ok(()) => (StatusCode::OK, "Resending"),
+        Err(Error::TestNotFinished) => (StatusCode::CONFLICT, "The test has not finished yet"),
+        Err(_) => (StatusCode::NOT_FOUND, "Unknown ID"),
+    }
+}
+
+async fn admin_download_messages(
+    Path(id): Path<Uuid>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = check_admin(&server_state, &headers) {
+        return rejection.into_response();
+    }
+    let sessions = server_state.sessions.lock().unwrap();
+    let Some(session) = sessions.get(&id) else {
+        return (StatusCode::NOT_FOUND, "Unknown ID").into_response();
+    };
+    let mut jsonl = Vec::new();
+    for message in &session.raw_messages {
+        serde_json::to_writer(&mut jsonl, message).unwrap();
+        jsonl.push(b'\n');
+    }
+    jsonl_attachment(format!("session-{}.jsonl", id), jsonl)
+}
+
+async fn admin_download_archive(
+    Path(id): Path<Uuid>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = check_admin(&server_state, &headers) {
+        return rejection.into_response();
+    }
+    let Some(archive) = server_state.archive.clone() else {
+        return (StatusCode::NOT_FOUND, "Archiving is not enabled").into_response();
+    };
+    match tokio::task::spawn_blocking(move || archive.read(&id)).await {
+        Ok(Ok(Some(jsonl))) => jsonl_attachment(format!("session-{}-archive.jsonl", id), jsonl),
+        Ok(Ok(None)) => (StatusCode::NOT_FOUND, "Nothing archived for this ID").into_response(),
+        Ok(Err(err)) => {
+            let err = Error::from(err);
+            server_state.metrics.error(&err);
+            error!(%id, ?err, "Failed to read archive");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(err) => {
+            error!(%id, ?err, "Reading archive panicked");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Captures are in the same format as /dump output, so they can be replayed.
+fn jsonl_attachment(filename: String, jsonl: Vec<u8>) -> Response {
+    (
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                "application/x-ndjson".to_owned(),
+            ),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        jsonl,
+    )
+        .into_response()
+}
+
+async fn count_sessions(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+) -> impl IntoResponse + Send {
+    let sessions = server_state.sessions.lock().unwrap();
+    format!("{}", sessions.len())
+}
+
+async fn metrics(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+) -> impl IntoResponse {
+    let (active_sessions, connected_websockets) = {
+        let sessions = server_state.sessions.lock().unwrap();
+        (sessions.len(), sessions.websocket_count())
+    };
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        server_state
+            .metrics
+            .encode(active_sessions, connected_websockets),
+    )
+}
+
+async fn handle_websocket_request(
+    ws: WebSocketUpgrade,
+    Path((id, token)): Path<(Uuid, String)>,
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+) -> impl IntoResponse {
+    let (websockets, state) = {
+        let sessions = server_state.sessions.lock().unwrap();
+        match sessions.get(&id) {
+            Some(session) if session.viewer_token_matches(&token) => {
+                (session.websockets.clone(), session.state.clone())
+            }
+            _ => return (StatusCode::NOT_FOUND, "Unknown ID").into_response(),
+        }
+    };
+    ws.on_upgrade(move |websocket| async move {
+        let (socket, websocket_count) = websockets.push(websocket, token);
+        info!(%id, websocket_count, "New websocket connected");
+        socket.notify(&state, &server_state.metrics);
+    })
+}
+
+#[derive(Template)]
+#[template(path = "graph.html")]
+struct LiveGraphResponse<'a> {
+    pub base_url: String,
+    pub id: Uuid,
+    pub viewer_token: String,
+    pub graph_width: u32,
+    pub graph_height: u32,
+    pub device_lock: Option<String>,
+    pub device_rejected: Option<String>,
+    pub language: String,
+    pub t: &'a Messages,
+    pub text_json: String,
+}
+
+async fn live_graph(
+    axum::extract::State(server_state): axum::extract::State<ServerState>,
+    Path((id, token)): Path<(Uuid, String)>,
+) -> impl IntoResponse {
+    if let Err(rejection) = check_viewer_token(&server_state, &id, &token) {
+        return rejection.into_response();
+    }
+    let session = server_state
+        .sessions
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map(|session| {
+            (
+                session.locale.clone(),
+                session.device_serial_number,
+                session.rejected_device.clone(),
+            )
+        });
+    if let Some((locale, device_serial_number, rejected_device)) = session {
+        let t = server_state.catalogs.get(&locale.language);
+        let language = locale.language;
+        Html(
             LiveGraphResponse {
+                device_lock: device_serial_number.map(|serial_number| {
+                    t.graph
+                        .device_lock
+                        .replace("{serial_number}", &serial_number.to_string())
+                }),
+                device_rejected: rejected_device.map(|rejected| {
+                    t.graph
+                        .device_rejected
+                        .replace("{serial_number}", &rejected.serial_number.to_string())
+                        .replace(
+                            "{time}",
+                            &t.format_timestamp(rejected.at, &locale.time_zone),
+                        )
+                }),
                 base_url: server_state.websocket_base_url.clone(),
                 id,
+                viewer_token: token,
                 graph_width: graph::WIDTH,
                 graph_height: graph::HEIGHT,
+                text_json: graph_text_json(t),
+                language,
+                t,
             }
             .render()
             .unwrap(),
@@ -328,3 +2000,1560 @@ async fn live_graph(
         ).into_response()
     }
 }
+
+// The live graph renders results client-side, so it needs the relevant strings as a JSON object.
+fn graph_text_json(t: &Messages) -> String {
+    let text = serde_json::json!({
+        "error": t.graph.error,
+        "results_heading": t.graph.results_heading,
+        "overall": t.graph.overall,
+        "subgroups_intro": t.graph.subgroups_intro,
+        "assay": t.graph.assay,
+        "results": {
+            "POSITIVE": t.results.positive,
+            "NEGATIVE": t.results.negative,
+            "INVALID": t.results.invalid,
+            "UNKNOWN": t.results.unknown,
+        },
+        "subgroups": t.subgroups,
+    });
+    // Escape "</" so no catalog entry can close the surrounding script tag.
+    text.to_string().replace("</", "<\\/")
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use axum::{
+        Router,
+        body::Body,
+        extract::connect_info::MockConnectInfo,
+        http::{
+            Request, StatusCode,
+            header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER},
+        },
+        response::Response,
+    };
+    use email_address::EmailAddress;
+    use jiff::{SignedDuration, Timestamp};
+    use pluslife_notifier::{
+        archive::Archive,
+        batches::Batches,
+        devices::DeviceRegistry,
+        emails::EmailTemplates,
+        expiry::ExpiryScheduler,
+        history::History,
+        i18n::{Catalogs, UserLocale},
+        mailgun::SendResponse,
+        messages::ParseMode,
+        metrics::Metrics,
+        notifier::{Mailer, OutgoingEmail, SendFuture, Transport},
+        panels::Panels,
+        qc::Outcome,
+        rate_limit::RateLimiter,
+        sessions::{DeliveryStatus, DumpMode, MAX_RECIPIENTS, ServerState, Sessions},
+        state::State,
+        verification::VerifiedEmails,
+    };
+    use tokio_util::{sync::CancellationToken, task::TaskTracker};
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    /// Records emails instead of sending them.
+    #[derive(Default)]
+    struct RecordingTransport {
+        sent: Mutex<Vec<(String, &'static str)>>,
+    }
+
+    impl Transport for RecordingTransport {
+        fn send(&self, email: OutgoingEmail) -> SendFuture<'_> {
+            let mut sent = self.sent.lock().unwrap();
+            sent.push((email.recipient.to_string(), email.email.kind));
+            let id = format!("<{}@example.com>", sent.len());
+            Box::pin(async move {
+                Ok(SendResponse {
+                    message: "Queued. Thank you.".to_owned(),
+                    id,
+                })
+            })
+        }
+    }
+
+    impl RecordingTransport {
+        /// The (recipient, kind) of each email sent so far, in order.
+        fn sent(&self) -> Vec<(String, &'static str)> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    fn server_state() -> ServerState {
+        recording_server_state().0
+    }
+
+    fn recording_server_state() -> (ServerState, Arc<RecordingTransport>) {
+        let catalogs = Arc::new(Catalogs::load().unwrap());
+        let transport = Arc::new(RecordingTransport::default());
+        let server_state = ServerState {
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+            base_url: "http://localhost".to_owned(),
+            websocket_base_url: "ws://localhost".to_owned(),
+            mailer: Arc::new(Mailer {
+                sender_email: EmailAddress::from_str("sender@example.com").unwrap(),
+                transport: transport.clone(),
+                templates: EmailTemplates::builtin(),
+                catalogs: catalogs.clone(),
+            }),
+            cleanup_period: Duration::from_secs(3600),
+            completed_retention: Duration::from_secs(60),
+            inactivity_warning: Duration::from_secs(900),
+            require_email_verification: false,
+            verification_memory: Duration::ZERO,
+            verified_emails: Arc::new(Mutex::new(VerifiedEmails::default())),
+            catalogs,
+            panels: Arc::new(Panels::builtin().unwrap()),
+            session_rate_limit_per_ip: Arc::new(Mutex::new(RateLimiter::new(
+                "3/1h".parse().unwrap(),
+            ))),
+            session_rate_limit_per_email: Arc::new(Mutex::new(RateLimiter::new(
+                "2/1h".parse().unwrap(),
+            ))),
+            max_active_sessions_per_ip: 20,
+            max_active_sessions_per_email: 20,
+            max_data_body_bytes: 64 * 1024,
+            trust_forwarded_for: false,
+            parse_mode: ParseMode::Strict,
+            dump_mode: DumpMode::Open,
+            admin_token: None,
+            metrics: Arc::new(Metrics::new()),
+            archive: None,
+            history: None,
+            history_link_rate_limit: Arc::new(Mutex::new(RateLimiter::new(
+                "2/1h".parse().unwrap(),
+            ))),
+            devices: Arc::new(Mutex::new(DeviceRegistry::load(None, None).unwrap())),
+            batches: Arc::new(Mutex::new(Batches::default())),
+            state_file: None,
+            tasks: TaskTracker::new(),
+            shutdown: CancellationToken::new(),
+            expiry: ExpiryScheduler::new(),
+            batch_expiry: ExpiryScheduler::new(),
+            watchdog: ExpiryScheduler::new(),
+        };
+        (server_state, transport)
+    }
+
+    fn app_from(server_state: ServerState) -> Router {
+        super::app(server_state).layer(MockConnectInfo(SocketAddr::from(([192, 0, 2, 1], 1234))))
+    }
+
+    async fn create_session(app: &Router, email: &str) -> StatusCode {
+        app.clone()
+            .oneshot(
+                Request::post("/session/create")
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(Body::from(format!("email={}", email.replace('@', "%40"))))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn session_creation_is_rate_limited_per_email() {
+        let app = app_from(ServerState {
+            session_rate_limit_per_ip: Arc::new(Mutex::new(RateLimiter::new(
+                "10/1h".parse().unwrap(),
+            ))),
+            ..server_state()
+        });
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            create_session(&app, "a@example.com").await
+        );
+        assert_eq!(StatusCode::OK, create_session(&app, "b@example.com").await);
+    }
+
+    #[tokio::test]
+    async fn session_creation_is_rate_limited_per_ip() {
+        let app = app_from(server_state());
+        for email in ["a@example.com", "b@example.com", "c@example.com"] {
+            assert_eq!(StatusCode::OK, create_session(&app, email).await);
+        }
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            create_session(&app, "d@example.com").await
+        );
+    }
+
+    #[tokio::test]
+    async fn active_sessions_are_capped() {
+        let app = app_from(ServerState {
+            max_active_sessions_per_ip: 1,
+            ..server_state()
+        });
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            create_session(&app, "b@example.com").await
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_sessions_cannot_exceed_the_cap() {
+        let server_state = ServerState {
+            max_active_sessions_per_ip: 2,
+            session_rate_limit_per_ip: Arc::new(Mutex::new(RateLimiter::new(
+                "100/1h".parse().unwrap(),
+            ))),
+            ..server_state()
+        };
+        let app = app_from(server_state.clone());
+        let requests: Vec<_> = (0..20)
+            .map(|n| {
+                let app = app.clone();
+                tokio::spawn(
+                    async move { create_session(&app, &format!("s{}@example.com", n)).await },
+                )
+            })
+            .collect();
+        let mut created = 0;
+        for request in requests {
+            if request.await.unwrap() == StatusCode::OK {
+                created += 1;
+            }
+        }
+        assert_eq!(2, created);
+        assert_eq!(2, server_state.sessions.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn data_body_size_is_capped() {
+        let server_state = server_state();
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        let response = app
+            .oneshot(
+                Request::post(format!("/session/{}/data/{}", id, token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(format!(
+                        "{{\"padding\": \"{}\"}}",
+                        "x".repeat(128 * 1024)
+                    )))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+    }
+
+    fn session_credentials(server_state: &ServerState) -> (Uuid, String) {
+        let sessions = server_state.sessions.lock().unwrap();
+        let session = sessions.get(&sessions.ids()[0]).unwrap();
+        (session.id, session.ingest_token.clone())
+    }
+
+    async fn post_data(app: &Router, path: String) -> StatusCode {
+        app.clone()
+            .oneshot(
+                Request::post(path)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(include_str!(
+                        "../../tests/fixtures/synthetic/new-data.json"
+                    )))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn data_requires_ingest_token() {
+        let server_state = server_state();
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            post_data(&app, format!("/session/{}/data", id)).await
+        );
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            post_data(&app, format!("/session/{}/data/{}", id, "0".repeat(64))).await
+        );
+        assert_eq!(
+            StatusCode::OK,
+            post_data(&app, format!("/session/{}/data/{}", id, token)).await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_sessions_are_warned_about_once_data_has_arrived_and_then_expire() {
+        let (server_state, transport) = recording_server_state();
+        server_state.spawn_expiry();
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+
+        // Nobody has started a test yet, so there's nothing to be silent about.
+        tokio::time::sleep(Duration::from_secs(1200)).await;
+        assert!(
+            server_state
+                .metrics
+                .encode(0, 0)
+                .contains("pluslife_sessions_silent_total 0\n")
+        );
+
+        assert_eq!(
+            StatusCode::OK,
+            post_data(&app, format!("/session/{}/data/{}", id, token)).await
+        );
+        tokio::time::sleep(Duration::from_secs(901)).await;
+        assert!(
+            server_state
+                .metrics
+                .encode(0, 0)
+                .contains("pluslife_sessions_silent_total 1\n")
+        );
+        assert!(server_state.sessions.lock().unwrap().get(&id).is_some());
+
+        tokio::time::sleep(Duration::from_secs(3600 - 900)).await;
+        assert!(server_state.sessions.lock().unwrap().get(&id).is_none());
+        server_state.tasks.close();
+        server_state.tasks.wait().await;
+        let metrics = server_state.metrics.encode(0, 0);
+        assert!(metrics.contains("pluslife_sessions_silent_total 1\n"));
+        assert!(metrics.contains("pluslife_sessions_expired_total 1\n"));
+        assert_eq!(
+            vec![
+                ("a@example.com".to_owned(), "silent"),
+                ("a@example.com".to_owned(), "expired")
+            ],
+            transport.sent()
+        );
+    }
+
+    async fn manage_recipients(
+        app: &Router,
+        method: &str,
+        path: String,
+        authorization: Option<String>,
+    ) -> StatusCode {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let request = request
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("email=b%40example.com"))
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn recipients_can_only_be_managed_with_the_session_token() {
+        let (server_state, transport) = recording_server_state();
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        let recipients = format!("/session/{}/recipients", id);
+        let recipient = format!("/session/{}/recipients/b@example.com", id);
+
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            manage_recipients(&app, "GET", recipients.clone(), None).await
+        );
+        // The session id is shared with viewers, so it mustn't work as a token.
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            manage_recipients(
+                &app,
+                "POST",
+                recipients.clone(),
+                Some(format!("Bearer {}", id))
+            )
+            .await
+        );
+        assert_eq!(
+            StatusCode::OK,
+            manage_recipients(
+                &app,
+                "POST",
+                recipients.clone(),
+                Some(format!("Bearer {}", token))
+            )
+            .await
+        );
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            manage_recipients(&app, "DELETE", recipient.clone(), None).await
+        );
+        assert_eq!(
+            StatusCode::OK,
+            manage_recipients(
+                &app,
+                "DELETE",
+                format!("{}?token={}", recipient, token),
+                None
+            )
+            .await
+        );
+        assert_eq!(
+            StatusCode::OK,
+            manage_recipients(&app, "GET", format!("{}?token={}", recipients, token), None).await
+        );
+        // Addresses don't need confirming here, and there's no result yet to send.
+        assert!(transport.sent().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_recipients_cannot_exceed_the_cap() {
+        let server_state = ServerState {
+            session_rate_limit_per_email: Arc::new(Mutex::new(RateLimiter::new(
+                "100/1h".parse().unwrap(),
+            ))),
+            ..server_state()
+        };
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        let requests: Vec<_> = (0..2 * MAX_RECIPIENTS)
+            .map(|n| {
+                let app = app.clone();
+                let request = Request::post(format!("/session/{}/recipients?token={}", id, token))
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(Body::from(format!("email=r{}%40example.com", n)))
+                    .unwrap();
+                tokio::spawn(async move { app.oneshot(request).await.unwrap().status() })
+            })
+            .collect();
+        let mut added = 0;
+        for request in requests {
+            if request.await.unwrap() == StatusCode::OK {
+                added += 1;
+            }
+        }
+        assert_eq!(MAX_RECIPIENTS - 1, added);
+        let sessions = server_state.sessions.lock().unwrap();
+        assert_eq!(MAX_RECIPIENTS, sessions.get(&id).unwrap().recipients.len());
+    }
+
+    fn verification_token(server_state: &ServerState, id: &Uuid) -> Option<String> {
+        let sessions = server_state.sessions.lock().unwrap();
+        sessions.get(id).unwrap().recipients[0]
+            .verification_token
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn recipients_confirm_their_address_before_data_is_accepted() {
+        let (server_state, transport) = recording_server_state();
+        let server_state = ServerState {
+            require_email_verification: true,
+            verification_memory: Duration::from_secs(60 * 60),
+            ..server_state
+        };
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        let confirmation = verification_token(&server_state, &id).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post(format!("/session/{}/data/{}", id, token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(include_str!(
+                        "../../tests/fixtures/synthetic/test-finished.json"
+                    )))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        {
+            let sessions = server_state.sessions.lock().unwrap();
+            let session = sessions.get(&id).unwrap();
+            assert!(!session.is_active());
+            assert!(matches!(session.state, State::IncompleteTest(_)));
+            assert!(matches!(
+                session.recipients[0].delivery,
+                DeliveryStatus::Pending
+            ));
+        }
+
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get_status(&app, format!("/session/{}/verify/{}", id, token)).await
+        );
+        assert!(
+            !server_state
+                .sessions
+                .lock()
+                .unwrap()
+                .get(&id)
+                .unwrap()
+                .is_active()
+        );
+
+        assert_eq!(
+            StatusCode::OK,
+            get_status(&app, format!("/session/{}/verify/{}", id, confirmation)).await
+        );
+        assert!(
+            server_state
+                .sessions
+                .lock()
+                .unwrap()
+                .get(&id)
+                .unwrap()
+                .is_active()
+        );
+        assert_eq!(
+            StatusCode::OK,
+            post_data(&app, format!("/session/{}/data/{}", id, token)).await
+        );
+        // Links only work once.
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get_status(&app, format!("/session/{}/verify/{}", id, confirmation)).await
+        );
+
+        // The confirmed address is remembered for the next test, but other addresses still need confirming.
+        let remembered = server_state
+            .create_session(
+                vec!["a@example.com".parse().unwrap()],
+                UserLocale::new("en".to_owned(), None),
+                None,
+                false,
+                false,
+            )
+            .unwrap();
+        assert!(verification_token(&server_state, &remembered.id).is_none());
+        assert!(
+            server_state
+                .sessions
+                .lock()
+                .unwrap()
+                .get(&remembered.id)
+                .unwrap()
+                .is_active()
+        );
+        let unknown = server_state
+            .create_session(
+                vec!["b@example.com".parse().unwrap()],
+                UserLocale::new("en".to_owned(), None),
+                None,
+                false,
+                false,
+            )
+            .unwrap();
+        assert!(verification_token(&server_state, &unknown.id).is_some());
+        server_state.tasks.close();
+        server_state.tasks.wait().await;
+        assert_eq!(
+            vec![
+                ("a@example.com".to_owned(), "verify"),
+                ("b@example.com".to_owned(), "verify")
+            ],
+            transport.sent()
+        );
+    }
+
+    #[tokio::test]
+    async fn finished_runs_are_emailed_their_result() {
+        let (server_state, transport) = recording_server_state();
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        let response = app
+            .oneshot(
+                Request::post(format!("/session/{}/data/{}", id, token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(include_str!(
+                        "../../tests/fixtures/synthetic/test-finished.json"
+                    )))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        {
+            let sessions = server_state.sessions.lock().unwrap();
+            let State::CompletedTest(completed_test) = &sessions.get(&id).unwrap().state else {
+                panic!("The test should have completed");
+            };
+            assert!(!completed_test.needs_retest());
+        }
+        server_state.tasks.close();
+        server_state.tasks.wait().await;
+        assert_eq!(
+            vec![("a@example.com".to_owned(), "result")],
+            transport.sent()
+        );
+    }
+
+    #[tokio::test]
+    async fn positive_results_are_delivered_when_the_internal_control_drops_out() {
+        let (server_state, transport) = recording_server_state();
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        let mut message: serde_json::Value = serde_json::from_str(include_str!(
+            "../../tests/fixtures/synthetic/test-finished.json"
+        ))
+        .unwrap();
+        let result = &mut message["test"]["result"];
+        result["detectionResult"] = "POSITIVE".into();
+        result["channelResults"][0] = "POSITIVE".into();
+        result["channelResults"][6] = "NEGATIVE".into();
+        result["subGroupResults"][0]["result"] = "POSITIVE".into();
+        result["subGroupResults"][1]["result"] = "NEGATIVE".into();
+        let response = app
+            .oneshot(
+                Request::post(format!("/session/{}/data/{}", id, token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(message.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        {
+            let sessions = server_state.sessions.lock().unwrap();
+            let State::CompletedTest(completed_test) = &sessions.get(&id).unwrap().state else {
+                panic!("The test should have completed");
+            };
+            assert_eq!(Outcome::Warn, completed_test.qc.outcome());
+            assert!(!completed_test.needs_retest());
+        }
+        server_state.tasks.close();
+        server_state.tasks.wait().await;
+        assert_eq!(
+            vec![("a@example.com".to_owned(), "result")],
+            transport.sent()
+        );
+    }
+
+    #[tokio::test]
+    async fn unsupported_message_versions_are_rejected_clearly() {
+        let server_state = server_state();
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+
+        let mut message: serde_json::Value =
+            serde_json::from_str(include_str!("../../tests/fixtures/synthetic/new-data.json"))
+                .unwrap();
+        message["version"] = 2.into();
+        let response = app
+            .oneshot(
+                Request::post(format!("/session/{}/data/{}", id, token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(message.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            "Unsupported message version 2, expected one of [1]",
+            String::from_utf8_lossy(&body)
+        );
+    }
+
+    async fn get_text(app: &Router, path: &str) -> String {
+        let response = app
+            .clone()
+            .oneshot(Request::get(path).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn privacy_policy_states_the_configured_retention_periods() {
+        let app = app_from(ServerState {
+            cleanup_period: Duration::from_secs(2 * 60 * 60),
+            completed_retention: Duration::from_secs(30 * 60),
+            verification_memory: Duration::from_secs(14 * 24 * 60 * 60),
+            ..server_state()
+        });
+        let page = get_text(&app, "/privacy.html?lang=en").await;
+        assert!(page.contains("we delete it within 30 minutes"));
+        assert!(page.contains("received any data for it for 2 hours"));
+        assert!(page.contains("for up to 14 days"));
+        assert!(page.contains("any uploaded test data once we haven"));
+        assert!(page.contains("received any more for 2 hours"));
+    }
+
+    async fn get_status(app: &Router, path: String) -> StatusCode {
+        app.clone()
+            .oneshot(Request::get(path).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn viewer_links_can_be_shared_and_revoked() {
+        let server_state = server_state();
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        let creator_viewer = server_state
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .unwrap()
+            .viewers[0]
+            .token
+            .clone();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post(format!("/session/{}/viewers", id))
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .header("authorization", format!("Bearer {}", token))
+                    .body(Body::from("label=GP&expires_in=1d"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let shared_viewer = {
+            let sessions = server_state.sessions.lock().unwrap();
+            let viewer = &sessions.get(&id).unwrap().viewers[1];
+            assert_eq!(Some("GP"), viewer.label.as_deref());
+            assert!(viewer.expires.is_some());
+            viewer.token.clone()
+        };
+
+        let view = |viewer: &str| format!("/session/{}/view/{}", id, viewer);
+        assert_eq!(StatusCode::OK, get_status(&app, view(&shared_viewer)).await);
+        assert_eq!(StatusCode::NOT_FOUND, get_status(&app, view(&token)).await);
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get_status(&app, format!("/session/{}/graph", id)).await
+        );
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::delete(format!(
+                    "/session/{}/viewers/{}?token={}",
+                    id, shared_viewer, token
+                ))
+                .body(Body::empty())
+                .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get_status(&app, view(&shared_viewer)).await
+        );
+        assert_eq!(
+            StatusCode::OK,
+            get_status(&app, view(&creator_viewer)).await
+        );
+    }
+
+    async fn admin_request(app: &Router, method: &str, path: String) -> (StatusCode, String) {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(path)
+                    .header("authorization", "Bearer secret")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn admin_api_lists_downloads_and_expires_sessions() {
+        let disabled = app_from(server_state());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            admin_request(&disabled, "GET", "/admin/api/sessions".to_owned())
+                .await
+                .0
+        );
+
+        let server_state = ServerState {
+            admin_token: Some("secret".to_owned()),
+            ..server_state()
+        };
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        assert_eq!(
+            StatusCode::OK,
+            post_data(&app, format!("/session/{}/data/{}", id, token)).await
+        );
+
+        let (status, body) = admin_request(&app, "GET", "/admin/api/sessions".to_owned()).await;
+        assert_eq!(StatusCode::OK, status);
+        let sessions: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(id.to_string(), sessions[0]["id"]);
+        assert_eq!("incomplete", sessions[0]["state"]["status"]);
+        assert_eq!(21, sessions[0]["state"]["samples"]);
+        assert_eq!("NEW_DATA", sessions[0]["last_event"]["event"]);
+
+        let (status, body) = admin_request(&app, "GET", "/admin/api/devices".to_owned()).await;
+        assert_eq!(StatusCode::OK, status);
+        let devices: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(123456789, devices[0]["serial_number"]);
+        assert_eq!("1.2.3", devices[0]["firmware"][0]["version"]);
+        assert_eq!(0, devices[0]["runs"]);
+        assert_eq!(false, devices[0]["firmware_flagged"]);
+
+        let (status, body) =
+            admin_request(&app, "GET", format!("/admin/api/sessions/{}/messages", id)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(1, body.lines().count());
+        let message: serde_json::Value = serde_json::from_str(body.trim()).unwrap();
+        assert_eq!("NEW_DATA", message["message"]["event"]);
+
+        assert_eq!(
+            StatusCode::CONFLICT,
+            admin_request(&app, "POST", format!("/admin/api/sessions/{}/resend", id))
+                .await
+                .0
+        );
+        assert_eq!(
+            StatusCode::OK,
+            admin_request(&app, "POST", format!("/admin/api/sessions/{}/expire", id))
+                .await
+                .0
+        );
+        assert_eq!(0, server_state.sessions.lock().unwrap().len());
+
+        // Removing a session which already has its result isn't an expiry.
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post(format!("/session/{}/data/{}", id, token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(include_str!(
+                        "../../tests/fixtures/synthetic/test-finished.json"
+                    )))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            StatusCode::OK,
+            admin_request(&app, "POST", format!("/admin/api/sessions/{}/expire", id))
+                .await
+                .0
+        );
+        assert!(
+            server_state
+                .metrics
+                .encode(0, 0)
+                .contains("pluslife_sessions_expired_total 1\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn archived_sessions_can_be_downloaded_after_expiry() {
+        let dir = std::env::temp_dir().join(format!("archive-{}", Uuid::new_v4()));
+        let server_state = ServerState {
+            admin_token: Some("secret".to_owned()),
+            archive: Some(Arc::new(
+                Archive::new(&dir, Duration::from_secs(24 * 60 * 60)).unwrap(),
+            )),
+            ..server_state()
+        };
+        let app = app_from(server_state.clone());
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/session/create")
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(Body::from("email=a%40example.com&archive=true"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let (id, token) = session_credentials(&server_state);
+        assert_eq!(
+            StatusCode::OK,
+            post_data(&app, format!("/session/{}/data/{}", id, token)).await
+        );
+        assert_eq!(
+            StatusCode::OK,
+            admin_request(&app, "POST", format!("/admin/api/sessions/{}/expire", id))
+                .await
+                .0
+        );
+
+        let (status, body) = admin_request(&app, "GET", format!("/admin/api/archive/{}", id)).await;
+        assert_eq!(StatusCode::OK, status);
+        let message: serde_json::Value = serde_json::from_str(body.trim()).unwrap();
+        assert_eq!("NEW_DATA", message["message"]["event"]);
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            admin_request(
+                &app,
+                "GET",
+                format!("/admin/api/archive/{}", Uuid::new_v4())
+            )
+            .await
+            .0
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sessions_only_accept_data_from_their_first_device() {
+        let server_state = ServerState {
+            admin_token: Some("secret".to_owned()),
+            ..server_state()
+        };
+        let app = app_from(server_state.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&server_state);
+        let viewer_token = server_state
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .unwrap()
+            .viewers[0]
+            .token
+            .clone();
+        assert_eq!(
+            StatusCode::OK,
+            post_data(&app, format!("/session/{}/data/{}", id, token)).await
+        );
+
+        let mut message: serde_json::Value =
+            serde_json::from_str(include_str!("../../tests/fixtures/synthetic/new-data.json"))
+                .unwrap();
+        message["device"]["sn"] = 987654321.into();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post(format!("/session/{}/data/{}", id, token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(message.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+        assert_eq!(
+            1,
+            server_state
+                .sessions
+                .lock()
+                .unwrap()
+                .get(&id)
+                .unwrap()
+                .raw_messages
+                .len()
+        );
+
+        let (status, body) = admin_request(
+            &app,
+            "GET",
+            format!("/session/{}/view/{}", id, viewer_token),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.contains("Only accepting data from device 123456789."));
+        assert!(body.contains("Data from device 987654321 was rejected at "));
+
+        let (status, body) = admin_request(&app, "GET", "/admin/api/sessions".to_owned()).await;
+        assert_eq!(StatusCode::OK, status);
+        let sessions: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(123456789, sessions[0]["device_serial_number"]);
+        assert_eq!(987654321, sessions[0]["rejected_device"]["serial_number"]);
+    }
+
+    #[tokio::test]
+    async fn opted_in_results_are_kept_in_a_history_behind_magic_links() {
+        let path = std::env::temp_dir().join(format!("history-{}.json", Uuid::new_v4()));
+        let history = Arc::new(Mutex::new(
+            History::load(&path, Duration::from_secs(24 * 60 * 60)).unwrap(),
+        ));
+        let server_state = ServerState {
+            history: Some(history.clone()),
+            ..server_state()
+        };
+        let app = app_from(server_state.clone());
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/session/create")
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(Body::from(
+                        "email=a%40example.com&history=true&time_zone=Asia%2FTokyo",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let (id, token) = session_credentials(&server_state);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post(format!("/session/{}/data/{}", id, token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(include_str!(
+                        "../../tests/fixtures/synthetic/test-finished.json"
+                    )))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let email = EmailAddress::from_str("a@example.com").unwrap();
+        let link = history.lock().unwrap().create_link(&email).unwrap();
+        let (status, body) = admin_request(&app, "GET", format!("/history/{}", link)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.contains("123456789"));
+        assert!(body.contains("COVID-19"));
+        // Shown in the time zone the session was created in.
+        assert!(body.contains("JST"));
+        let (status, body) =
+            admin_request(&app, "GET", format!("/history/{}/export.csv", link)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(2, body.lines().count());
+        assert!(body.contains(&format!("{},123456789,COVID-19", id)));
+
+        let (status, body) = admin_request(&app, "POST", format!("/history/{}/delete", link)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.contains("Your history has been deleted."));
+        assert!(history.lock().unwrap().records(&email).is_empty());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get_status(&app, format!("/history/{}", link)).await
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    async fn api_request(app: &Router, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        )
+    }
+
+    #[tokio::test]
+    async fn sessions_can_be_created_and_followed_through_the_json_api() {
+        let (server_state, transport) = recording_server_state();
+        let app = app_from(server_state.clone());
+        let (status, created) = api_request(
+            &app,
+            Request::post("/api/v1/sessions")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"email": "a@example.com", "locale": "de"}"#))
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(StatusCode::CREATED, status);
+        assert_eq!("waiting_for_data", created["state"]);
+        assert!(created["expires"].is_string());
+        assert!(
+            created["viewer_url"]
+                .as_str()
+                .unwrap()
+                .starts_with("http://localhost/session/")
+        );
+        let webhook = created["webhook_url"]
+            .as_str()
+            .unwrap()
+            .strip_prefix("http://localhost")
+            .unwrap()
+            .to_owned();
+        let token = webhook.rsplit('/').next().unwrap().to_owned();
+        let status_path = format!("/api/v1/sessions/{}", created["id"].as_str().unwrap());
+
+        assert_eq!(StatusCode::OK, post_data(&app, webhook.clone()).await);
+        let (status, running) = api_request(
+            &app,
+            Request::get(&status_path)
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("running", running["state"]);
+        assert!(running["samples"].as_u64().unwrap() > 0);
+        assert!(running["result"].is_null());
+        let (status, _) = api_request(
+            &app,
+            Request::get(format!("{}?token={}", status_path, token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            get_status(&app, status_path.clone()).await
+        );
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post(&webhook)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(include_str!(
+                        "../../tests/fixtures/synthetic/test-finished.json"
+                    )))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let (status, completed) = api_request(
+            &app,
+            Request::get(&status_path)
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("completed", completed["state"]);
+        assert_eq!(21, completed["samples"]);
+        assert_eq!("NEGATIVE", completed["result"]["overall"]);
+        server_state.tasks.close();
+        server_state.tasks.wait().await;
+        assert_eq!(
+            vec![("a@example.com".to_owned(), "result")],
+            transport.sent()
+        );
+
+        let (status, document) = api_request(
+            &app,
+            Request::get("/api/v1/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(document["paths"]["/api/v1/sessions"]["post"].is_object());
+        assert!(document["paths"]["/api/v1/sessions/{id}"]["get"].is_object());
+        assert!(document["components"]["schemas"]["ApiSession"].is_object());
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn client_drives_a_session_end_to_end() {
+        use pluslife_notifier::{
+            Error,
+            api::{ApiSessionState, CreateSessionRequest},
+            client::Client,
+            messages::{DetectionResult, v1::MessageV1},
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let server_state = ServerState {
+            base_url: base_url.clone(),
+            ..server_state()
+        };
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                super::app(server_state).into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        let fixture = |json: &str| -> MessageV1 { serde_json::from_str(json).unwrap() };
+
+        let client = Client::new(base_url);
+        let session = client
+            .create_session(&CreateSessionRequest {
+                email: "a@example.com".to_owned(),
+                ..CreateSessionRequest::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(ApiSessionState::WaitingForData, session.state);
+        let viewer_url = session.viewer_url.clone().unwrap();
+        let mut updates = client.subscribe(&viewer_url).await.unwrap();
+        assert!(updates.next().await.unwrap().unwrap().results.is_none());
+
+        client
+            .post_message(
+                &session.webhook_url,
+                &fixture(include_str!("../../tests/fixtures/synthetic/new-data.json")),
+            )
+            .await
+            .unwrap();
+        let update = updates.next().await.unwrap().unwrap();
+        assert!(update.graph_png_base64.is_some());
+        assert!(!client.graph_png(&viewer_url).await.unwrap().is_empty());
+
+        client
+            .post_message(
+                &session.webhook_url,
+                &fixture(include_str!(
+                    "../../tests/fixtures/synthetic/test-finished.json"
+                )),
+            )
+            .await
+            .unwrap();
+        let results = updates.next().await.unwrap().unwrap().results.unwrap();
+        assert_eq!(DetectionResult::Negative, results.overall);
+
+        let ingest_token = session.webhook_url.rsplit('/').next().unwrap();
+        let finished = client.session(&session.id, ingest_token).await.unwrap();
+        assert_eq!(ApiSessionState::Completed, finished.state);
+        match client.session(&session.id, "wrong").await {
+            Err(Error::Api { status: 401, .. }) => {}
+            other => panic!("Expected a 401 but got {:?}", other.map(|_| ())),
+        }
+    }
+
+    async fn post_batch(app: &Router, emails: &[&str]) -> Response {
+        let tests: Vec<serde_json::Value> = emails
+            .iter()
+            .map(|email| serde_json::json!({"label": email, "email": email}))
+            .collect();
+        app.clone()
+            .oneshot(
+                Request::post("/batch/create")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::json!({"coordinator": "lab@example.com", "tests": tests})
+                            .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn batches_count_every_test_against_the_creators_limits() {
+        let capped = ServerState {
+            max_active_sessions_per_ip: 1,
+            ..server_state()
+        };
+        // A batch of 2 would give the creator two active sessions.
+        let app = app_from(capped.clone());
+        let response = post_batch(&app, &["a@x.com", "b@x.com"]).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert!(!response.headers().contains_key(RETRY_AFTER));
+        assert_eq!(0, capped.sessions.lock().unwrap().len());
+
+        let server_state = ServerState {
+            max_active_sessions_per_email: 1,
+            ..server_state()
+        };
+        let app = app_from(server_state.clone());
+        // The IP may create 3 sessions an hour, so a batch of 4 could never succeed and isn't worth retrying.
+        let response = post_batch(&app, &["a@x.com", "b@x.com", "c@x.com", "d@x.com"]).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert!(!response.headers().contains_key(RETRY_AFTER));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            "At most 3 sessions can be created at once from one address",
+            String::from_utf8_lossy(&body)
+        );
+        assert_eq!(0, server_state.sessions.lock().unwrap().len());
+
+        // The same address twice would give it two active sessions.
+        let response = post_batch(&app, &["a@x.com", "a@x.com"]).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert!(!response.headers().contains_key(RETRY_AFTER));
+
+        assert_eq!(StatusCode::OK, create_session(&app, "e@x.com").await);
+        let response = post_batch(&app, &["a@x.com", "b@x.com", "c@x.com"]).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert!(response.headers().contains_key(RETRY_AFTER));
+        assert_eq!(1, server_state.sessions.lock().unwrap().len());
+        assert_eq!(
+            StatusCode::OK,
+            post_batch(&app, &["a@x.com", "b@x.com"]).await.status()
+        );
+        assert_eq!(3, server_state.sessions.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn batches_refused_by_one_limit_use_up_none_of_the_others() {
+        let server_state = server_state();
+        let exhausted: EmailAddress = "z@x.com".parse().unwrap();
+        server_state
+            .session_rate_limit_per_email
+            .lock()
+            .unwrap()
+            .check_n(exhausted, 2)
+            .unwrap();
+        let app = app_from(server_state.clone());
+        let response = post_batch(&app, &["a@x.com", "b@x.com", "z@x.com"]).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert!(response.headers().contains_key(RETRY_AFTER));
+        assert_eq!(0, server_state.sessions.lock().unwrap().len());
+
+        // Neither the IP nor the other addresses were charged for the refused batch.
+        let a: EmailAddress = "a@x.com".parse().unwrap();
+        assert!(
+            server_state
+                .session_rate_limit_per_email
+                .lock()
+                .unwrap()
+                .peek_n(&a, 2)
+                .is_ok()
+        );
+        assert_eq!(
+            StatusCode::OK,
+            post_batch(&app, &["a@x.com", "b@x.com", "c@x.com"])
+                .await
+                .status()
+        );
+        assert_eq!(3, server_state.sessions.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn batches_create_sessions_with_a_dashboard_and_printable_sheet() {
+        let (server_state, transport) = recording_server_state();
+        let app = app_from(server_state.clone());
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/batch/create")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        r#"{"coordinator": "lab@example.com", "tests": [
+                            {"label": "Alice", "email": "a@example.com"},
+                            {"label": "Bob", "email": "b@example.com"}
+                        ]}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let dashboard = created["dashboard_url"]
+            .as_str()
+            .unwrap()
+            .strip_prefix("http://localhost")
+            .unwrap()
+            .to_owned();
+        let tests = created["tests"].as_array().unwrap();
+        assert_eq!(2, tests.len());
+
+        let (status, body) = admin_request(&app, "GET", dashboard.clone()).await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.contains("Batch of 2 tests"));
+        assert_eq!(2, body.matches("Waiting for data").count());
+        let (status, body) = admin_request(&app, "GET", format!("{}/sheet", dashboard)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(2, body.matches("<svg").count());
+        assert!(body.contains(tests[1]["webhook_url"].as_str().unwrap()));
+
+        for test in tests {
+            let webhook = test["webhook_url"]
+                .as_str()
+                .unwrap()
+                .strip_prefix("http://localhost")
+                .unwrap();
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::post(webhook)
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from(include_str!(
+                            "../../tests/fixtures/synthetic/test-finished.json"
+                        )))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+        }
+        let (status, body) = admin_request(&app, "GET", dashboard.clone()).await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(body.contains("Every test has finished."));
+        assert!(body.contains("lab@example.com"));
+        assert!(!body.contains("http-equiv=\"refresh\""));
+        server_state.tasks.close();
+        server_state.tasks.wait().await;
+        let mut sent = transport.sent();
+        sent.sort();
+        assert_eq!(
+            vec![
+                ("a@example.com".to_owned(), "result"),
+                ("b@example.com".to_owned(), "result"),
+                ("lab@example.com".to_owned(), "batch_summary")
+            ],
+            sent
+        );
+
+        let wrong_token = format!("{}x", dashboard);
+        assert_eq!(StatusCode::NOT_FOUND, get_status(&app, wrong_token).await);
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_session_creation_and_saves_sessions_for_restart() {
+        let state_file = std::env::temp_dir().join(format!("sessions-{}.json", Uuid::new_v4()));
+        let running = ServerState {
+            state_file: Some(state_file.clone()),
+            ..server_state()
+        };
+        let app = app_from(running.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&running);
+        assert_eq!(
+            StatusCode::OK,
+            post_data(&app, format!("/session/{}/data/{}", id, token)).await
+        );
+
+        running.begin_shutdown().await;
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            create_session(&app, "b@example.com").await
+        );
+        running.finish_shutdown(Duration::from_secs(1)).await;
+
+        let restarted = ServerState {
+            state_file: Some(state_file.clone()),
+            ..server_state()
+        };
+        assert_eq!(1, restarted.restore_sessions().unwrap());
+        assert!(!state_file.exists());
+        let (restored_id, restored_token) = session_credentials(&restarted);
+        assert_eq!((id, token.clone()), (restored_id, restored_token));
+        let (_, body) = admin_request(
+            &app_from(ServerState {
+                admin_token: Some("secret".to_owned()),
+                ..restarted.clone()
+            }),
+            "GET",
+            "/admin/api/sessions".to_owned(),
+        )
+        .await;
+        let sessions: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(21, sessions[0]["state"]["samples"]);
+        assert_eq!(
+            StatusCode::OK,
+            post_data(
+                &app_from(restarted),
+                format!("/session/{}/data/{}", id, token)
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn restored_sessions_keep_deadlines_extended_by_new_data() {
+        let state_file = std::env::temp_dir().join(format!("sessions-{}.json", Uuid::new_v4()));
+        let running = ServerState {
+            state_file: Some(state_file.clone()),
+            ..server_state()
+        };
+        let app = app_from(running.clone());
+        assert_eq!(StatusCode::OK, create_session(&app, "a@example.com").await);
+        let (id, token) = session_credentials(&running);
+        // A test started more than a cleanup period ago, which is still running as data keeps arriving.
+        running
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .unwrap()
+            .created = Timestamp::now() - Duration::from_secs(2 * 60 * 60);
+        assert_eq!(
+            StatusCode::OK,
+            post_data(&app, format!("/session/{}/data/{}", id, token)).await
+        );
+        let deadline = running.expiry.deadline(&id).unwrap();
+        running.finish_shutdown(Duration::from_secs(1)).await;
+
+        let restarted = ServerState {
+            state_file: Some(state_file.clone()),
+            ..server_state()
+        };
+        assert_eq!(1, restarted.restore_sessions().unwrap());
+        let restored = restarted.expiry.deadline(&id).unwrap();
+        assert!(restored.duration_since(deadline).abs() < SignedDuration::from_secs(1));
+    }
+
+    async fn dump(app: Router, authorization: Option<&str>) -> StatusCode {
+        let mut request = Request::post("/dump").header(CONTENT_TYPE, "application/json");
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        app.oneshot(request.body(Body::from("{}")).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn dump_can_be_disabled_or_require_admin_token() {
+        let disabled = app_from(ServerState {
+            dump_mode: DumpMode::Disabled,
+            ..server_state()
+        });
+        assert_eq!(StatusCode::NOT_FOUND, dump(disabled, None).await);
+
+        let admin = app_from(ServerState {
+            dump_mode: DumpMode::Admin,
+            admin_token: Some("secret".to_owned()),
+            ..server_state()
+        });
+        assert_eq!(StatusCode::UNAUTHORIZED, dump(admin.clone(), None).await);
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            dump(admin.clone(), Some("Bearer wrong")).await
+        );
+        assert_eq!(StatusCode::OK, dump(admin, Some("Bearer secret")).await);
+    }
+}
@@ -0,0 +1,147 @@
+use futures_util::StreamExt;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async, tungstenite};
+use uuid::Uuid;
+
+use crate::{
+    Error,
+    api::{ApiSession, CreateSessionRequest},
+    messages::v1::MessageV1,
+    sessions::RawMessage,
+    websockets::WebsocketMessage,
+};
+
+// A typed client for tools which talk to the server, built on the same request, response and message types the server
+// uses, so a change to one side which the other doesn't understand fails to compile rather than at runtime.
+//
+// Sessions are addressed by the URLs the server hands out (the webhook and viewer URLs in ApiSession), as those carry
+// the tokens which grant access, so the client never needs to know how they are put together.
+
+pub struct Client {
+    base_url: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// `base_url` is where the server is reachable, e.g. "https://results.wang", without a trailing slash.
+    pub fn new(base_url: impl Into<String>) -> Client {
+        Client {
+            base_url: base_url.into(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn create_session(
+        &self,
+        request: &CreateSessionRequest,
+    ) -> Result<ApiSession, Error> {
+        let response = self
+            .http
+            .post(format!("{}/api/v1/sessions", self.base_url))
+            .json(request)
+            .send()
+            .await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    pub async fn session(&self, id: &Uuid, ingest_token: &str) -> Result<ApiSession, Error> {
+        let response = self
+            .http
+            .get(format!("{}/api/v1/sessions/{}", self.base_url, id))
+            .bearer_auth(ingest_token)
+            .send()
+            .await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    /// Posts a message to a session's webhook URL, as the device would.
+    pub async fn post_message(&self, webhook_url: &str, message: &MessageV1) -> Result<(), Error> {
+        let response = self.http.post(webhook_url).json(message).send().await?;
+        check(response).await?;
+        Ok(())
+    }
+
+    /// Connects to a session's live updates. The server sends its current state straight away.
+    pub async fn subscribe(&self, viewer_url: &str) -> Result<Subscription, Error> {
+        let url = format!("{}/updates", viewer_url)
+            .replacen("http://", "ws://", 1)
+            .replacen("https://", "wss://", 1);
+        let (socket, _) = connect_async(url).await?;
+        Ok(Subscription { socket })
+    }
+
+    /// The graph as it stands, as a PNG.
+    pub async fn graph_png(&self, viewer_url: &str) -> Result<Vec<u8>, Error> {
+        let response = self
+            .http
+            .get(format!("{}/graph.png", viewer_url))
+            .send()
+            .await?;
+        Ok(check(response).await?.bytes().await?.to_vec())
+    }
+
+    /// The messages the session has received, oldest first. Needs the admin token.
+    pub async fn export_messages(
+        &self,
+        id: &Uuid,
+        admin_token: &str,
+    ) -> Result<Vec<RawMessage>, Error> {
+        let response = self
+            .http
+            .get(format!(
+                "{}/admin/api/sessions/{}/messages",
+                self.base_url, id
+            ))
+            .bearer_auth(admin_token)
+            .send()
+            .await?;
+        let jsonl = check(response).await?.text().await?;
+        Ok(jsonl
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// A test history as CSV, given the token from a history link.
+    pub async fn export_history(&self, token: &str) -> Result<String, Error> {
+        let response = self
+            .http
+            .get(format!("{}/history/{}/export.csv", self.base_url, token))
+            .send()
+            .await?;
+        Ok(check(response).await?.text().await?)
+    }
+}
+
+pub struct Subscription {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Subscription {
+    /// Waits for the next update, or returns None once the server closes the connection.
+    pub async fn next(&mut self) -> Option<Result<WebsocketMessage, Error>> {
+        loop {
+            match self.socket.next().await? {
+                Ok(tungstenite::Message::Text(text)) => {
+                    return Some(serde_json::from_str(&text).map_err(Into::into));
+                }
+                Ok(tungstenite::Message::Close(_)) => return None,
+                Ok(_) => continue,
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+}
+
+/// Turns an unsuccessful response into an error carrying the server's explanation.
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response)
+    } else {
+        Err(Error::Api {
+            status: status.as_u16(),
+            message: response.text().await.unwrap_or_default(),
+        })
+    }
+}
@@ -0,0 +1,682 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::{Args, Parser};
+use email_address::EmailAddress;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    Error,
+    emails::EmailTemplates,
+    expiry::MAX_DELAY,
+    mailgun::Region,
+    messages::ParseMode,
+    rate_limit::TokenBucket,
+    sessions::DumpMode,
+    telemetry::{LogFormat, TelemetryConfig},
+};
+
+// Settings are layered: a TOML file (--config or CONFIG_FILE) first, then environment variables, then command line
+// flags, with later layers taking precedence. Every setting is read as a string whichever layer it comes from, and
+// only parsed once the layers have been merged, so that all problems can be reported together.
+
+#[derive(Parser)]
+#[command(about = "Emails PlusLife test results")]
+pub struct ConfigArgs {
+    /// A TOML file to read settings from, using the same names as the flags below with underscores, e.g. base_url.
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration, report every problem found, and exit.
+    #[arg(long)]
+    pub check_config: bool,
+
+    #[command(flatten)]
+    pub settings: Settings,
+}
+
+#[derive(Args, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The address to listen on, e.g. 0.0.0.0 for all IPv4 interfaces or :: for IPv6 (and usually IPv4) too.
+    #[arg(long, env = "BIND_ADDRESS")]
+    pub bind_address: Option<String>,
+    #[arg(long, env = "PORT")]
+    pub port: Option<String>,
+    /// The public URL of this server, used in links, e.g. https://pluslife.example.com
+    #[arg(long, env = "BASE_URL")]
+    pub base_url: Option<String>,
+    #[arg(long, env = "SENDER_EMAIL")]
+    pub sender_email: Option<String>,
+    #[arg(long, env = "MAILGUN_DOMAIN")]
+    pub mailgun_domain: Option<String>,
+    #[arg(long, env = "MAILGUN_API_KEY", hide_env_values = true)]
+    pub mailgun_api_key: Option<String>,
+    /// Either eu or us.
+    #[arg(long, env = "MAILGUN_REGION")]
+    pub mailgun_region: Option<String>,
+    /// How long a session waits for a result before it is deleted, e.g. 2h.
+    #[arg(long, env = "CLEANUP_PERIOD")]
+    pub cleanup_period: Option<String>,
+    #[arg(long, env = "COMPLETED_RETENTION")]
+    pub completed_retention: Option<String>,
+    /// How long a session can go without new data before its recipients are warned, e.g. 15m.
+    #[arg(long, env = "INACTIVITY_WARNING")]
+    pub inactivity_warning: Option<String>,
+    #[arg(long, env = "REQUIRE_EMAIL_VERIFICATION")]
+    pub require_email_verification: Option<String>,
+    #[arg(long, env = "VERIFICATION_MEMORY")]
+    pub verification_memory: Option<String>,
+    #[arg(long, env = "EMAIL_TEMPLATE_DIR")]
+    pub email_template_dir: Option<String>,
+    /// A TOML file of panel definitions to use instead of the builtin ones.
+    #[arg(long, env = "PANELS_FILE")]
+    pub panels_file: Option<String>,
+    /// e.g. 10/1h for a burst of up to 10, refilling at 10 per hour.
+    #[arg(long, env = "SESSION_RATE_LIMIT_PER_IP")]
+    pub session_rate_limit_per_ip: Option<String>,
+    #[arg(long, env = "SESSION_RATE_LIMIT_PER_EMAIL")]
+    pub session_rate_limit_per_email: Option<String>,
+    #[arg(long, env = "MAX_ACTIVE_SESSIONS_PER_IP")]
+    pub max_active_sessions_per_ip: Option<String>,
+    #[arg(long, env = "MAX_ACTIVE_SESSIONS_PER_EMAIL")]
+    pub max_active_sessions_per_email: Option<String>,
+    #[arg(long, env = "MAX_DATA_BODY_BYTES")]
+    pub max_data_body_bytes: Option<String>,
+    #[arg(long, env = "TRUST_FORWARDED_FOR")]
+    pub trust_forwarded_for: Option<String>,
+    /// One of lenient (the default) or strict, which rejects messages with fields or values we don't recognise.
+    #[arg(long, env = "PARSE_MODE")]
+    pub parse_mode: Option<String>,
+    /// One of open, admin or disabled. Defaults to admin if admin_token is set, and disabled otherwise, as dumped
+    /// messages include device serial numbers.
+    #[arg(long, env = "DUMP_MODE")]
+    pub dump_mode: Option<String>,
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    /// A directory to archive the raw messages of sessions which opt in to it. Archiving is unavailable if unset.
+    #[arg(long, env = "ARCHIVE_DIR")]
+    pub archive_dir: Option<String>,
+    /// How long archived messages are kept for, e.g. 14d.
+    #[arg(long, env = "ARCHIVE_RETENTION")]
+    pub archive_retention: Option<String>,
+    /// A file to keep the test history of addresses which opt in to it. History is unavailable if unset.
+    #[arg(long, env = "HISTORY_FILE")]
+    pub history_file: Option<String>,
+    /// How long test history is kept for, e.g. 365d.
+    #[arg(long, env = "HISTORY_RETENTION")]
+    pub history_retention: Option<String>,
+    /// How often a link to an address's history can be requested, e.g. 3/1h. Separate from the session limits, so
+    /// looking up past results doesn't use up an address's allowance for new tests.
+    #[arg(long, env = "HISTORY_LINK_RATE_LIMIT")]
+    pub history_link_rate_limit: Option<String>,
+    /// A file to keep the device registry in across restarts. The registry is only kept in memory if unset.
+    #[arg(long, env = "DEVICE_REGISTRY_FILE")]
+    pub device_registry_file: Option<String>,
+    /// A comma-separated list of device software versions known to work, e.g. 1.2.3,1.2.4. Devices reporting any
+    /// other version are flagged in the admin API. Nothing is flagged if unset.
+    #[arg(long, env = "KNOWN_GOOD_FIRMWARE")]
+    pub known_good_firmware: Option<String>,
+    /// A file to save sessions which are still waiting for a result to on shutdown, and to restore them from on startup.
+    #[arg(long, env = "STATE_FILE")]
+    pub state_file: Option<String>,
+    /// How long to wait for notifications which are still being sent when shutting down, e.g. 30s.
+    #[arg(long, env = "SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<String>,
+    /// A RUST_LOG-style filter, e.g. info,pluslife_notifier=debug
+    #[arg(long, env = "RUST_LOG")]
+    pub log_filter: Option<String>,
+    /// Either pretty or json.
+    #[arg(long, env = "LOG_FORMAT")]
+    pub log_format: Option<String>,
+    /// The base URL of an OTLP/HTTP collector to export spans to, e.g. http://localhost:4318
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+}
+
+pub struct Config {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub base_url: String,
+    pub websocket_base_url: String,
+    pub sender_email: EmailAddress,
+    pub mailgun_domain: String,
+    pub mailgun_api_key: String,
+    pub mailgun_region: Region,
+    pub cleanup_period: Duration,
+    pub completed_retention: Duration,
+    pub inactivity_warning: Duration,
+    pub require_email_verification: bool,
+    pub verification_memory: Duration,
+    pub email_templates: EmailTemplates,
+    pub panels_file: Option<PathBuf>,
+    pub session_rate_limit_per_ip: TokenBucket,
+    pub session_rate_limit_per_email: TokenBucket,
+    pub max_active_sessions_per_ip: usize,
+    pub max_active_sessions_per_email: usize,
+    pub max_data_body_bytes: usize,
+    pub trust_forwarded_for: bool,
+    pub parse_mode: ParseMode,
+    pub dump_mode: DumpMode,
+    pub admin_token: Option<String>,
+    pub archive_dir: Option<PathBuf>,
+    pub archive_retention: Duration,
+    pub history_file: Option<PathBuf>,
+    pub history_retention: Duration,
+    pub history_link_rate_limit: TokenBucket,
+    pub device_registry_file: Option<PathBuf>,
+    pub known_good_firmware: Option<Vec<String>>,
+    pub state_file: Option<PathBuf>,
+    pub shutdown_timeout: Duration,
+    pub telemetry: TelemetryConfig,
+}
+
+impl Config {
+    /// Merges the config file (if any) with the settings from the environment and command line, and validates them.
+    pub fn load(args: &ConfigArgs) -> Result<Config, Vec<Error>> {
+        let mut settings = match &args.config {
+            Some(path) => read_file(path).map_err(|err| vec![err])?,
+            None => toml::Table::new(),
+        };
+        let overrides = toml::Table::try_from(&args.settings).map_err(|err| {
+            vec![Error::InvalidConfig {
+                name: "command line".to_owned(),
+                cause: Box::new(err),
+            }]
+        })?;
+        settings.extend(overrides);
+        let settings = Settings::deserialize(settings).map_err(|err| {
+            vec![Error::InvalidConfig {
+                name: args
+                    .config
+                    .as_ref()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default(),
+                cause: Box::new(err),
+            }]
+        })?;
+        Config::from_settings(settings)
+    }
+
+    pub fn from_settings(settings: Settings) -> Result<Config, Vec<Error>> {
+        let mut v = Validator::default();
+
+        let base_url = v.required("base_url", settings.base_url, |base_url| {
+            match base_url.strip_prefix("http") {
+                Some(suffix) => Ok((base_url.clone(), format!("ws{}", suffix))),
+                None => Err(format!(
+                    "Expected base_url to start with http but was '{}'",
+                    base_url
+                )),
+            }
+        });
+        let sender_email = v.required("sender_email", settings.sender_email, |email| {
+            EmailAddress::from_str(&email)
+        });
+        let mailgun_domain = v.required(
+            "mailgun_domain",
+            settings.mailgun_domain,
+            Ok::<_, Infallible>,
+        );
+        let mailgun_api_key = v.required(
+            "mailgun_api_key",
+            settings.mailgun_api_key,
+            Ok::<_, Infallible>,
+        );
+        let port = v.required("port", settings.port, |port| port.parse::<u16>());
+        let cleanup_period = v.required("cleanup_period", settings.cleanup_period, |value| {
+            parse_delay(&value)
+        });
+
+        let bind_address = v.or_default(
+            "bind_address",
+            settings.bind_address,
+            |address| address.parse(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        );
+        let mailgun_region = v.or_default(
+            "mailgun_region",
+            settings.mailgun_region,
+            |region| region.parse(),
+            Region::EU,
+        );
+        // Completed sessions are kept briefly so delivery status can be checked and late viewers can see the result.
+        let completed_retention = v.or_default(
+            "completed_retention",
+            settings.completed_retention,
+            |value| parse_delay(&value),
+            Duration::from_secs(60 * 60),
+        );
+        let inactivity_warning = v.or_default(
+            "inactivity_warning",
+            settings.inactivity_warning,
+            |value| parse_delay(&value),
+            Duration::from_secs(15 * 60),
+        );
+        let require_email_verification = v.or_default(
+            "require_email_verification",
+            settings.require_email_verification,
+            |value| value.parse(),
+            false,
+        );
+        let verification_memory = v.or_default(
+            "verification_memory",
+            settings.verification_memory,
+            |value| parse_duration(&value),
+            Duration::from_secs(30 * 24 * 60 * 60),
+        );
+        let email_templates = v.or_default(
+            "email_template_dir",
+            settings.email_template_dir,
+            |dir| EmailTemplates::with_overrides(Path::new(&dir)),
+            EmailTemplates::builtin(),
+        );
+        let panels_file = v.optional("panels_file", settings.panels_file, |path| {
+            std::fs::metadata(&path).map(|_| PathBuf::from(path))
+        });
+        let session_rate_limit_per_ip = v.or_default(
+            "session_rate_limit_per_ip",
+            settings.session_rate_limit_per_ip,
+            |value| value.parse(),
+            TokenBucket {
+                capacity: 10,
+                period: Duration::from_secs(60 * 60),
+            },
+        );
+        let session_rate_limit_per_email = v.or_default(
+            "session_rate_limit_per_email",
+            settings.session_rate_limit_per_email,
+            |value| value.parse(),
+            TokenBucket {
+                capacity: 5,
+                period: Duration::from_secs(60 * 60),
+            },
+        );
+        let max_active_sessions_per_ip = v.or_default(
+            "max_active_sessions_per_ip",
+            settings.max_active_sessions_per_ip,
+            |value| value.parse(),
+            20,
+        );
+        let max_active_sessions_per_email = v.or_default(
+            "max_active_sessions_per_email",
+            settings.max_active_sessions_per_email,
+            |value| value.parse(),
+            5,
+        );
+        let max_data_body_bytes = v.or_default(
+            "max_data_body_bytes",
+            settings.max_data_body_bytes,
+            |value| value.parse(),
+            256 * 1024,
+        );
+        let trust_forwarded_for = v.or_default(
+            "trust_forwarded_for",
+            settings.trust_forwarded_for,
+            |value| value.parse(),
+            false,
+        );
+        let parse_mode = v.or_default(
+            "parse_mode",
+            settings.parse_mode,
+            |value| value.parse(),
+            ParseMode::Lenient,
+        );
+        let admin_token = settings.admin_token;
+        let dump_mode = v.or_default(
+            "dump_mode",
+            settings.dump_mode,
+            |value| value.parse(),
+            if admin_token.is_some() {
+                DumpMode::Admin
+            } else {
+                DumpMode::Disabled
+            },
+        );
+        if dump_mode == DumpMode::Admin && admin_token.is_none() {
+            v.errors.push(Error::InvalidConfig {
+                name: "dump_mode".to_owned(),
+                cause: "dump_mode=admin requires admin_token to be set".into(),
+            });
+        }
+        let archive_dir = v.optional("archive_dir", settings.archive_dir, |dir| {
+            Ok::<_, Infallible>(PathBuf::from(dir))
+        });
+        let archive_retention = v.or_default(
+            "archive_retention",
+            settings.archive_retention,
+            |value| parse_duration(&value),
+            Duration::from_secs(14 * 24 * 60 * 60),
+        );
+        let history_file = v.optional("history_file", settings.history_file, |path| {
+            Ok::<_, Infallible>(PathBuf::from(path))
+        });
+        let history_retention = v.or_default(
+            "history_retention",
+            settings.history_retention,
+            |value| parse_duration(&value),
+            Duration::from_secs(365 * 24 * 60 * 60),
+        );
+        let history_link_rate_limit = v.or_default(
+            "history_link_rate_limit",
+            settings.history_link_rate_limit,
+            |value| value.parse(),
+            TokenBucket {
+                capacity: 3,
+                period: Duration::from_secs(60 * 60),
+            },
+        );
+        let device_registry_file = v.optional(
+            "device_registry_file",
+            settings.device_registry_file,
+            |path| Ok::<_, Infallible>(PathBuf::from(path)),
+        );
+        let known_good_firmware = v.optional(
+            "known_good_firmware",
+            settings.known_good_firmware,
+            |list| {
+                Ok::<_, Infallible>(
+                    list.split(',')
+                        .map(str::trim)
+                        .filter(|version| !version.is_empty())
+                        .map(str::to_owned)
+                        .collect(),
+                )
+            },
+        );
+        let state_file = v.optional("state_file", settings.state_file, |path| {
+            Ok::<_, Infallible>(PathBuf::from(path))
+        });
+        let shutdown_timeout = v.or_default(
+            "shutdown_timeout",
+            settings.shutdown_timeout,
+            |value| parse_duration(&value),
+            Duration::from_secs(30),
+        );
+        let log_filter = v.or_default(
+            "log_filter",
+            settings.log_filter,
+            |filter| EnvFilter::try_new(&filter).map(|_| filter),
+            "info".to_owned(),
+        );
+        let log_format = v.or_default(
+            "log_format",
+            settings.log_format,
+            |format| format.parse(),
+            LogFormat::Pretty,
+        );
+
+        let (
+            Some((base_url, websocket_base_url)),
+            Some(sender_email),
+            Some(mailgun_domain),
+            Some(mailgun_api_key),
+            Some(port),
+            Some(cleanup_period),
+        ) = (
+            base_url,
+            sender_email,
+            mailgun_domain,
+            mailgun_api_key,
+            port,
+            cleanup_period,
+        )
+        else {
+            return Err(v.errors);
+        };
+        if !v.errors.is_empty() {
+            return Err(v.errors);
+        }
+        Ok(Config {
+            bind_address,
+            port,
+            base_url,
+            websocket_base_url,
+            sender_email,
+            mailgun_domain,
+            mailgun_api_key,
+            mailgun_region,
+            cleanup_period,
+            completed_retention,
+            inactivity_warning,
+            require_email_verification,
+            verification_memory,
+            email_templates,
+            panels_file,
+            session_rate_limit_per_ip,
+            session_rate_limit_per_email,
+            max_active_sessions_per_ip,
+            max_active_sessions_per_email,
+            max_data_body_bytes,
+            trust_forwarded_for,
+            parse_mode,
+            dump_mode,
+            admin_token,
+            archive_dir,
+            archive_retention,
+            history_file,
+            history_retention,
+            history_link_rate_limit,
+            device_registry_file,
+            known_good_firmware,
+            state_file,
+            shutdown_timeout,
+            telemetry: TelemetryConfig {
+                filter: log_filter,
+                format: log_format,
+                otlp_endpoint: settings
+                    .otlp_endpoint
+                    .filter(|endpoint| !endpoint.is_empty()),
+            },
+        })
+    }
+}
+
+/// Reads a TOML config file, turning numbers and booleans into strings so they're parsed like any other setting.
+fn read_file(path: &Path) -> Result<toml::Table, Error> {
+    let invalid = |cause: Box<dyn std::error::Error + Send + Sync>| Error::InvalidConfig {
+        name: path.display().to_string(),
+        cause,
+    };
+    let source = std::fs::read_to_string(path).map_err(|err| invalid(Box::new(err)))?;
+    let table: toml::Table = toml::from_str(&source).map_err(|err| invalid(Box::new(err)))?;
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                toml::Value::Datetime(value) => value.to_string(),
+                toml::Value::Array(_) | toml::Value::Table(_) => {
+                    return Err(invalid(
+                        format!("Expected '{}' to be a single value", key).into(),
+                    ));
+                }
+            };
+            Ok((key, toml::Value::String(value)))
+        })
+        .collect()
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    duration_str::parse(value).map_err(|err| format!("Failed to parse duration {}: {}", value, err))
+}
+
+/// Parses a duration which sessions are scheduled with, which mustn't exceed what the scheduler can handle.
+fn parse_delay(value: &str) -> Result<Duration, String> {
+    let duration = parse_duration(value)?;
+    if duration > MAX_DELAY {
+        return Err(format!(
+            "Expected at most {}d but was {}",
+            MAX_DELAY.as_secs() / (24 * 60 * 60),
+            value
+        ));
+    }
+    Ok(duration)
+}
+
+#[derive(Default)]
+struct Validator {
+    errors: Vec<Error>,
+}
+
+impl Validator {
+    fn optional<T, E>(
+        &mut self,
+        name: &str,
+        value: Option<String>,
+        parse: impl FnOnce(String) -> Result<T, E>,
+    ) -> Option<T>
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    {
+        match parse(value?) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.errors.push(Error::InvalidConfig {
+                    name: name.to_owned(),
+                    cause: err.into(),
+                });
+                None
+            }
+        }
+    }
+
+    fn required<T, E>(
+        &mut self,
+        name: &str,
+        value: Option<String>,
+        parse: impl FnOnce(String) -> Result<T, E>,
+    ) -> Option<T>
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    {
+        if value.is_none() {
+            self.errors.push(Error::InvalidConfig {
+                name: name.to_owned(),
+                cause: "Missing required setting".into(),
+            });
+        }
+        self.optional(name, value, parse)
+    }
+
+    fn or_default<T, E>(
+        &mut self,
+        name: &str,
+        value: Option<String>,
+        parse: impl FnOnce(String) -> Result<T, E>,
+        default: T,
+    ) -> T
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync + 'static>>,
+    {
+        self.optional(name, value, parse).unwrap_or(default)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::{Config, ConfigArgs};
+    use crate::{Error, mailgun::Region, rate_limit::TokenBucket, sessions::DumpMode};
+
+    /// Parses the flags alone, so settings in the environment the tests run in can't change the outcome.
+    fn parse_flags(flags: &[&OsStr]) -> ConfigArgs {
+        let matches = ConfigArgs::command()
+            .mut_args(|arg| arg.env(None::<&str>))
+            .try_get_matches_from(flags)
+            .unwrap();
+        ConfigArgs::from_arg_matches(&matches).unwrap()
+    }
+
+    fn write_config(contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn flags_override_config_file() {
+        let path = write_config(
+            r#"
+            base_url = "https://pluslife.example.com"
+            sender_email = "results@example.com"
+            mailgun_domain = "example.com"
+            mailgun_api_key = "key"
+            mailgun_region = "us"
+            cleanup_period = "2h"
+            port = 8000
+            bind_address = "::"
+            history_link_rate_limit = "1/1d"
+            "#,
+        );
+        let args = parse_flags(&[
+            "web".as_ref(),
+            "--config".as_ref(),
+            path.as_os_str(),
+            "--port".as_ref(),
+            "9000".as_ref(),
+        ]);
+        let config = Config::load(&args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(9000, config.port);
+        assert_eq!("::", config.bind_address.to_string());
+        assert_eq!("wss://pluslife.example.com", config.websocket_base_url);
+        assert_eq!(Region::US, config.mailgun_region);
+        assert_eq!(DumpMode::Disabled, config.dump_mode);
+        assert_eq!(
+            "1/1d".parse::<TokenBucket>().unwrap(),
+            config.history_link_rate_limit
+        );
+    }
+
+    #[test]
+    fn reports_every_invalid_setting() {
+        let path = write_config(
+            r#"
+            base_url = "ftp://example.com"
+            sender_email = "not an address"
+            port = 123456
+            dump_mode = "admin"
+            email_template_dir = "/nonexistent/email-templates"
+            completed_retention = "3y"
+            "#,
+        );
+        let args = parse_flags(&["web".as_ref(), "--config".as_ref(), path.as_os_str()]);
+        let errors = Config::load(&args).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut names: Vec<&str> = errors
+            .iter()
+            .map(|err| match err {
+                Error::InvalidConfig { name, .. } => name.as_str(),
+                _ => panic!("Unexpected error {:?}", err),
+            })
+            .collect();
+        names.sort();
+        assert_eq!(
+            vec![
+                "base_url",
+                "cleanup_period",
+                "completed_retention",
+                "dump_mode",
+                "email_template_dir",
+                "mailgun_api_key",
+                "mailgun_domain",
+                "port",
+                "sender_email"
+            ],
+            names
+        );
+    }
+}
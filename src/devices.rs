@@ -0,0 +1,229 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    Error,
+    messages::{DetectionResult, Device},
+    qc::Outcome,
+    state::CompletedTest,
+};
+
+// Every message says which device sent it, so the registry keeps a record of each device by serial number: when it
+// was first and last seen, which software versions it has run, and how its tests turned out. A lab running several
+// devices can then see which one keeps producing invalid results, or is still on an old firmware.
+//
+// Sightings are only kept in memory, and the registry is written out after each completed run and on shutdown, so a
+// crash loses at most the last-seen times since the last result.
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceRecord {
+    pub serial_number: u64,
+    pub model: String,
+    pub hardware_version: String,
+    pub first_seen: Timestamp,
+    pub last_seen: Timestamp,
+    /// Each software version the device has reported, oldest first. The last is the one it's running now.
+    pub firmware: Vec<FirmwareSighting>,
+    pub runs: u64,
+    pub invalid_results: u64,
+    pub qc_failures: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FirmwareSighting {
+    pub version: String,
+    pub first_seen: Timestamp,
+}
+
+#[derive(Serialize)]
+pub struct DeviceSummary {
+    #[serde(flatten)]
+    pub record: DeviceRecord,
+    /// The fraction of runs the device reported as invalid, or None if it hasn't finished a run yet.
+    pub invalid_rate: Option<f64>,
+    /// Whether the device is running software which isn't on the known-good list.
+    pub firmware_flagged: bool,
+}
+
+pub struct DeviceRegistry {
+    path: Option<PathBuf>,
+    known_good_firmware: Option<Vec<String>>,
+    devices: BTreeMap<u64, DeviceRecord>,
+}
+
+impl DeviceRegistry {
+    pub fn load(
+        path: Option<&Path>,
+        known_good_firmware: Option<Vec<String>>,
+    ) -> Result<DeviceRegistry, Error> {
+        let records: Vec<DeviceRecord> = match path.map(std::fs::read) {
+            None => Vec::new(),
+            Some(Ok(contents)) => serde_json::from_slice(&contents)?,
+            Some(Err(err)) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Some(Err(err)) => return Err(err.into()),
+        };
+        Ok(DeviceRegistry {
+            path: path.map(Path::to_owned),
+            known_good_firmware,
+            devices: records
+                .into_iter()
+                .map(|record| (record.serial_number, record))
+                .collect(),
+        })
+    }
+
+    /// Records that the device sent a message.
+    pub fn observe(&mut self, device: &Device, now: Timestamp) {
+        let record = self
+            .devices
+            .entry(device.serial_number)
+            .or_insert_with(|| DeviceRecord {
+                serial_number: device.serial_number,
+                model: device.model.clone(),
+                hardware_version: device.hardware_version.clone(),
+                first_seen: now,
+                last_seen: now,
+                firmware: Vec::new(),
+                runs: 0,
+                invalid_results: 0,
+                qc_failures: 0,
+            });
+        record.last_seen = now;
+        record.model.clone_from(&device.model);
+        record.hardware_version.clone_from(&device.hardware_version);
+        if record
+            .firmware
+            .last()
+            .is_none_or(|current| current.version != device.software_version)
+        {
+            record.firmware.push(FirmwareSighting {
+                version: device.software_version.clone(),
+                first_seen: now,
+            });
+            if !self.is_known_good(&device.software_version) {
+                warn!(
+                    serial_number = device.serial_number,
+                    version = device.software_version,
+                    "Device is running firmware which isn't known to be good"
+                );
+            }
+        }
+    }
+
+    /// Records the outcome of a test the device finished, and saves the registry.
+    pub fn record_run(&mut self, completed_test: &CompletedTest) -> Result<(), Error> {
+        let Some(record) = self.devices.get_mut(&completed_test.serial_number) else {
+            return Ok(());
+        };
+        record.runs += 1;
+        if completed_test.overall == DetectionResult::Invalid {
+            record.invalid_results += 1;
+        }
+        if completed_test.qc.outcome() == Outcome::Fail {
+            record.qc_failures += 1;
+        }
+        self.save()
+    }
+
+    pub fn summaries(&self) -> Vec<DeviceSummary> {
+        self.devices
+            .values()
+            .map(|record| DeviceSummary {
+                invalid_rate: (record.runs > 0)
+                    .then(|| record.invalid_results as f64 / record.runs as f64),
+                firmware_flagged: record
+                    .firmware
+                    .last()
+                    .is_some_and(|current| !self.is_known_good(&current.version)),
+                record: record.clone(),
+            })
+            .collect()
+    }
+
+    fn is_known_good(&self, version: &str) -> bool {
+        self.known_good_firmware
+            .as_ref()
+            .is_none_or(|known_good| known_good.iter().any(|good| good == version))
+    }
+
+    /// Writes the registry to its file, if it has one.
+    pub fn save(&self) -> Result<(), Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let records: Vec<&DeviceRecord> = self.devices.values().collect();
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_vec(&records)?)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use jiff::Timestamp;
+    use uuid::Uuid;
+
+    use super::DeviceRegistry;
+    use crate::{
+        messages::{DetectionResult, Message, ParseMode},
+        qc::Report,
+        state::CompletedTest,
+    };
+
+    #[test]
+    fn tracks_firmware_and_invalid_results_per_device() {
+        let path = std::env::temp_dir().join(format!("devices-{}.json", Uuid::new_v4()));
+        let raw = serde_json::from_str(include_str!("../tests/fixtures/synthetic/new-data.json"))
+            .unwrap();
+        let (mut message, _) = Message::parse(&raw, ParseMode::Strict).unwrap();
+        let mut registry =
+            DeviceRegistry::load(Some(&path), Some(vec!["1.2.3".to_owned()])).unwrap();
+        let started = Timestamp::now();
+        registry.observe(&message.device, started);
+        message.device.software_version = "1.3.0".to_owned();
+        registry.observe(&message.device, started + Duration::from_secs(60));
+        let mut completed_test = CompletedTest {
+            overall: DetectionResult::Invalid,
+            subgroup_results: Vec::new(),
+            completed: started,
+            serial_number: message.device.serial_number,
+            samples: 0,
+            panel: None,
+            graph_png: Vec::new(),
+            qc: Report::default(),
+        };
+        registry.record_run(&completed_test).unwrap();
+        completed_test.overall = DetectionResult::Negative;
+        registry.record_run(&completed_test).unwrap();
+
+        let registry = DeviceRegistry::load(Some(&path), Some(vec!["1.2.3".to_owned()])).unwrap();
+        let summaries = registry.summaries();
+        assert_eq!(1, summaries.len());
+        let summary = &summaries[0];
+        assert_eq!(123456789, summary.record.serial_number);
+        assert_eq!(started, summary.record.first_seen);
+        assert_eq!(
+            vec!["1.2.3", "1.3.0"],
+            summary
+                .record
+                .firmware
+                .iter()
+                .map(|sighting| sighting.version.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(2, summary.record.runs);
+        assert_eq!(Some(0.5), summary.invalid_rate);
+        assert!(summary.firmware_flagged);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,551 @@
+use std::path::Path;
+
+use askama::Template;
+use serde::Serialize;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    Error,
+    batches::{Batch, BatchOutcome},
+    i18n::{EmailMessages, Messages},
+    messages::{DetectionResult, SubgroupResult},
+    panels::Panel,
+    state::CompletedTest,
+};
+
+// Email bodies are compiled in from templates/email, but a deployment can point EMAIL_TEMPLATE_DIR at a directory
+// containing any of the same file names (e.g. result.html, layout.txt) to override them without recompiling.
+// Overrides are rendered with minijinja, which autoescapes .html files. The builtins stick to the syntax both engines
+// accept (no `if let`, method calls or `!`), so any of them can be copied into the directory as a starting point.
+// Either way, templates see a single `email` variable with the fields of the email struct, including `email.t`,
+// the email section of the recipient's message catalog.
+
+pub struct RenderedEmail {
+    /// The NAME of the template it was rendered from, e.g. result.
+    pub kind: &'static str,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+pub trait EmailTemplate: Serialize {
+    const NAME: &'static str;
+
+    fn subject(&self) -> String;
+
+    fn render_builtin_html(&self) -> askama::Result<String>;
+
+    fn render_builtin_text(&self) -> askama::Result<String>;
+}
+
+/// Implements EmailTemplate for each email struct, with the subject taken from its catalog and the builtin bodies
+/// compiled from the given files, which should be named after the email.
+macro_rules! email_templates {
+    ($($email:ident: $name:literal, $subject:ident, $html:literal, $text:literal;)*) => {$(
+        const _: () = {
+            #[derive(Template)]
+            #[template(path = $html)]
+            struct Html<'a> {
+                email: &'a $email,
+            }
+
+            #[derive(Template)]
+            #[template(path = $text)]
+            struct Text<'a> {
+                email: &'a $email,
+            }
+
+            impl EmailTemplate for $email {
+                const NAME: &'static str = $name;
+
+                fn subject(&self) -> String {
+                    self.t.$subject.clone()
+                }
+
+                fn render_builtin_html(&self) -> askama::Result<String> {
+                    Html { email: self }.render()
+                }
+
+                fn render_builtin_text(&self) -> askama::Result<String> {
+                    Text { email: self }.render()
+                }
+            }
+        };
+    )*};
+}
+
+#[derive(Clone)]
+pub struct EmailTemplates {
+    overrides: Option<minijinja::Environment<'static>>,
+}
+
+impl EmailTemplates {
+    pub fn builtin() -> EmailTemplates {
+        EmailTemplates { overrides: None }
+    }
+
+    pub fn with_overrides(dir: &Path) -> Result<EmailTemplates, std::io::Error> {
+        // Fail at startup rather than on the first email if the directory is missing or unreadable.
+        std::fs::read_dir(dir)?;
+        info!(dir = %dir.display(), "Loading email template overrides");
+        let mut env = minijinja::Environment::new();
+        // Overrides may extend either an overridden layout or the builtin one, which is referred to by the same name.
+        let dir = dir.to_owned();
+        env.set_loader(move |name| {
+            let name = name.strip_prefix("email/").unwrap_or(name);
+            match minijinja::path_loader(&dir)(name)? {
+                Some(source) => Ok(Some(source)),
+                None => Ok(builtin_layout_source(name).map(str::to_owned)),
+            }
+        });
+        Ok(EmailTemplates {
+            overrides: Some(env),
+        })
+    }
+
+    pub fn render<T: EmailTemplate>(&self, email: &T) -> Result<RenderedEmail, Error> {
+        let html = match self.render_override(email, &format!("{}.html", T::NAME))? {
+            Some(html) => html,
+            None => email.render_builtin_html()?,
+        };
+        let text = match self.render_override(email, &format!("{}.txt", T::NAME))? {
+            Some(text) => text,
+            None => email.render_builtin_text()?,
+        };
+        Ok(RenderedEmail {
+            kind: T::NAME,
+            subject: email.subject(),
+            text,
+            html,
+        })
+    }
+
+    fn render_override<T: EmailTemplate>(
+        &self,
+        email: &T,
+        name: &str,
+    ) -> Result<Option<String>, Error> {
+        let Some(env) = &self.overrides else {
+            return Ok(None);
+        };
+        match env.get_template(name) {
+            Ok(template) => Ok(Some(
+                template.render(minijinja::context! { email => email })?,
+            )),
+            Err(err) if err.kind() == minijinja::ErrorKind::TemplateNotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ResultEmail {
+    pub t: EmailMessages,
+    /// The name of the panel the test matched, or empty if it matched none. Optional fields are empty strings rather
+    /// than options, so the same template condition works in askama and minijinja.
+    pub assay: String,
+    pub guidance: String,
+    pub overall: String,
+    pub subgroup_results: Vec<SubgroupResultLine>,
+    pub completed_at: String,
+}
+
+#[derive(Serialize)]
+pub struct SubgroupResultLine {
+    pub name: String,
+    pub result: String,
+}
+
+impl ResultEmail {
+    /// Prefers the panel's wording, if the test matched one, over the catalog's.
+    pub fn new(
+        messages: &Messages,
+        language: &str,
+        overall: DetectionResult,
+        subgroup_results: &[SubgroupResult],
+        panel: Option<&Panel>,
+        completed_at: String,
+    ) -> ResultEmail {
+        let result = |result: DetectionResult| {
+            panel
+                .and_then(|panel| panel.result(result, language))
+                .unwrap_or_else(|| messages.result(result))
+                .to_owned()
+        };
+        ResultEmail {
+            t: messages.email.clone(),
+            assay: panel
+                .map(|panel| panel.name.get(language).to_owned())
+                .unwrap_or_default(),
+            guidance: panel
+                .and_then(|panel| panel.guidance.as_ref())
+                .map(|guidance| guidance.get(language).to_owned())
+                .unwrap_or_default(),
+            overall: result(overall),
+            subgroup_results: subgroup_results
+                .iter()
+                .map(|subgroup| SubgroupResultLine {
+                    name: panel
+                        .and_then(|panel| panel.subgroup_name(&subgroup.name, language))
+                        .unwrap_or_else(|| messages.subgroup_name(&subgroup.name))
+                        .to_owned(),
+                    result: result(subgroup.result),
+                })
+                .collect(),
+            completed_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RetestEmail {
+    pub t: EmailMessages,
+    /// Why the result can't be relied on.
+    pub reasons: Vec<String>,
+    pub completed_at: String,
+}
+
+impl RetestEmail {
+    pub fn new(
+        messages: &Messages,
+        completed_test: &CompletedTest,
+        completed_at: String,
+    ) -> RetestEmail {
+        let invalid = (completed_test.overall == DetectionResult::Invalid)
+            .then(|| messages.email.retest_invalid.clone());
+        RetestEmail {
+            t: messages.email.clone(),
+            reasons: invalid
+                .into_iter()
+                .chain(
+                    completed_test
+                        .qc
+                        .failures()
+                        .map(|check| messages.qc_reason(check)),
+                )
+                .collect(),
+            completed_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ErrorEmail {
+    pub t: EmailMessages,
+    pub id: Uuid,
+    pub error: String,
+}
+
+#[derive(Serialize)]
+pub struct ExpiredEmail {
+    pub t: EmailMessages,
+    pub id: Uuid,
+    pub created_at: String,
+}
+
+#[derive(Serialize)]
+pub struct SilentEmail {
+    pub t: EmailMessages,
+    pub id: Uuid,
+    /// The intro with the length of the silence filled in.
+    pub intro: String,
+}
+
+#[derive(Serialize)]
+pub struct VerifyEmail {
+    pub t: EmailMessages,
+    pub link: String,
+}
+
+#[derive(Serialize)]
+pub struct HistoryLinkEmail {
+    pub t: EmailMessages,
+    pub link: String,
+}
+
+#[derive(Serialize)]
+pub struct BatchSummaryEmail {
+    pub t: EmailMessages,
+    pub tests: Vec<BatchSummaryLine>,
+}
+
+#[derive(Serialize)]
+pub struct BatchSummaryLine {
+    pub label: String,
+    pub outcome: String,
+}
+
+impl BatchSummaryEmail {
+    pub fn new(messages: &Messages, batch: &Batch) -> BatchSummaryEmail {
+        BatchSummaryEmail {
+            t: messages.email.clone(),
+            tests: batch
+                .tests
+                .iter()
+                .map(|test| BatchSummaryLine {
+                    label: test.label.clone(),
+                    outcome: match test.outcome {
+                        Some(BatchOutcome::Completed(result)) => messages.result(result).to_owned(),
+                        Some(BatchOutcome::Retest) => messages.email.batch_retest.clone(),
+                        Some(BatchOutcome::Expired) | None => messages.email.batch_expired.clone(),
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+email_templates! {
+    ResultEmail: "result", results_subject, "email/result.html", "email/result.txt";
+    RetestEmail: "retest", retest_subject, "email/retest.html", "email/retest.txt";
+    ErrorEmail: "error", error_subject, "email/error.html", "email/error.txt";
+    ExpiredEmail: "expired", expired_subject, "email/expired.html", "email/expired.txt";
+    SilentEmail: "silent", silent_subject, "email/silent.html", "email/silent.txt";
+    VerifyEmail: "verify", verify_subject, "email/verify.html", "email/verify.txt";
+    HistoryLinkEmail: "history_link", history_subject, "email/history_link.html", "email/history_link.txt";
+    BatchSummaryEmail: "batch_summary", batch_subject, "email/batch_summary.html", "email/batch_summary.txt";
+}
+
+fn builtin_layout_source(name: &str) -> Option<&'static str> {
+    match name {
+        "layout.html" => Some(include_str!("../templates/email/layout.html")),
+        "layout.txt" => Some(include_str!("../templates/email/layout.txt")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{
+        BatchSummaryEmail, BatchSummaryLine, EmailTemplate, EmailTemplates, ErrorEmail,
+        ExpiredEmail, HistoryLinkEmail, ResultEmail, RetestEmail, SilentEmail, VerifyEmail,
+    };
+    use crate::{
+        i18n::Catalogs,
+        messages::{DetectionResult, SubgroupResult},
+        panels::Panels,
+        qc::{Check, Outcome, Reason, Report},
+        state::CompletedTest,
+    };
+
+    #[test]
+    fn override_can_extend_builtin_layout() {
+        let dir = std::env::temp_dir().join(format!("email-overrides-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("result.html"),
+            "{% extends \"email/layout.html\" %}{% block content %}Rebranded {{ email.overall }}{% endblock %}",
+        )
+        .unwrap();
+        let templates = EmailTemplates::with_overrides(&dir).unwrap();
+        let rendered = templates
+            .render(&ResultEmail::new(
+                Catalogs::load().unwrap().get("en"),
+                "en",
+                DetectionResult::Positive,
+                &[],
+                None,
+                "now".to_owned(),
+            ))
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(rendered.html.contains("Rebranded Positive"));
+        assert!(rendered.html.contains("Sent by"));
+        assert!(rendered.text.contains("Your overall result is: Positive"));
+    }
+
+    /// Renders the email with askama and again through the override path, with the builtin directory as the
+    /// overrides, so an operator can start from a copy of any builtin.
+    fn assert_builtin_renders_as_override<T: EmailTemplate>(email: &T) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("templates/email");
+        let builtin = EmailTemplates::builtin().render(email).unwrap();
+        let overridden = EmailTemplates::with_overrides(&dir)
+            .unwrap()
+            .render(email)
+            .unwrap_or_else(|err| panic!("{} didn't render with minijinja: {:?}", T::NAME, err));
+        assert_eq!(builtin.text, overridden.text, "{}", T::NAME);
+        // The engines escape differently (e.g. &#39; and &#x27;), so compare the HTML with its entities decoded.
+        let decode = |html: &str| {
+            [
+                ("&#39;", "'"),
+                ("&#x27;", "'"),
+                ("&#34;", "\""),
+                ("&quot;", "\""),
+                ("&#x2f;", "/"),
+                ("&#38;", "&"),
+                ("&amp;", "&"),
+            ]
+            .iter()
+            .fold(html.to_owned(), |html, (entity, character)| {
+                html.replace(entity, character)
+            })
+        };
+        assert_eq!(
+            decode(&builtin.html),
+            decode(&overridden.html),
+            "{}",
+            T::NAME
+        );
+    }
+
+    #[test]
+    fn every_builtin_renders_through_the_override_path() {
+        let catalogs = Catalogs::load().unwrap();
+        let messages = catalogs.get("en");
+        let t = || messages.email.clone();
+        let panels = Panels::builtin().unwrap();
+        let subgroups = [
+            SubgroupResult {
+                name: "COVID-19".to_owned(),
+                result: DetectionResult::Negative,
+            },
+            SubgroupResult {
+                name: "IC".to_owned(),
+                result: DetectionResult::Positive,
+            },
+        ];
+        for panel in [None, Some(&panels.panels[0])] {
+            assert_builtin_renders_as_override(&ResultEmail::new(
+                messages,
+                "en",
+                DetectionResult::Negative,
+                &subgroups,
+                panel,
+                "now".to_owned(),
+            ));
+        }
+        let mut completed_test = CompletedTest {
+            overall: DetectionResult::Invalid,
+            subgroup_results: Vec::new(),
+            completed: jiff::Timestamp::now(),
+            serial_number: 1234,
+            samples: 0,
+            panel: None,
+            graph_png: Vec::new(),
+            qc: Report::default(),
+        };
+        assert_builtin_renders_as_override(&RetestEmail::new(
+            messages,
+            &completed_test,
+            "now".to_owned(),
+        ));
+        completed_test.overall = DetectionResult::Negative;
+        assert_builtin_renders_as_override(&RetestEmail::new(
+            messages,
+            &completed_test,
+            "now".to_owned(),
+        ));
+        let id = uuid::Uuid::new_v4();
+        assert_builtin_renders_as_override(&ErrorEmail {
+            t: t(),
+            id,
+            error: "UnknownSession".to_owned(),
+        });
+        assert_builtin_renders_as_override(&ExpiredEmail {
+            t: t(),
+            id,
+            created_at: "now".to_owned(),
+        });
+        assert_builtin_renders_as_override(&SilentEmail {
+            t: t(),
+            id,
+            intro: "Nothing for 15 minutes".to_owned(),
+        });
+        assert_builtin_renders_as_override(&VerifyEmail {
+            t: t(),
+            link: "https://example.com/verify?a=1&b=2".to_owned(),
+        });
+        assert_builtin_renders_as_override(&HistoryLinkEmail {
+            t: t(),
+            link: "https://example.com/history/abc".to_owned(),
+        });
+        assert_builtin_renders_as_override(&BatchSummaryEmail {
+            t: t(),
+            tests: vec![BatchSummaryLine {
+                label: "Alice".to_owned(),
+                outcome: "Negative".to_owned(),
+            }],
+        });
+    }
+
+    #[test]
+    fn result_email_escapes_subgroup_names_in_html_only() {
+        let email = ResultEmail::new(
+            Catalogs::load().unwrap().get("en"),
+            "en",
+            DetectionResult::Negative,
+            &[
+                SubgroupResult {
+                    name: "<b>Flu</b>".to_owned(),
+                    result: DetectionResult::Positive,
+                },
+                SubgroupResult {
+                    name: "IC".to_owned(),
+                    result: DetectionResult::Positive,
+                },
+            ],
+            None,
+            "now".to_owned(),
+        );
+        let rendered = EmailTemplates::builtin().render(&email).unwrap();
+        assert!(rendered.html.contains("&#60;b&#62;Flu&#60;/b&#62;"));
+        assert!(!rendered.html.contains("<b>Flu</b>"));
+        assert!(rendered.text.contains(" * <b>Flu</b>: Positive"));
+        assert!(rendered.text.contains(" * Control: Positive"));
+    }
+
+    #[test]
+    fn retest_email_lists_why_the_result_is_unreliable() {
+        let completed_test = CompletedTest {
+            overall: DetectionResult::Invalid,
+            subgroup_results: Vec::new(),
+            completed: jiff::Timestamp::now(),
+            serial_number: 1234,
+            samples: 0,
+            panel: None,
+            graph_png: Vec::new(),
+            qc: Report {
+                checks: vec![Check {
+                    name: "thermal_profile",
+                    outcome: Outcome::Fail,
+                    reason: Reason::TooCold,
+                    params: vec![("min", "55.0".to_owned()), ("limit", "60.0".to_owned())],
+                }],
+            },
+        };
+        assert!(completed_test.needs_retest());
+        let email = RetestEmail::new(
+            Catalogs::load().unwrap().get("en"),
+            &completed_test,
+            "now".to_owned(),
+        );
+        let rendered = EmailTemplates::builtin().render(&email).unwrap();
+        assert_eq!("Your PlusLife test needs to be repeated", rendered.subject);
+        assert!(
+            rendered
+                .text
+                .contains(" * The device reported the result as invalid.")
+        );
+        assert!(
+            rendered
+                .text
+                .contains(" * The temperature fell to 55.0°C, below the 60.0°C the test needs.")
+        );
+        assert!(!rendered.text.contains("INVALID"));
+
+        let email = RetestEmail::new(
+            Catalogs::load().unwrap().get("de"),
+            &completed_test,
+            "now".to_owned(),
+        );
+        let rendered = EmailTemplates::builtin().render(&email).unwrap();
+        assert!(
+            rendered
+                .text
+                .contains(" * Die Temperatur ist auf 55.0 °C gefallen")
+        );
+    }
+}
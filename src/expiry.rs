@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use jiff::Timestamp;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::time::{DelayQueue, delay_queue::Key};
+use uuid::Uuid;
+
+// Every session's deadline lives in a single DelayQueue owned by one task, rather than each session having its own
+// sleeping task. Deadlines can be moved (e.g. on activity, or once a result arrives) or cancelled by sending the task a
+// command, and it calls back with each session whose deadline passes.
+//
+// Each schedule is numbered, so a deadline which fires while a newer schedule or a cancellation is still on its way to
+// the task is recognised as stale: it neither clears the newer deadline nor calls back.
+
+/// DelayQueue panics on delays of more than about two years, so settings which are scheduled are capped well short of
+/// that.
+pub const MAX_DELAY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+enum Command {
+    Schedule(Uuid, Duration, u64),
+    Cancel(Uuid),
+}
+
+enum Event {
+    Command(Option<Command>),
+    Expired(Uuid),
+}
+
+type Deadlines = HashMap<Uuid, (u64, Timestamp)>;
+
+#[derive(Clone)]
+pub struct ExpiryScheduler {
+    commands: mpsc::UnboundedSender<Command>,
+    receiver: Arc<Mutex<Option<mpsc::UnboundedReceiver<Command>>>>,
+    /// When each pending deadline falls, for reporting, along with the schedule which set it. The queue itself works
+    /// in monotonic time.
+    deadlines: Arc<Mutex<Deadlines>>,
+    generations: Arc<AtomicU64>,
+}
+
+impl Default for ExpiryScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpiryScheduler {
+    /// Commands are queued until [`ExpiryScheduler::run`] is called.
+    pub fn new() -> ExpiryScheduler {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        ExpiryScheduler {
+            commands,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+            deadlines: Arc::default(),
+            generations: Arc::default(),
+        }
+    }
+
+    /// Expires the session after `delay`, or MAX_DELAY if that's shorter, replacing any deadline it already had.
+    pub fn schedule(&self, id: Uuid, delay: Duration) {
+        let delay = delay.min(MAX_DELAY);
+        let deadline = Timestamp::now()
+            .checked_add(delay)
+            .unwrap_or(Timestamp::MAX);
+        let generation = self.generations.fetch_add(1, Ordering::Relaxed);
+        self.deadlines
+            .lock()
+            .unwrap()
+            .insert(id, (generation, deadline));
+        let _ = self.commands.send(Command::Schedule(id, delay, generation));
+    }
+
+    pub fn cancel(&self, id: Uuid) {
+        self.deadlines.lock().unwrap().remove(&id);
+        let _ = self.commands.send(Command::Cancel(id));
+    }
+
+    /// Starts calling `on_expired` with each session whose deadline passes. Returns None if already running.
+    pub fn run(&self, mut on_expired: impl FnMut(Uuid) + Send + 'static) -> Option<JoinHandle<()>> {
+        let commands = self.receiver.lock().unwrap().take()?;
+        let deadlines = self.deadlines.clone();
+        Some(tokio::spawn(run(commands, move |id, generation| {
+            let mut deadlines = deadlines.lock().unwrap();
+            if deadlines
+                .get(&id)
+                .is_some_and(|(current, _)| *current == generation)
+            {
+                deadlines.remove(&id);
+                drop(deadlines);
+                on_expired(id)
+            }
+        })))
+    }
+
+    /// When the session is due to expire, if it has a deadline.
+    pub fn deadline(&self, id: &Uuid) -> Option<Timestamp> {
+        self.deadlines
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|(_, deadline)| *deadline)
+    }
+}
+
+async fn run(
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    mut on_expired: impl FnMut(Uuid, u64),
+) {
+    let mut queue: DelayQueue<Uuid> = DelayQueue::new();
+    let mut keys: HashMap<Uuid, (Key, u64)> = HashMap::new();
+    loop {
+        // An empty queue yields None, which disables that branch until the next command arrives.
+        let event = tokio::select! {
+            command = commands.recv() => Event::Command(command),
+            Some(expired) = std::future::poll_fn(|cx| queue.poll_expired(cx)) => {
+                Event::Expired(expired.into_inner())
+            }
+        };
+        match event {
+            Event::Command(Some(Command::Schedule(id, delay, generation))) => {
+                match keys.get_mut(&id) {
+                    Some((key, scheduled)) => {
+                        queue.reset(key, delay);
+                        *scheduled = generation;
+                    }
+                    None => {
+                        keys.insert(id, (queue.insert(id, delay), generation));
+                    }
+                }
+            }
+            Event::Command(Some(Command::Cancel(id))) => {
+                if let Some((key, _)) = keys.remove(&id) {
+                    queue.remove(&key);
+                }
+            }
+            Event::Command(None) => return,
+            Event::Expired(id) => {
+                if let Some((_, generation)) = keys.remove(&id) {
+                    on_expired(id, generation);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use jiff::Timestamp;
+    use uuid::Uuid;
+
+    use super::{Command, ExpiryScheduler, MAX_DELAY};
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_extends_and_cancels_deadlines() {
+        let expired: Arc<Mutex<Vec<Uuid>>> = Arc::default();
+        let scheduler = ExpiryScheduler::new();
+        scheduler.run({
+            let expired = expired.clone();
+            move |id| expired.lock().unwrap().push(id)
+        });
+        let (extended, cancelled, untouched) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        for id in [extended, cancelled, untouched] {
+            scheduler.schedule(id, Duration::from_secs(60));
+        }
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        scheduler.schedule(extended, Duration::from_secs(60));
+        scheduler.cancel(cancelled);
+        assert!(scheduler.deadline(&cancelled).is_none());
+        assert!(scheduler.deadline(&extended).is_some());
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(vec![untouched], *expired.lock().unwrap());
+        assert!(scheduler.deadline(&untouched).is_none());
+
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(vec![untouched, extended], *expired.lock().unwrap());
+        assert!(scheduler.run(|_| {}).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_beyond_the_queue_limit_are_capped() {
+        let scheduler = ExpiryScheduler::new();
+        let running = scheduler.run(|_| {}).unwrap();
+        let id = Uuid::new_v4();
+        scheduler.schedule(id, Duration::from_secs(10 * 365 * 24 * 60 * 60));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!running.is_finished());
+        let deadline = scheduler.deadline(&id).unwrap();
+        assert!(deadline <= Timestamp::now() + MAX_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_deadlines_leave_a_reschedule_alone() {
+        let expired: Arc<Mutex<Vec<Uuid>>> = Arc::default();
+        let scheduler = ExpiryScheduler::new();
+        scheduler.run({
+            let expired = expired.clone();
+            move |id| expired.lock().unwrap().push(id)
+        });
+        let id = Uuid::new_v4();
+        scheduler.schedule(id, Duration::from_secs(10));
+
+        // As if the deadline fires between a reschedule taking effect here and its command reaching the queue.
+        let rescheduled = Timestamp::now() + Duration::from_secs(60);
+        scheduler
+            .deadlines
+            .lock()
+            .unwrap()
+            .insert(id, (1, rescheduled));
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(expired.lock().unwrap().is_empty());
+        assert_eq!(Some(rescheduled), scheduler.deadline(&id));
+
+        let _ = scheduler
+            .commands
+            .send(Command::Schedule(id, Duration::from_secs(60), 1));
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(vec![id], *expired.lock().unwrap());
+        assert!(scheduler.deadline(&id).is_none());
+    }
+}
@@ -4,14 +4,24 @@ use plotters::{
     chart::ChartBuilder,
     prelude::{BitMapBackend, Circle, EmptyElement, IntoDrawingArea},
     series::{LineSeries, PointSeries},
-    style::{RGBAColor, RGBColor, ShapeStyle, WHITE},
+    style::{Color, RGBAColor, RGBColor, ShapeStyle, WHITE},
 };
 
-use crate::{Error, messages::TestData};
+use crate::{Error, messages::TestData, panels::Panel};
 
 pub const WIDTH: u32 = 800;
 pub const HEIGHT: u32 = 600;
 
+const DEFAULT_COLOURS: [RGBColor; 7] = [
+    RGBColor(166, 206, 227),
+    RGBColor(32, 120, 180),
+    RGBColor(178, 223, 138),
+    RGBColor(52, 160, 45),
+    RGBColor(166, 206, 227),
+    RGBColor(252, 154, 154),
+    RGBColor(254, 192, 112),
+];
+
 pub struct GraphData {
     pub min_time: f32,
     pub max_time: f32,
@@ -40,6 +50,7 @@ impl GraphData {
             .enumerate()
             .map(|(index, line)| Line {
                 color: line.color,
+                label: line.label.clone(),
                 points: line
                     .points
                     .iter()
@@ -116,7 +127,13 @@ impl GraphData {
             .draw()?;
 
         for line in &self.lines {
-            chart.draw_series(LineSeries::new(line.points.clone(), &line.color))?;
+            let series = chart.draw_series(LineSeries::new(line.points.clone(), &line.color))?;
+            if let Some(label) = &line.label {
+                let color = line.color;
+                series
+                    .label(label)
+                    .legend(move |(x, y)| Circle::new((x + 10, y), 4, color.filled()));
+            }
 
             chart.draw_series(PointSeries::of_element(
                 line.points.clone(),
@@ -127,6 +144,13 @@ impl GraphData {
                 },
             ))?;
         }
+        if self.lines.iter().any(|line| line.label.is_some()) {
+            chart
+                .configure_series_labels()
+                .background_style(WHITE)
+                .border_style(RGBColor(200, 200, 200))
+                .draw()?;
+        }
         root.present()?;
         Ok(())
     }
@@ -134,46 +158,50 @@ impl GraphData {
 
 pub struct Line {
     pub color: RGBColor,
+    pub label: Option<String>,
     pub points: Vec<(f32, u32)>,
 }
 
 impl Line {
-    pub fn new(color: RGBColor) -> Line {
+    pub fn new(color: RGBColor, label: Option<String>) -> Line {
         Line {
             color,
+            label,
             points: Vec::new(),
         }
     }
 }
 
 impl TestData {
-    pub fn to_graph(&self) -> Result<GraphData, Error> {
+    /// Draws each channel in its panel's colour, if the panel is known, or the default palette otherwise.
+    pub fn to_graph(&self, panel: Option<&Panel>) -> Result<GraphData, Error> {
         let mut min_time = f32::MAX;
         let mut max_time = f32::MIN;
         let mut min_value = u32::MAX;
         let mut max_value = u32::MIN;
-        let mut lines = vec![
-            Line::new(RGBColor(166, 206, 227)),
-            Line::new(RGBColor(32, 120, 180)),
-            Line::new(RGBColor(178, 223, 138)),
-            Line::new(RGBColor(52, 160, 45)),
-            Line::new(RGBColor(166, 206, 227)),
-            Line::new(RGBColor(252, 154, 154)),
-            Line::new(RGBColor(254, 192, 112)),
-        ];
+        let mut lines: Vec<Line> = DEFAULT_COLOURS
+            .iter()
+            .map(|color| Line::new(*color, None))
+            .collect();
+        for (line, (color, label)) in lines
+            .iter_mut()
+            .zip(panel.map(Panel::graph_lines).unwrap_or_default())
+        {
+            *line = Line::new(color, label);
+        }
 
         for sample in &self.samples {
-            let time_minutes = sample.sampling_time as f32 / 600f32;
+            let time_minutes = sample.elapsed.as_secs_f32() / 60f32;
             min_time = f32::min(min_time, time_minutes);
             max_time = f32::max(max_time, time_minutes);
-            min_value = u32::min(min_value, sample.first_channel_result);
-            max_value = u32::max(max_value, sample.first_channel_result);
-            if sample.starting_channel >= lines.len() {
-                return Err(Error::TooManyChannels(sample.starting_channel));
+            min_value = u32::min(min_value, sample.value);
+            max_value = u32::max(max_value, sample.value);
+            if sample.channel >= lines.len() {
+                return Err(Error::TooManyChannels(sample.channel));
             }
-            lines[sample.starting_channel]
+            lines[sample.channel]
                 .points
-                .push((time_minutes, sample.first_channel_result));
+                .push((time_minutes, sample.value));
         }
         Ok(GraphData {
             min_time,
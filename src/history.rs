@@ -0,0 +1,276 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use email_address::EmailAddress;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    Error,
+    messages::{DetectionResult, SubgroupResult},
+    tokens::random_token,
+};
+
+// Sessions which opt in have their result recorded against each recipient's address, so someone running many tests
+// can look back over them. Records are kept in memory and the whole history is rewritten to a single JSON file after
+// every change, the same way the session state file is. They are deleted once older than the retention period, or
+// all at once when their owner asks.
+//
+// Histories are read through magic links: asking for one emails a short-lived token to the address, so only whoever
+// can read that inbox can see its history. Links are only kept in memory, so a restart invalidates them.
+
+const LINK_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HistoryRecord {
+    pub session_id: Uuid,
+    pub completed: Timestamp,
+    pub serial_number: u64,
+    /// The English name of the panel the test matched, if any.
+    pub panel: Option<String>,
+    pub overall: DetectionResult,
+    pub subgroup_results: Vec<SubgroupResult>,
+    /// The IANA name of the time zone the session was created in, which the history page shows the time in.
+    #[serde(default)]
+    pub time_zone: Option<String>,
+}
+
+pub struct History {
+    path: PathBuf,
+    retention: Duration,
+    records: HashMap<EmailAddress, Vec<HistoryRecord>>,
+    links: HashMap<String, (EmailAddress, Timestamp)>,
+}
+
+impl History {
+    pub fn load(path: &Path, retention: Duration) -> Result<History, Error> {
+        let mut history = History {
+            path: path.to_owned(),
+            retention,
+            records: read_records(path)?,
+            links: HashMap::new(),
+        };
+        history.prune(Timestamp::now())?;
+        Ok(history)
+    }
+
+    /// Checks that the file can be loaded, without pruning it as loading does.
+    pub fn check(path: &Path) -> Result<(), Error> {
+        read_records(path).map(|_| ())
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    pub fn record(&mut self, emails: &[EmailAddress], record: HistoryRecord) -> Result<(), Error> {
+        for email in emails {
+            self.records
+                .entry(email.clone())
+                .or_default()
+                .push(record.clone());
+        }
+        self.save()
+    }
+
+    /// Oldest first.
+    pub fn records(&self, email: &EmailAddress) -> &[HistoryRecord] {
+        self.records
+            .get(email)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Forgets everything recorded for the address, returning how many records there were.
+    pub fn delete(&mut self, email: &EmailAddress) -> Result<usize, Error> {
+        let deleted = self
+            .records
+            .remove(email)
+            .map_or(0, |records| records.len());
+        self.links.retain(|_, (linked, _)| linked != email);
+        self.save()?;
+        Ok(deleted)
+    }
+
+    /// Deletes records which completed more than the retention period before `now`.
+    pub fn prune(&mut self, now: Timestamp) -> Result<usize, Error> {
+        let cutoff = now.checked_sub(self.retention).unwrap_or(Timestamp::MIN);
+        let mut pruned = 0;
+        for records in self.records.values_mut() {
+            let before = records.len();
+            records.retain(|record| record.completed >= cutoff);
+            pruned += before - records.len();
+        }
+        self.records.retain(|_, records| !records.is_empty());
+        self.links.retain(|_, (_, expires)| *expires > now);
+        if pruned > 0 {
+            self.save()?;
+        }
+        Ok(pruned)
+    }
+
+    /// Returns a token granting access to the address's history for a while, or None if there is no history to see.
+    pub fn create_link(&mut self, email: &EmailAddress) -> Option<String> {
+        if self.records(email).is_empty() {
+            return None;
+        }
+        let token = random_token();
+        let expires = Timestamp::now()
+            .checked_add(LINK_LIFETIME)
+            .unwrap_or(Timestamp::MAX);
+        self.links.insert(token.clone(), (email.clone(), expires));
+        Some(token)
+    }
+
+    pub fn redeem_link(&self, token: &str) -> Option<&EmailAddress> {
+        self.links
+            .get(token)
+            .filter(|(_, expires)| *expires > Timestamp::now())
+            .map(|(email, _)| email)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_vec(&self.records)?)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+/// One row per test, with subgroups as "name=RESULT" pairs separated by semicolons.
+pub fn to_csv(records: &[HistoryRecord]) -> String {
+    let mut csv = "completed,session_id,serial_number,panel,overall,subgroups\n".to_owned();
+    for record in records {
+        let subgroups = record
+            .subgroup_results
+            .iter()
+            .map(|subgroup| format!("{}={}", subgroup.name, subgroup.result))
+            .collect::<Vec<_>>()
+            .join(";");
+        let fields = [
+            record.completed.to_string(),
+            record.session_id.to_string(),
+            record.serial_number.to_string(),
+            record.panel.clone().unwrap_or_default(),
+            record.overall.to_string(),
+            subgroups,
+        ];
+        let row = fields
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<_>>();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quotes the field if needed. Subgroup and panel names come from whoever posts to the webhook, so anything a
+/// spreadsheet would run as a formula is prefixed with a quote to keep it text.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", field)
+    } else {
+        field.to_owned()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn read_records(path: &Path) -> Result<HashMap<EmailAddress, Vec<HistoryRecord>>, Error> {
+    match std::fs::read(path) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use email_address::EmailAddress;
+    use jiff::Timestamp;
+    use uuid::Uuid;
+
+    use super::{History, HistoryRecord, to_csv};
+    use crate::messages::{DetectionResult, SubgroupResult};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn record(completed: Timestamp) -> HistoryRecord {
+        HistoryRecord {
+            session_id: Uuid::nil(),
+            completed,
+            serial_number: 1234,
+            panel: Some("COVID-19".to_owned()),
+            overall: DetectionResult::Negative,
+            subgroup_results: vec![
+                SubgroupResult {
+                    name: "COVID-19".to_owned(),
+                    result: DetectionResult::Negative,
+                },
+                SubgroupResult {
+                    name: "Flu, \"A\"".to_owned(),
+                    result: DetectionResult::Positive,
+                },
+            ],
+            time_zone: None,
+        }
+    }
+
+    #[test]
+    fn records_prunes_exports_and_deletes() {
+        let path = std::env::temp_dir().join(format!("history-{}.json", Uuid::new_v4()));
+        let email: EmailAddress = "a@example.com".parse().unwrap();
+        let mut history = History::load(&path, 7 * DAY).unwrap();
+        assert_eq!(None, history.create_link(&email));
+        let now = Timestamp::now();
+        for completed in [now - 10 * DAY, now - DAY] {
+            history
+                .record(std::slice::from_ref(&email), record(completed))
+                .unwrap();
+        }
+
+        let mut history = History::load(&path, 7 * DAY).unwrap();
+        assert_eq!(1, history.records(&email).len());
+        assert_eq!(
+            format!(
+                "completed,session_id,serial_number,panel,overall,subgroups\n\
+                 {},00000000-0000-0000-0000-000000000000,1234,COVID-19,Negative,\
+                 \"COVID-19=Negative;Flu, \"\"A\"\"=Positive\"\n",
+                now - DAY
+            ),
+            to_csv(history.records(&email))
+        );
+
+        let mut formula = record(now);
+        formula.panel = Some("@SUM(A1)".to_owned());
+        formula.subgroup_results[0].name = "=HYPERLINK(\"http://example.com\")".to_owned();
+        assert!(
+            to_csv(&[formula]).ends_with(
+                ",'@SUM(A1),Negative,\"'=HYPERLINK(\"\"http://example.com\"\")=Negative;Flu, \"\"A\"\"=Positive\"\n"
+            )
+        );
+
+        let token = history.create_link(&email).unwrap();
+        assert_eq!(Some(&email), history.redeem_link(&token));
+        assert_eq!(1, history.delete(&email).unwrap());
+        assert_eq!(None, history.redeem_link(&token));
+        assert!(
+            History::load(&path, 7 * DAY)
+                .unwrap()
+                .records(&email)
+                .is_empty()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
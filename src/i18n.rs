@@ -0,0 +1,502 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use jiff::{Timestamp, tz::TimeZone};
+use rust_embed::RustEmbed;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error,
+    messages::DetectionResult,
+    qc::{Check, Reason},
+};
+
+// Message catalogs live in locales/<language>.toml, one file per language.
+// English is the reference catalog: any key missing from another language falls back to the English text,
+// so a partial translation never stops the server from starting.
+
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+#[derive(RustEmbed)]
+#[folder = "locales/"]
+struct LocaleFiles;
+
+pub struct Catalogs {
+    catalogs: BTreeMap<String, Messages>,
+}
+
+impl Catalogs {
+    pub fn load() -> Result<Catalogs, Error> {
+        let reference = Self::parse(DEFAULT_LANGUAGE)?;
+        let mut catalogs = BTreeMap::new();
+        for file in LocaleFiles::iter() {
+            let Some(language) = file.strip_suffix(".toml") else {
+                continue;
+            };
+            let mut table = reference.clone();
+            merge(&mut table, Self::parse(language)?);
+            let messages = Messages::deserialize(table).map_err(|err| Error::InvalidCatalog {
+                language: language.to_owned(),
+                cause: Box::new(err),
+            })?;
+            catalogs.insert(language.to_owned(), messages);
+        }
+        Ok(Catalogs { catalogs })
+    }
+
+    fn parse(language: &str) -> Result<toml::Table, Error> {
+        let file = LocaleFiles::get(&format!("{}.toml", language)).ok_or_else(|| {
+            Error::InvalidCatalog {
+                language: language.to_owned(),
+                cause: "Missing catalog file".into(),
+            }
+        })?;
+        let source = std::str::from_utf8(&file.data).map_err(|err| Error::InvalidCatalog {
+            language: language.to_owned(),
+            cause: Box::new(err),
+        })?;
+        toml::from_str(source).map_err(|err| Error::InvalidCatalog {
+            language: language.to_owned(),
+            cause: Box::new(err),
+        })
+    }
+
+    pub fn get(&self, language: &str) -> &Messages {
+        self.catalogs
+            .get(language)
+            .or_else(|| self.catalogs.get(DEFAULT_LANGUAGE))
+            .expect("The default catalog is always loaded")
+    }
+
+    pub fn languages(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.catalogs
+            .iter()
+            .map(|(language, messages)| (language.as_str(), messages.language_name.as_str()))
+    }
+
+    /// Picks a supported language, preferring an explicit choice (e.g. from a form) over an Accept-Language header.
+    pub fn negotiate(&self, requested: Option<&str>, accept_language: Option<&str>) -> String {
+        let mut candidates: Vec<(&str, f32)> = Vec::new();
+        if let Some(requested) = requested {
+            candidates.push((requested, f32::MAX));
+        }
+        if let Some(accept_language) = accept_language {
+            candidates.extend(parse_accept_language(accept_language));
+        }
+        // Stable sort, so equal weights keep the order they were given in.
+        candidates.sort_by(|(_, left), (_, right)| right.total_cmp(left));
+        candidates
+            .into_iter()
+            .filter_map(|(tag, _)| {
+                let primary = tag.split('-').next().unwrap_or(tag).trim();
+                self.catalogs
+                    .keys()
+                    .find(|language| language.eq_ignore_ascii_case(primary))
+            })
+            .next()
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_LANGUAGE.to_owned())
+    }
+}
+
+fn parse_accept_language(header: &str) -> impl Iterator<Item = (&str, f32)> {
+    header.split(',').filter_map(|entry| {
+        let mut parts = entry.split(';');
+        let tag = parts.next()?.trim();
+        if tag.is_empty() || tag == "*" {
+            return None;
+        }
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        Some((tag, quality))
+    })
+}
+
+fn merge(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+                merge(base, overrides)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The language and time zone a session's pages and emails should be written in.
+#[derive(Clone, Debug)]
+pub struct UserLocale {
+    pub language: String,
+    pub time_zone: TimeZone,
+}
+
+impl UserLocale {
+    pub fn new(language: String, time_zone: Option<&str>) -> UserLocale {
+        let time_zone = time_zone
+            .and_then(|name| TimeZone::get(name).ok())
+            .unwrap_or(TimeZone::UTC);
+        UserLocale {
+            language,
+            time_zone,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Messages {
+    pub language_name: String,
+    pub dates: DateMessages,
+    pub results: ResultMessages,
+    pub subgroups: HashMap<String, String>,
+    pub index: IndexMessages,
+    pub privacy: PrivacyMessages,
+    pub session_created: SessionCreatedMessages,
+    pub graph: GraphMessages,
+    pub history: HistoryMessages,
+    pub batch: BatchMessages,
+    pub verified: VerifiedMessages,
+    pub email: EmailMessages,
+    pub qc: QcMessages,
+}
+
+impl Messages {
+    pub fn result(&self, result: DetectionResult) -> &str {
+        match result {
+            DetectionResult::Positive => &self.results.positive,
+            DetectionResult::Negative => &self.results.negative,
+            DetectionResult::Invalid => &self.results.invalid,
+            DetectionResult::Unknown => &self.results.unknown,
+        }
+    }
+
+    pub fn subgroup_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.subgroups.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    pub fn format_timestamp(&self, timestamp: Timestamp, time_zone: &TimeZone) -> String {
+        let zoned = timestamp.to_zoned(time_zone.clone());
+        let month = self
+            .dates
+            .months
+            .get(zoned.month() as usize - 1)
+            .cloned()
+            .unwrap_or_else(|| zoned.month().to_string());
+        self.dates
+            .format
+            .replace("{day}", &zoned.day().to_string())
+            .replace("{month}", &month)
+            .replace("{year}", &zoned.year().to_string())
+            .replace("{time}", &zoned.strftime("%H:%M").to_string())
+            .replace("{zone}", &zoned.strftime("%Z").to_string())
+    }
+
+    /// Explains a quality control check's outcome, filling in its parameters.
+    pub fn qc_reason(&self, check: &Check) -> String {
+        let qc = &self.qc;
+        let template = match check.reason {
+            Reason::ControlAmplified => &qc.control_amplified,
+            Reason::ControlSuppressed => &qc.control_suppressed,
+            Reason::ControlFailed => &qc.control_failed,
+            Reason::ControlMissing => &qc.control_missing,
+            Reason::SubgroupsExceedChannels => &qc.subgroups_exceed_channels,
+            Reason::PositiveWithoutTarget => &qc.positive_without_target,
+            Reason::NegativeWithTarget => &qc.negative_with_target,
+            Reason::ResultsAgree => &qc.results_agree,
+            Reason::Samples => &qc.samples,
+            Reason::SamplesOfExpected => &qc.samples_of_expected,
+            Reason::LargestGap => &qc.largest_gap,
+            Reason::NoTemperatures => &qc.no_temperatures,
+            Reason::TemperatureRange => &qc.temperature_range,
+            Reason::TooCold => &qc.too_cold,
+            Reason::TooHot => &qc.too_hot,
+        };
+        check
+            .params
+            .iter()
+            .fold(template.clone(), |text, (name, value)| {
+                text.replace(&format!("{{{}}}", name), value)
+            })
+    }
+
+    /// Writes a period in the largest whole unit that fits, rounding up, e.g. 90 minutes is "2 hours".
+    pub fn format_duration(&self, duration: Duration) -> String {
+        const HOUR: u64 = 60 * 60;
+        const DAY: u64 = 24 * HOUR;
+        let secs = duration.as_secs();
+        let (n, forms) = if secs >= DAY && secs.is_multiple_of(DAY) {
+            (secs / DAY, &self.dates.days)
+        } else if secs >= HOUR {
+            (secs.div_ceil(HOUR), &self.dates.hours)
+        } else {
+            (secs.div_ceil(60), &self.dates.minutes)
+        };
+        let form = if n == 1 { &forms[0] } else { &forms[1] };
+        form.replace("{n}", &n.to_string())
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DateMessages {
+    pub format: String,
+    pub months: Vec<String>,
+    pub minutes: [String; 2],
+    pub hours: [String; 2],
+    pub days: [String; 2],
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ResultMessages {
+    pub positive: String,
+    pub negative: String,
+    pub invalid: String,
+    pub unknown: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct IndexMessages {
+    pub title: String,
+    pub intro: String,
+    pub how_heading: String,
+    pub how_body: String,
+    pub stay_connected: String,
+    pub get_started: String,
+    pub email_label: String,
+    pub language_label: String,
+    pub archive_label: String,
+    pub history_label: String,
+    pub history_link: String,
+    pub submit: String,
+    pub privacy_link: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PrivacyMessages {
+    pub title: String,
+    pub heading: String,
+    pub approach_heading: String,
+    pub approach_body: String,
+    pub collected_heading: String,
+    pub collected_intro: String,
+    pub datum_column: String,
+    pub why_column: String,
+    pub retention_column: String,
+    pub email_datum: String,
+    pub email_why: String,
+    pub email_retention: String,
+    pub test_data_datum: String,
+    pub test_data_why: String,
+    pub test_data_retention: String,
+    pub locale_datum: String,
+    pub locale_why: String,
+    pub locale_retention: String,
+    pub archive_datum: String,
+    pub archive_why: String,
+    pub archive_retention: String,
+    pub history_datum: String,
+    pub history_why: String,
+    pub history_retention: String,
+    pub processors_heading: String,
+    pub processors_intro: String,
+    pub processor_hosting: String,
+    pub processor_email: String,
+    pub processor_management: String,
+    pub questions_heading: String,
+    pub questions_body: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SessionCreatedMessages {
+    pub heading: String,
+    pub copy_link: String,
+    pub copy_to_clipboard: String,
+    pub open_app: String,
+    pub open_settings: String,
+    pub paste_link: String,
+    pub run_test: String,
+    pub verification_pending: String,
+    pub expires: String,
+    pub live_graph_heading: String,
+    pub live_graph_link: String,
+    pub live_graph_stops: String,
+    pub live_graph_refresh: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct GraphMessages {
+    pub title: String,
+    pub no_data: String,
+    pub error: String,
+    pub results_heading: String,
+    pub overall: String,
+    pub subgroups_intro: String,
+    pub assay: String,
+    pub device_lock: String,
+    pub device_rejected: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HistoryMessages {
+    pub title: String,
+    pub heading: String,
+    pub intro: String,
+    pub email_label: String,
+    pub submit: String,
+    pub sent: String,
+    pub showing: String,
+    pub empty: String,
+    pub completed_column: String,
+    pub device_column: String,
+    pub test_column: String,
+    pub overall_column: String,
+    pub subgroups_column: String,
+    pub export: String,
+    pub delete: String,
+    pub deleted: String,
+    pub invalid_link: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BatchMessages {
+    pub title: String,
+    pub heading: String,
+    pub label_column: String,
+    pub state_column: String,
+    pub waiting: String,
+    pub running: String,
+    pub retest: String,
+    pub expired: String,
+    pub live_graph: String,
+    pub finished: String,
+    pub sheet_link: String,
+    pub sheet_title: String,
+    pub sheet_intro: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VerifiedMessages {
+    pub title: String,
+    pub heading: String,
+    pub body: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct EmailMessages {
+    pub results_subject: String,
+    pub results_heading: String,
+    pub overall: String,
+    pub subgroups_intro: String,
+    pub assay: String,
+    pub completed_at: String,
+    pub graph_alt: String,
+    pub error_subject: String,
+    pub error_heading: String,
+    pub error_intro: String,
+    pub request_id: String,
+    pub verify_subject: String,
+    pub verify_heading: String,
+    pub verify_intro: String,
+    pub verify_button: String,
+    pub verify_ignore: String,
+    pub expired_subject: String,
+    pub expired_heading: String,
+    pub expired_intro: String,
+    pub expired_created_at: String,
+    pub silent_subject: String,
+    pub silent_heading: String,
+    pub silent_intro: String,
+    pub retest_subject: String,
+    pub retest_heading: String,
+    pub retest_intro: String,
+    pub retest_details: String,
+    pub retest_invalid: String,
+    pub history_subject: String,
+    pub history_heading: String,
+    pub history_intro: String,
+    pub history_button: String,
+    pub history_ignore: String,
+    pub batch_subject: String,
+    pub batch_heading: String,
+    pub batch_intro: String,
+    pub batch_retest: String,
+    pub batch_expired: String,
+    pub footer: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct QcMessages {
+    pub control_amplified: String,
+    pub control_suppressed: String,
+    pub control_failed: String,
+    pub control_missing: String,
+    pub subgroups_exceed_channels: String,
+    pub positive_without_target: String,
+    pub negative_with_target: String,
+    pub results_agree: String,
+    pub samples: String,
+    pub samples_of_expected: String,
+    pub largest_gap: String,
+    pub no_temperatures: String,
+    pub temperature_range: String,
+    pub too_cold: String,
+    pub too_hot: String,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use jiff::{Timestamp, tz::TimeZone};
+
+    use super::Catalogs;
+
+    #[test]
+    fn negotiates_supported_language() {
+        let catalogs = Catalogs::load().unwrap();
+        assert_eq!("en", catalogs.negotiate(None, None));
+        assert_eq!(
+            "de",
+            catalogs.negotiate(None, Some("fr-FR, de-DE;q=0.8, en;q=0.5"))
+        );
+        assert_eq!("en", catalogs.negotiate(Some("en"), Some("de")));
+        assert_eq!("en", catalogs.negotiate(Some("xx"), Some("fr")));
+    }
+
+    #[test]
+    fn formats_timestamps_in_time_zone() {
+        let catalogs = Catalogs::load().unwrap();
+        let timestamp: Timestamp = "2025-03-01T12:30:00Z".parse().unwrap();
+        let time_zone = TimeZone::get("Europe/Berlin").unwrap();
+        assert_eq!(
+            "1. März 2025, 13:30 CET",
+            catalogs.get("de").format_timestamp(timestamp, &time_zone)
+        );
+    }
+
+    #[test]
+    fn formats_durations_in_the_largest_whole_unit() {
+        let catalogs = Catalogs::load().unwrap();
+        let en = catalogs.get("en");
+        assert_eq!("1 hour", en.format_duration(Duration::from_secs(60 * 60)));
+        assert_eq!("2 hours", en.format_duration(Duration::from_secs(90 * 60)));
+        assert_eq!(
+            "7 days",
+            en.format_duration(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            "36 hours",
+            en.format_duration(Duration::from_secs(36 * 60 * 60))
+        );
+        assert_eq!(
+            "15 Minuten",
+            catalogs
+                .get("de")
+                .format_duration(Duration::from_secs(15 * 60))
+        );
+    }
+}
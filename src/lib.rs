@@ -3,22 +3,72 @@ use serde::{Deserialize, Serialize};
 
 use crate::{messages::Message, state::State};
 
+pub mod api;
+pub mod archive;
+pub mod batches;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod config;
+pub mod devices;
+pub mod emails;
+pub mod expiry;
 pub mod graph;
+pub mod history;
+pub mod i18n;
 pub mod mailgun;
 pub mod messages;
+pub mod metrics;
 pub mod notifier;
+pub mod panels;
+pub mod persistence;
+pub mod qc;
+pub mod qr;
+pub mod rate_limit;
 pub mod sessions;
 pub mod state;
+pub mod telemetry;
+pub mod tokens;
+pub mod verification;
 pub mod websockets;
 
-#[derive(Debug)]
+#[derive(Debug, strum_macros::IntoStaticStr)]
 pub enum Error {
     TestFinishedMissingResult,
-    MissingTestFinished(State),
-    UnexpectedMessage(State, Box<Message>),
+    MissingTestFinished(Box<State>),
+    UnexpectedMessage(Box<State>, Box<Message>),
     TooManyChannels(usize),
+    SchemaDrift(messages::SchemaDrift),
+    UnsupportedVersion(Option<serde_json::Value>),
 
-    InvalidEnvVar {
+    UnknownSession,
+    TestNotFinished,
+    /// The session is bound to another device.
+    WrongDevice {
+        expected: u64,
+        received: u64,
+    },
+    RateLimited(std::time::Duration),
+    /// More was asked for at once than the rate limit ever allows, so retrying won't help.
+    OverRateLimitCapacity(u32),
+    TooManyActiveSessions,
+    ShuttingDown,
+    NoRecipients,
+    TooManyRecipients(usize),
+    UnknownRecipient,
+    LastRecipient,
+    TooManyViewers(usize),
+    UnknownViewer,
+    InvalidEmail(email_address::Error),
+
+    InvalidConfig {
+        name: String,
+        cause: Box<dyn std::error::Error + Send + Sync + 'static>,
+    },
+    InvalidCatalog {
+        language: String,
+        cause: Box<dyn std::error::Error + Send + Sync + 'static>,
+    },
+    InvalidPanels {
         name: String,
         cause: Box<dyn std::error::Error + Send + Sync + 'static>,
     },
@@ -27,6 +77,17 @@ pub enum Error {
     Serde(serde_json::Error),
     Plotting(plotters::drawing::DrawingAreaErrorKind<plotters_bitmap::BitMapBackendError>),
     Reqwest(reqwest::Error),
+    /// The server rejected a client request.
+    Api {
+        status: u16,
+        message: String,
+    },
+    #[cfg(feature = "client")]
+    Websocket(Box<tokio_tungstenite::tungstenite::Error>),
+    Template(askama::Error),
+    TemplateOverride(minijinja::Error),
+    SpanExporter(opentelemetry_otlp::ExporterBuildError),
+    Logging(tracing_subscriber::util::TryInitError),
 }
 
 impl Error {
@@ -36,11 +97,36 @@ impl Error {
             Error::MissingTestFinished(state) => Some(state),
             Error::UnexpectedMessage(state, _) => Some(state),
             Error::TooManyChannels(_) => None,
-            Error::InvalidEnvVar { .. } => None,
+            Error::SchemaDrift(_) => None,
+            Error::UnsupportedVersion(_) => None,
+            Error::UnknownSession => None,
+            Error::TestNotFinished => None,
+            Error::WrongDevice { .. } => None,
+            Error::RateLimited(_) => None,
+            Error::OverRateLimitCapacity(_) => None,
+            Error::TooManyActiveSessions => None,
+            Error::ShuttingDown => None,
+            Error::NoRecipients => None,
+            Error::TooManyRecipients(_) => None,
+            Error::UnknownRecipient => None,
+            Error::LastRecipient => None,
+            Error::TooManyViewers(_) => None,
+            Error::UnknownViewer => None,
+            Error::InvalidEmail(_) => None,
+            Error::InvalidConfig { .. } => None,
+            Error::InvalidCatalog { .. } => None,
+            Error::InvalidPanels { .. } => None,
             Error::Io(_) => None,
             Error::Serde(_) => None,
             Error::Plotting(_) => None,
             Error::Reqwest(_) => None,
+            Error::Api { .. } => None,
+            #[cfg(feature = "client")]
+            Error::Websocket(_) => None,
+            Error::Template(_) => None,
+            Error::TemplateOverride(_) => None,
+            Error::SpanExporter(_) => None,
+            Error::Logging(_) => None,
         }
     }
 }
@@ -51,6 +137,21 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<email_address::Error> for Error {
+    fn from(err: email_address::Error) -> Self {
+        Error::InvalidEmail(err)
+    }
+}
+
+impl From<rate_limit::Exceeded> for Error {
+    fn from(exceeded: rate_limit::Exceeded) -> Self {
+        match exceeded {
+            rate_limit::Exceeded::RetryAfter(retry_after) => Error::RateLimited(retry_after),
+            rate_limit::Exceeded::Capacity(capacity) => Error::OverRateLimitCapacity(capacity),
+        }
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Self {
         Error::Serde(err)
@@ -71,8 +172,40 @@ impl From<reqwest::Error> for Error {
     }
 }
 
+#[cfg(feature = "client")]
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::Websocket(Box::new(err))
+    }
+}
+
+impl From<askama::Error> for Error {
+    fn from(err: askama::Error) -> Self {
+        Error::Template(err)
+    }
+}
+
+impl From<minijinja::Error> for Error {
+    fn from(err: minijinja::Error) -> Self {
+        Error::TemplateOverride(err)
+    }
+}
+
+impl From<opentelemetry_otlp::ExporterBuildError> for Error {
+    fn from(err: opentelemetry_otlp::ExporterBuildError) -> Self {
+        Error::SpanExporter(err)
+    }
+}
+
+impl From<tracing_subscriber::util::TryInitError> for Error {
+    fn from(err: tracing_subscriber::util::TryInitError) -> Self {
+        Error::Logging(err)
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct LogWrapper {
     pub timestamp: Timestamp,
-    pub message: Message,
+    /// The message as it was received, in whichever wire format version it was sent in.
+    pub message: serde_json::Value,
 }
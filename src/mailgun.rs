@@ -1,15 +1,33 @@
+use std::str::FromStr;
+
 use email_address::EmailAddress;
 use mime::Mime;
 use reqwest::multipart::Part;
 use serde::Deserialize;
 
-use crate::Error;
+use crate::{
+    Error,
+    notifier::{OutgoingEmail, SendFuture, Transport},
+};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Region {
     EU,
     US,
 }
 
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "eu" => Ok(Region::EU),
+            "us" => Ok(Region::US),
+            _ => Err(format!("Expected one of 'eu' or 'us' but was '{}'", s)),
+        }
+    }
+}
+
 impl Region {
     fn base_url(&self) -> &'static str {
         match self {
@@ -19,6 +37,32 @@ impl Region {
     }
 }
 
+pub struct Mailgun {
+    pub domain: String,
+    pub region: Region,
+    pub api_key: String,
+}
+
+impl Transport for Mailgun {
+    fn send(&self, email: OutgoingEmail) -> SendFuture<'_> {
+        Box::pin(async move {
+            send_mailgun(
+                email.sender_name,
+                &email.sender_email,
+                std::slice::from_ref(&email.recipient),
+                email.email.subject,
+                email.email.text,
+                Some(email.email.html),
+                &self.region,
+                email.attachments,
+                &self.domain,
+                &self.api_key,
+            )
+            .await
+        })
+    }
+}
+
 pub struct Attachment {
     pub attachment_type: AttachmentType,
     pub name: String,
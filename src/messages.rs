@@ -1,18 +1,129 @@
+use std::{str::FromStr, time::Duration};
+
 use jiff::Timestamp;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_ignored::Path;
+
+use crate::{Error, messages::v1::MessageV1};
+
+pub mod v1;
+
+// Messages are parsed leniently by default: fields we don't know about are ignored and enum values we don't know about
+// become Unknown, so a virus.sucks or firmware update adding something doesn't break every running test. Whatever was
+// ignored is reported back as SchemaDrift so it can be noticed and supported. Strict mode rejects any drift instead.
+//
+// Each version of the wire format has its own types (see v1) which are decoded as sent, then normalised into the
+// Message type below, which is all the rest of the server sees. Supporting a new version means adding a module with a
+// WireMessage implementation and a line in Message::parse.
+
+pub const SUPPORTED_VERSIONS: &[u8] = &[MessageV1::VERSION];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseMode {
+    Strict,
+    Lenient,
+}
+
+impl FromStr for ParseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ParseMode::Strict),
+            "lenient" => Ok(ParseMode::Lenient),
+            _ => Err(format!(
+                "Expected one of 'strict' or 'lenient' but was '{}'",
+                s
+            )),
+        }
+    }
+}
+
+/// Parts of a message which this server doesn't understand.
+#[derive(Debug, Default, PartialEq)]
+pub struct SchemaDrift {
+    /// Paths of fields which were ignored, with array indices elided, e.g. "test.data.samples[].extra".
+    pub unknown_fields: Vec<String>,
+    /// Paths of values which weren't recognised, with the value itself.
+    pub unknown_values: Vec<(String, serde_json::Value)>,
+}
+
+impl SchemaDrift {
+    pub fn is_empty(&self) -> bool {
+        self.unknown_fields.is_empty() && self.unknown_values.is_empty()
+    }
+
+    /// One human-readable line per difference, for the raw message archive.
+    pub fn describe(&self) -> Vec<String> {
+        let fields = self
+            .unknown_fields
+            .iter()
+            .map(|path| format!("unknown field {}", path));
+        let values = self
+            .unknown_values
+            .iter()
+            .map(|(path, value)| format!("unknown value {} at {}", value, path));
+        fields.chain(values).collect()
+    }
+}
+
+/// A version of the wire format.
+pub trait WireMessage: DeserializeOwned + Into<Message> {
+    const VERSION: u8;
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+    /// Paths of enum values which weren't recognised, with the value as it appears in `raw`.
+    fn unknown_values(&self, raw: &serde_json::Value) -> Vec<(String, serde_json::Value)>;
+}
+
+#[derive(Debug)]
 pub struct Message {
-    pub version: u8,
     pub event: Event,
     pub device: Device,
     pub test: Test,
 }
 
+impl Message {
+    pub fn parse(
+        raw: &serde_json::Value,
+        mode: ParseMode,
+    ) -> Result<(Message, SchemaDrift), Error> {
+        let (message, drift) = match raw.get("version") {
+            Some(version) if *version == MessageV1::VERSION => decode::<MessageV1>(raw)?,
+            version => return Err(Error::UnsupportedVersion(version.cloned())),
+        };
+        match mode {
+            ParseMode::Strict if !drift.is_empty() => Err(Error::SchemaDrift(drift)),
+            _ => Ok((message, drift)),
+        }
+    }
+}
+
+fn decode<T: WireMessage>(raw: &serde_json::Value) -> Result<(Message, SchemaDrift), Error> {
+    let mut unknown_fields = Vec::new();
+    let wire: T = serde_ignored::deserialize(raw, |path| unknown_fields.push(field_path(&path)))?;
+    let drift = SchemaDrift {
+        unknown_fields,
+        unknown_values: wire.unknown_values(raw),
+    };
+    Ok((wire.into(), drift))
+}
+
+fn field_path(path: &Path) -> String {
+    match path {
+        Path::Root => String::new(),
+        Path::Seq { parent, .. } => format!("{}[]", field_path(parent)),
+        Path::Map { parent, key } => match field_path(parent) {
+            parent if parent.is_empty() => key.clone(),
+            parent => format!("{}.{}", parent, key),
+        },
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => field_path(parent),
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, strum_macros::Display, Serialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
-#[serde(deny_unknown_fields)]
 pub enum Event {
     TestStarted,
     ContinueTest,
@@ -20,40 +131,31 @@ pub enum Event {
     NewData,
     DeviceReady,
     AlreadyTesting,
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Clone, Debug)]
 pub struct Device {
-    #[serde(rename = "hwVersion")]
     pub hardware_version: String,
-    #[serde(rename = "swVersion")]
     pub software_version: String,
-    #[serde(rename = "deviceModel")]
-    pub device_model: String,
-    #[serde(rename = "sn")]
+    pub model: String,
     pub serial_number: u64,
     pub configuration: String,
-
-    #[serde(rename = "currentTemp")]
     pub current_temp: Option<DegreesC>,
-    #[serde(rename = "targetTemp")]
     pub target_temp: Option<DegreesC>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Debug)]
 pub struct Test {
     pub data: TestData,
     pub state: TestState,
     pub result: Option<TestResult>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Clone, Debug)]
 pub struct TestData {
     pub samples: Vec<TestSample>,
-    #[serde(rename = "temperatureSamples")]
     pub temperature_samples: Vec<TemperatureSample>,
 }
 
@@ -66,116 +168,159 @@ impl TestData {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Clone, Debug)]
 pub struct TestSample {
-    // current_data_index is the index of the sample within the test run.
-    #[serde(rename = "currentDataIndex")]
-    pub current_data_index: u8,
-
-    #[serde(rename = "firstChannelResult")]
-    pub first_channel_result: u32,
-
-    // So far this appears to always be exactly 1.
-    #[serde(rename = "numberOfChannels")]
-    pub number_of_channels: usize,
-
-    /// sample_stream_number is a test identifier, which virus.sucks currently always hard-codes to 166.
-    #[serde(rename = "sampleStreamNumber")]
-    pub sample_stream_number: u8,
-
-    /// sample_type appears to be a reserved field always set to 1.
-    #[serde(rename = "sampleType")]
-    pub sample_type: u8,
-
-    #[serde(rename = "samplingTemperature")]
-    pub sampling_temperature: DegreesC,
-
-    // This is hundred-milliseconds since start of test.
-    #[serde(rename = "samplingTime")]
-    pub sampling_time: u16,
-
-    // Each sample currently appears to contain exactly one channel's sample, so the meaning of starting is unclear.
-    // In the wire format, this is a u8.
-    #[serde(rename = "startingChannel")]
-    pub starting_channel: usize,
-
-    // total_number_of_samples always appears to be current_data_index + 1.
-    #[serde(rename = "totalNumberOfSamples")]
-    pub total_number_of_samples: u8,
+    /// The index of the sample within the test run.
+    pub index: u8,
+    pub channel: usize,
+    pub value: u32,
+    pub temperature: DegreesC,
+    /// Time since the start of the test.
+    pub elapsed: Duration,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Clone, Debug)]
 pub struct TemperatureSample {
     pub time: Timestamp,
     pub temp: DegreesC,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, PartialEq, Eq, Serialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
-#[serde(deny_unknown_fields)]
 pub enum TestState {
     Idle,
     Testing,
     Done,
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Debug)]
 pub struct TestResult {
-    // TODO: What is this?
-    #[serde(rename = "detectionType")]
+    /// Identifies the kind of cartridge, which panels map to an assay.
     pub detection_type: i64,
-
-    // Maybe same as sampleStreamNumber?
-    #[serde(rename = "detectionFlowNumber")]
-    pub detection_flow_number: i64,
-
-    #[serde(rename = "detectionResult")]
-    pub detection_result: DetectionResult,
-
-    // This appears to always be 7 so far.
-    #[serde(rename = "numberOfChannels")]
-    pub number_of_channels: u8,
-
-    // TODO: Is this ever not 0?
-    #[serde(rename = "startingChannel")]
-    pub starting_channel: usize,
-
-    #[serde(rename = "channelResults")]
+    pub overall: DetectionResult,
     pub channel_results: Vec<DetectionResult>,
-
-    #[serde(rename = "numberOfSubGroups")]
-    pub number_of_subgroups: usize,
-
-    #[serde(rename = "subGroupResults")]
     pub subgroup_results: Vec<SubgroupResult>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields)]
+#[derive(Clone, Debug, Deserialize, Serialize, utoipa::ToSchema)]
 pub struct SubgroupResult {
     pub name: String,
     pub result: DetectionResult,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, strum_macros::Display, Serialize)]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    PartialEq,
+    Eq,
+    Hash,
+    strum_macros::Display,
+    Serialize,
+    utoipa::ToSchema,
+)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
-#[serde(deny_unknown_fields)]
 pub enum DetectionResult {
     Positive,
     Negative,
     Invalid,
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
-#[serde(deny_unknown_fields, transparent)]
+#[serde(transparent)]
 pub struct DegreesC(pub f64);
 
 #[cfg(test)]
 mod test {
-    use super::Event;
+    use std::time::Duration;
+
+    use super::{DetectionResult, Event, Message, ParseMode, SchemaDrift};
+    use crate::Error;
+
+    fn fixture() -> serde_json::Value {
+        serde_json::from_str(include_str!(
+            "../tests/fixtures/synthetic/test-finished.json"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_fixture_strictly_without_drift() {
+        let (message, drift) = Message::parse(&fixture(), ParseMode::Strict).unwrap();
+        assert_eq!(Event::TestFinished, message.event);
+        assert!(drift.is_empty());
+    }
+
+    #[test]
+    fn lenient_mode_reports_unknown_fields_and_values() {
+        let mut raw = fixture();
+        raw["device"]["batteryLevel"] = 80.into();
+        raw["test"]["data"]["samples"][1]["gain"] = 2.into();
+        raw["event"] = "FIRMWARE_UPDATE".into();
+        raw["test"]["result"]["detectionResult"] = "INCONCLUSIVE".into();
+
+        let (message, drift) = Message::parse(&raw, ParseMode::Lenient).unwrap();
+        assert_eq!(Event::Unknown, message.event);
+        assert_eq!(
+            vec!["device.batteryLevel", "test.data.samples[].gain"],
+            drift.unknown_fields
+        );
+        assert_eq!(
+            vec![
+                ("event".to_owned(), "FIRMWARE_UPDATE".into()),
+                (
+                    "test.result.detectionResult".to_owned(),
+                    "INCONCLUSIVE".into()
+                ),
+            ],
+            drift.unknown_values
+        );
+        assert_eq!(
+            "unknown value \"FIRMWARE_UPDATE\" at event",
+            drift.describe()[2]
+        );
+
+        match Message::parse(&raw, ParseMode::Strict) {
+            Err(Error::SchemaDrift(SchemaDrift { unknown_fields, .. })) => {
+                assert_eq!(2, unknown_fields.len())
+            }
+            other => panic!("Expected schema drift but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn normalises_v1_messages() {
+        let (message, _) = Message::parse(&fixture(), ParseMode::Strict).unwrap();
+        let sample = &message.test.data.samples[1];
+        assert_eq!(1, sample.index);
+        assert_eq!(1, sample.channel);
+        assert_eq!(1001, sample.value);
+        assert_eq!(Duration::from_secs(1), sample.elapsed);
+        let result = message.test.result.unwrap();
+        assert_eq!(DetectionResult::Negative, result.overall);
+        assert_eq!(7, result.channel_results.len());
+        assert_eq!("IC", result.subgroup_results[1].name);
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let mut raw = fixture();
+        raw["version"] = 2.into();
+        match Message::parse(&raw, ParseMode::Lenient) {
+            Err(Error::UnsupportedVersion(Some(version))) => assert_eq!(2, version),
+            other => panic!("Expected an unsupported version but got {:?}", other),
+        }
+        raw.as_object_mut().unwrap().remove("version");
+        assert!(matches!(
+            Message::parse(&raw, ParseMode::Lenient),
+            Err(Error::UnsupportedVersion(None))
+        ));
+    }
 
     #[test]
     fn event_from_str() {
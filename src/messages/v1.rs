@@ -0,0 +1,331 @@
+use std::time::Duration;
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use crate::messages::{
+    DegreesC, DetectionResult, Device, Event, Message, SubgroupResult, TemperatureSample, Test,
+    TestData, TestResult, TestSample, TestState, WireMessage,
+};
+
+// The format virus.sucks sends today, field for field.
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageV1 {
+    pub version: u8,
+    pub event: Event,
+    pub device: DeviceV1,
+    pub test: TestV1,
+}
+
+impl WireMessage for MessageV1 {
+    const VERSION: u8 = 1;
+
+    fn unknown_values(&self, raw: &serde_json::Value) -> Vec<(String, serde_json::Value)> {
+        let mut unknown = Vec::new();
+        let mut check = |is_unknown: bool, path: &str, pointer: String| {
+            if is_unknown {
+                let value = raw.pointer(&pointer).cloned().unwrap_or_default();
+                unknown.push((path.to_owned(), value));
+            }
+        };
+        check(self.event == Event::Unknown, "event", "/event".to_owned());
+        check(
+            self.test.state == TestState::Unknown,
+            "test.state",
+            "/test/state".to_owned(),
+        );
+        if let Some(result) = &self.test.result {
+            check(
+                result.detection_result == DetectionResult::Unknown,
+                "test.result.detectionResult",
+                "/test/result/detectionResult".to_owned(),
+            );
+            for (i, channel_result) in result.channel_results.iter().enumerate() {
+                check(
+                    *channel_result == DetectionResult::Unknown,
+                    "test.result.channelResults[]",
+                    format!("/test/result/channelResults/{}", i),
+                );
+            }
+            for (i, subgroup) in result.subgroup_results.iter().enumerate() {
+                check(
+                    subgroup.result == DetectionResult::Unknown,
+                    "test.result.subGroupResults[].result",
+                    format!("/test/result/subGroupResults/{}/result", i),
+                );
+            }
+        }
+        unknown
+    }
+}
+
+impl From<MessageV1> for Message {
+    fn from(message: MessageV1) -> Message {
+        Message {
+            event: message.event,
+            device: message.device.into(),
+            test: message.test.into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeviceV1 {
+    #[serde(rename = "hwVersion")]
+    pub hardware_version: String,
+    #[serde(rename = "swVersion")]
+    pub software_version: String,
+    #[serde(rename = "deviceModel")]
+    pub device_model: String,
+    #[serde(rename = "sn")]
+    pub serial_number: u64,
+    pub configuration: String,
+
+    #[serde(rename = "currentTemp")]
+    pub current_temp: Option<DegreesC>,
+    #[serde(rename = "targetTemp")]
+    pub target_temp: Option<DegreesC>,
+}
+
+impl From<DeviceV1> for Device {
+    fn from(device: DeviceV1) -> Device {
+        Device {
+            hardware_version: device.hardware_version,
+            software_version: device.software_version,
+            model: device.device_model,
+            serial_number: device.serial_number,
+            configuration: device.configuration,
+            current_temp: device.current_temp,
+            target_temp: device.target_temp,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TestV1 {
+    pub data: TestDataV1,
+    pub state: TestState,
+    pub result: Option<TestResultV1>,
+}
+
+impl From<TestV1> for Test {
+    fn from(test: TestV1) -> Test {
+        Test {
+            data: test.data.into(),
+            state: test.state,
+            result: test.result.map(Into::into),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TestDataV1 {
+    pub samples: Vec<TestSampleV1>,
+    #[serde(rename = "temperatureSamples")]
+    pub temperature_samples: Vec<TemperatureSampleV1>,
+}
+
+impl From<TestDataV1> for TestData {
+    fn from(data: TestDataV1) -> TestData {
+        TestData {
+            samples: data.samples.into_iter().map(Into::into).collect(),
+            temperature_samples: data
+                .temperature_samples
+                .into_iter()
+                .map(|sample| TemperatureSample {
+                    time: sample.time,
+                    temp: sample.temp,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TestSampleV1 {
+    // current_data_index is the index of the sample within the test run.
+    #[serde(rename = "currentDataIndex")]
+    pub current_data_index: u8,
+
+    #[serde(rename = "firstChannelResult")]
+    pub first_channel_result: u32,
+
+    // So far this appears to always be exactly 1.
+    #[serde(rename = "numberOfChannels")]
+    pub number_of_channels: usize,
+
+    /// sample_stream_number is a test identifier, which virus.sucks currently always hard-codes to 166.
+    #[serde(rename = "sampleStreamNumber")]
+    pub sample_stream_number: u8,
+
+    /// sample_type appears to be a reserved field always set to 1.
+    #[serde(rename = "sampleType")]
+    pub sample_type: u8,
+
+    #[serde(rename = "samplingTemperature")]
+    pub sampling_temperature: DegreesC,
+
+    // This is hundred-milliseconds since start of test.
+    #[serde(rename = "samplingTime")]
+    pub sampling_time: u16,
+
+    // Each sample currently appears to contain exactly one channel's sample, so the meaning of starting is unclear.
+    // In the wire format, this is a u8.
+    #[serde(rename = "startingChannel")]
+    pub starting_channel: usize,
+
+    // total_number_of_samples always appears to be current_data_index + 1.
+    #[serde(rename = "totalNumberOfSamples")]
+    pub total_number_of_samples: u8,
+}
+
+impl From<TestSampleV1> for TestSample {
+    fn from(sample: TestSampleV1) -> TestSample {
+        TestSample {
+            index: sample.current_data_index,
+            channel: sample.starting_channel,
+            value: sample.first_channel_result,
+            temperature: sample.sampling_temperature,
+            elapsed: Duration::from_millis(u64::from(sample.sampling_time) * 100),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TemperatureSampleV1 {
+    pub time: Timestamp,
+    pub temp: DegreesC,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TestResultV1 {
+    // TODO: What is this?
+    #[serde(rename = "detectionType")]
+    pub detection_type: i64,
+
+    // Maybe same as sampleStreamNumber?
+    #[serde(rename = "detectionFlowNumber")]
+    pub detection_flow_number: i64,
+
+    #[serde(rename = "detectionResult")]
+    pub detection_result: DetectionResult,
+
+    // This appears to always be 7 so far.
+    #[serde(rename = "numberOfChannels")]
+    pub number_of_channels: u8,
+
+    // TODO: Is this ever not 0?
+    #[serde(rename = "startingChannel")]
+    pub starting_channel: usize,
+
+    #[serde(rename = "channelResults")]
+    pub channel_results: Vec<DetectionResult>,
+
+    #[serde(rename = "numberOfSubGroups")]
+    pub number_of_subgroups: usize,
+
+    #[serde(rename = "subGroupResults")]
+    pub subgroup_results: Vec<SubgroupResultV1>,
+}
+
+impl From<TestResultV1> for TestResult {
+    fn from(result: TestResultV1) -> TestResult {
+        TestResult {
+            detection_type: result.detection_type,
+            overall: result.detection_result,
+            channel_results: result.channel_results,
+            subgroup_results: result
+                .subgroup_results
+                .into_iter()
+                .map(|subgroup| SubgroupResult {
+                    name: subgroup.name,
+                    result: subgroup.result,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SubgroupResultV1 {
+    pub name: String,
+    pub result: DetectionResult,
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::MessageV1;
+    use crate::messages::{Event, Message, ParseMode, WireMessage};
+
+    /// Every message under tests/fixtures/v1, so a newly added capture is checked without touching the tests.
+    fn fixtures() -> Vec<(String, serde_json::Value)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v1");
+        let mut fixtures: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "json")
+            })
+            .map(|path| {
+                let raw = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+                (path.display().to_string(), raw)
+            })
+            .collect();
+        fixtures.sort_by(|a, b| a.0.cmp(&b.0));
+        fixtures
+    }
+
+    #[test]
+    #[ignore = "no real v1 captures have been collected yet, see tests/fixtures/v1/README.md"]
+    fn fixtures_round_trip_field_for_field() {
+        let fixtures = fixtures();
+        assert!(!fixtures.is_empty());
+        for (name, raw) in fixtures {
+            let wire: MessageV1 = serde_json::from_value(raw.clone())
+                .unwrap_or_else(|err| panic!("{} didn't parse: {}", name, err));
+            assert_eq!(raw, serde_json::to_value(&wire).unwrap(), "{}", name);
+            assert!(wire.unknown_values(&raw).is_empty(), "{}", name);
+
+            let (message, drift) = Message::parse(&raw, ParseMode::Strict)
+                .unwrap_or_else(|err| panic!("{} didn't parse strictly: {:?}", name, err));
+            assert!(drift.is_empty(), "{}", name);
+            assert_eq!(
+                message.event == Event::TestFinished,
+                message.test.result.is_some(),
+                "{} should only carry a result once the test has finished",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_values_are_reported_where_they_appear() {
+        let mut raw: serde_json::Value = serde_json::from_str(include_str!(
+            "../../tests/fixtures/synthetic/test-finished.json"
+        ))
+        .unwrap();
+        raw["test"]["state"] = "PAUSED".into();
+        raw["test"]["result"]["channelResults"][3] = "WEAK_POSITIVE".into();
+        raw["test"]["result"]["subGroupResults"][1]["result"] = "RETRY".into();
+
+        let wire: MessageV1 = serde_json::from_value(raw.clone()).unwrap();
+        assert_eq!(
+            vec![
+                ("test.state".to_owned(), "PAUSED".into()),
+                (
+                    "test.result.channelResults[]".to_owned(),
+                    "WEAK_POSITIVE".into()
+                ),
+                (
+                    "test.result.subGroupResults[].result".to_owned(),
+                    "RETRY".into()
+                ),
+            ],
+            wire.unknown_values(&raw)
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use std::time::Instant;
+
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{counter::Counter, family::Family, gauge::Gauge, histogram::Histogram},
+    registry::Registry,
+};
+
+use crate::{
+    Error,
+    messages::{Event, SchemaDrift},
+    qc::Report,
+};
+
+// Counters are updated as things happen, whereas gauges describing what's currently in memory (e.g. active sessions)
+// are set from the session map just before each scrape, so they can never drift from the truth.
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct EventLabels {
+    event: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ErrorLabels {
+    kind: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SchemaDriftLabels {
+    kind: &'static str,
+    path: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct QcLabels {
+    check: &'static str,
+    outcome: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct NotificationLabels {
+    channel: &'static str,
+    kind: &'static str,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    active_sessions: Gauge,
+    connected_websockets: Gauge,
+    sessions_created: Counter,
+    sessions_completed: Counter,
+    sessions_expired: Counter,
+    sessions_silent: Counter,
+    messages_received: Family<EventLabels, Counter>,
+    errors: Family<ErrorLabels, Counter>,
+    schema_drift: Family<SchemaDriftLabels, Counter>,
+    qc_checks: Family<QcLabels, Counter>,
+    notification_duration: Family<NotificationLabels, Histogram>,
+    notification_failures: Family<NotificationLabels, Counter>,
+    graph_render_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let mut registry = Registry::with_prefix("pluslife");
+        let active_sessions = Gauge::default();
+        registry.register(
+            "active_sessions",
+            "Sessions currently held in memory",
+            active_sessions.clone(),
+        );
+        let connected_websockets = Gauge::default();
+        registry.register(
+            "connected_websockets",
+            "Live graph websockets attached to sessions held in memory",
+            connected_websockets.clone(),
+        );
+        let sessions_created = Counter::default();
+        registry.register(
+            "sessions_created",
+            "Sessions created",
+            sessions_created.clone(),
+        );
+        let sessions_completed = Counter::default();
+        registry.register(
+            "sessions_completed",
+            "Sessions which received a test result",
+            sessions_completed.clone(),
+        );
+        let sessions_expired = Counter::default();
+        registry.register(
+            "sessions_expired",
+            "Sessions removed without a result, after CLEANUP_PERIOD without new data or by an operator",
+            sessions_expired.clone(),
+        );
+        let sessions_silent = Counter::default();
+        registry.register(
+            "sessions_silent",
+            "Sessions whose recipients were warned after INACTIVITY_WARNING without new data",
+            sessions_silent.clone(),
+        );
+        let messages_received = Family::<EventLabels, Counter>::default();
+        registry.register(
+            "messages_received",
+            "Webhook messages accepted, by event",
+            messages_received.clone(),
+        );
+        let errors = Family::<ErrorLabels, Counter>::default();
+        registry.register("errors", "Errors, by kind", errors.clone());
+        let schema_drift = Family::<SchemaDriftLabels, Counter>::default();
+        registry.register(
+            "schema_drift",
+            "Unknown fields and values seen in webhook messages, by kind and path",
+            schema_drift.clone(),
+        );
+        let qc_checks = Family::<QcLabels, Counter>::default();
+        registry.register(
+            "qc_checks",
+            "Quality control checks run on completed tests, by check and outcome",
+            qc_checks.clone(),
+        );
+        let notification_duration =
+            Family::<NotificationLabels, Histogram>::new_with_constructor(|| {
+                Histogram::new([0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0])
+            });
+        registry.register(
+            "notification_duration_seconds",
+            "Time taken to send a notification, by channel and kind",
+            notification_duration.clone(),
+        );
+        let notification_failures = Family::<NotificationLabels, Counter>::default();
+        registry.register(
+            "notification_failures",
+            "Notifications which failed to send, by channel and kind",
+            notification_failures.clone(),
+        );
+        let graph_render_duration = Histogram::new([0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]);
+        registry.register(
+            "graph_render_duration_seconds",
+            "Time taken to render a graph PNG",
+            graph_render_duration.clone(),
+        );
+        Metrics {
+            registry,
+            active_sessions,
+            connected_websockets,
+            sessions_created,
+            sessions_completed,
+            sessions_expired,
+            sessions_silent,
+            messages_received,
+            errors,
+            schema_drift,
+            qc_checks,
+            notification_duration,
+            notification_failures,
+            graph_render_duration,
+        }
+    }
+
+    pub fn session_created(&self) {
+        self.sessions_created.inc();
+    }
+
+    pub fn session_completed(&self) {
+        self.sessions_completed.inc();
+    }
+
+    pub fn session_expired(&self) {
+        self.sessions_expired.inc();
+    }
+
+    pub fn session_silent(&self) {
+        self.sessions_silent.inc();
+    }
+
+    pub fn message_received(&self, event: Event) {
+        self.messages_received
+            .get_or_create(&EventLabels {
+                event: event.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn error(&self, error: &Error) {
+        self.errors
+            .get_or_create(&ErrorLabels { kind: error.into() })
+            .inc();
+    }
+
+    pub fn schema_drift(&self, drift: &SchemaDrift) {
+        for path in &drift.unknown_fields {
+            self.schema_drift
+                .get_or_create(&SchemaDriftLabels {
+                    kind: "field",
+                    path: path.clone(),
+                })
+                .inc();
+        }
+        for (path, _) in &drift.unknown_values {
+            self.schema_drift
+                .get_or_create(&SchemaDriftLabels {
+                    kind: "value",
+                    path: path.clone(),
+                })
+                .inc();
+        }
+    }
+
+    pub fn qc(&self, report: &Report) {
+        for check in &report.checks {
+            self.qc_checks
+                .get_or_create(&QcLabels {
+                    check: check.name,
+                    outcome: check.outcome.to_string(),
+                })
+                .inc();
+        }
+    }
+
+    /// Records how long a notification took to send over `channel` (e.g. "email"), and whether it failed.
+    pub fn notification_sent<T, E>(
+        &self,
+        channel: &'static str,
+        kind: &'static str,
+        started: Instant,
+        result: &Result<T, E>,
+    ) {
+        let labels = NotificationLabels { channel, kind };
+        self.notification_duration
+            .get_or_create(&labels)
+            .observe(started.elapsed().as_secs_f64());
+        if result.is_err() {
+            self.notification_failures.get_or_create(&labels).inc();
+        }
+    }
+
+    pub fn time_graph_render<T>(&self, render: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = tracing::info_span!("render_graph").in_scope(render);
+        self.graph_render_duration
+            .observe(started.elapsed().as_secs_f64());
+        result
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn encode(&self, active_sessions: usize, connected_websockets: usize) -> String {
+        self.active_sessions.set(active_sessions as i64);
+        self.connected_websockets.set(connected_websockets as i64);
+        let mut out = String::new();
+        encode(&mut out, &self.registry).expect("Writing to a String should not fail");
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use crate::{Error, messages::Event};
+
+    #[test]
+    fn encodes_labelled_metrics() {
+        let metrics = Metrics::new();
+        metrics.message_received(Event::NewData);
+        metrics.message_received(Event::NewData);
+        metrics.error(&Error::UnknownSession);
+        let text = metrics.encode(3, 1);
+        assert!(text.contains("pluslife_active_sessions 3\n"));
+        assert!(text.contains("pluslife_messages_received_total{event=\"NewData\"} 2\n"));
+        assert!(text.contains("pluslife_errors_total{kind=\"UnknownSession\"} 1\n"));
+    }
+}
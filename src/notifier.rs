@@ -1,112 +1,184 @@
+use std::{pin::Pin, sync::Arc, time::Duration};
+
 use email_address::EmailAddress;
+use jiff::Timestamp;
 use uuid::Uuid;
 
 use crate::{
     Error,
-    mailgun::{Attachment, Region, send_mailgun},
-    messages::SubgroupResult,
+    batches::Batch,
+    emails::{
+        BatchSummaryEmail, EmailTemplates, ErrorEmail, ExpiredEmail, HistoryLinkEmail,
+        RenderedEmail, ResultEmail, RetestEmail, SilentEmail, VerifyEmail,
+    },
+    i18n::{Catalogs, UserLocale},
+    mailgun::{Attachment, SendResponse},
     state::CompletedTest,
 };
 
 const SENDER_NAME: &str = "PlusLife Results";
 
-pub async fn notify(
-    sender_email: &EmailAddress,
-    mailgun_domain: &str,
-    mailgun_api_key: &str,
-    completed_test: CompletedTest,
-    recipient: EmailAddress,
-) -> Result<(), Error> {
-    let html = format!(
-        r#"<h2>Your PlusLife results are in.</h2>
-
-<p>Your overall result is: {}</p>
-<p>Your subgroup results are:</p>
-{}
-"#,
-        completed_test.overall,
-        to_html_list(&completed_test.subgroup_results)
-    );
-    let text = format!(
-        r#"Your PlusLife results are in.
-
-Your overall result is: {}
-Your subgroup results are:
-{}
-"#,
-        completed_test.overall,
-        to_markdown_list(&completed_test.subgroup_results),
-    );
-
-    let attachments = vec![Attachment {
-        attachment_type: crate::mailgun::AttachmentType::Inline,
-        name: "graph.png".to_string(),
-        bytes: completed_test.graph_png,
-        mime_type: mime::IMAGE_PNG,
-    }];
-
-    send_mailgun(
-        SENDER_NAME,
-        sender_email,
-        &[recipient],
-        "Your PlusLife Results".to_owned(),
-        text,
-        Some(html),
-        &Region::EU,
-        attachments,
-        mailgun_domain,
-        mailgun_api_key,
-    )
-    .await?;
-    Ok(())
+/// An email ready to be delivered to a single recipient.
+pub struct OutgoingEmail {
+    pub sender_name: &'static str,
+    pub sender_email: EmailAddress,
+    pub recipient: EmailAddress,
+    pub email: RenderedEmail,
+    pub attachments: Vec<Attachment>,
 }
 
-pub async fn notify_error(
-    sender_email: &EmailAddress,
-    mailgun_domain: &str,
-    mailgun_api_key: &str,
-    id: &Uuid,
-    error: &str,
-    recipient: EmailAddress,
-) -> Result<(), Error> {
-    send_mailgun(
-        SENDER_NAME,
-        sender_email,
-        &[recipient],
-        "Error getting PlusLife results".to_owned(),
-        format!("Sorry, an error occurred notifying you of your PlusLife result: {}. Your request ID was {}", error, id),
-        None,
-        &Region::EU,
-        Vec::new(),
-        mailgun_domain,
-        mailgun_api_key,
-    ).await?;
-    Ok(())
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<SendResponse, Error>> + Send + 'a>>;
+
+/// Delivers emails, i.e. [`crate::mailgun::Mailgun`] outside of tests.
+pub trait Transport: Send + Sync {
+    fn send(&self, email: OutgoingEmail) -> SendFuture<'_>;
 }
 
-fn to_html_list(results: &[SubgroupResult]) -> String {
-    let mut str = "<ul>\n".to_owned();
-    for result in results {
-        str.push_str("  <li><strong>");
-        str.push_str(normalise_result_name(&result.name));
-        str.push_str("</strong>: ");
-        str.push_str(&result.result.to_string());
-        str.push_str("</li>\n");
-    }
-    str.push_str("</ul>");
-    str
+/// Everything needed to render an email in the recipient's language and send it.
+pub struct Mailer {
+    pub sender_email: EmailAddress,
+    pub transport: Arc<dyn Transport>,
+    pub templates: EmailTemplates,
+    pub catalogs: Arc<Catalogs>,
 }
 
-fn to_markdown_list(results: &[SubgroupResult]) -> String {
-    let mut str = String::new();
-    for result in results {
-        str.push_str(" * ");
-        str.push_str(normalise_result_name(&result.name));
-        str.push('\n');
+impl Mailer {
+    async fn send(
+        &self,
+        recipient: &EmailAddress,
+        email: RenderedEmail,
+        attachments: Vec<Attachment>,
+    ) -> Result<SendResponse, Error> {
+        self.transport
+            .send(OutgoingEmail {
+                sender_name: SENDER_NAME,
+                sender_email: self.sender_email.clone(),
+                recipient: recipient.clone(),
+                email,
+                attachments,
+            })
+            .await
+    }
+
+    pub async fn notify(
+        &self,
+        locale: &UserLocale,
+        completed_test: CompletedTest,
+        recipient: EmailAddress,
+    ) -> Result<SendResponse, Error> {
+        let messages = self.catalogs.get(&locale.language);
+        let completed_at = messages.format_timestamp(completed_test.completed, &locale.time_zone);
+        let email = if completed_test.needs_retest() {
+            self.templates
+                .render(&RetestEmail::new(messages, &completed_test, completed_at))?
+        } else {
+            self.templates.render(&ResultEmail::new(
+                messages,
+                &locale.language,
+                completed_test.overall,
+                &completed_test.subgroup_results,
+                completed_test.panel.as_deref(),
+                completed_at,
+            ))?
+        };
+
+        let attachments = vec![Attachment {
+            attachment_type: crate::mailgun::AttachmentType::Inline,
+            name: "graph.png".to_string(),
+            bytes: completed_test.graph_png,
+            mime_type: mime::IMAGE_PNG,
+        }];
+
+        self.send(&recipient, email, attachments).await
+    }
+
+    pub async fn notify_error(
+        &self,
+        locale: &UserLocale,
+        id: &Uuid,
+        error: &str,
+        recipient: EmailAddress,
+    ) -> Result<(), Error> {
+        let email = self.templates.render(&ErrorEmail {
+            t: self.catalogs.get(&locale.language).email.clone(),
+            id: *id,
+            error: error.to_owned(),
+        })?;
+        self.send(&recipient, email, Vec::new()).await?;
+        Ok(())
     }
-    str
-}
 
-fn normalise_result_name(name: &str) -> &str {
-    if name == "IC" { "Control" } else { name }
+    pub async fn notify_expired(
+        &self,
+        locale: &UserLocale,
+        id: &Uuid,
+        created: Timestamp,
+        recipient: EmailAddress,
+    ) -> Result<(), Error> {
+        let messages = self.catalogs.get(&locale.language);
+        let email = self.templates.render(&ExpiredEmail {
+            t: messages.email.clone(),
+            id: *id,
+            created_at: messages.format_timestamp(created, &locale.time_zone),
+        })?;
+        self.send(&recipient, email, Vec::new()).await?;
+        Ok(())
+    }
+
+    pub async fn notify_silent(
+        &self,
+        locale: &UserLocale,
+        id: &Uuid,
+        silence: Duration,
+        recipient: EmailAddress,
+    ) -> Result<(), Error> {
+        let messages = self.catalogs.get(&locale.language);
+        let email = self.templates.render(&SilentEmail {
+            t: messages.email.clone(),
+            id: *id,
+            intro: messages
+                .email
+                .silent_intro
+                .replace("{minutes}", &(silence.as_secs() / 60).to_string()),
+        })?;
+        self.send(&recipient, email, Vec::new()).await?;
+        Ok(())
+    }
+
+    pub async fn notify_verification(
+        &self,
+        locale: &UserLocale,
+        link: String,
+        recipient: EmailAddress,
+    ) -> Result<(), Error> {
+        let email = self.templates.render(&VerifyEmail {
+            t: self.catalogs.get(&locale.language).email.clone(),
+            link,
+        })?;
+        self.send(&recipient, email, Vec::new()).await?;
+        Ok(())
+    }
+
+    pub async fn notify_history_link(
+        &self,
+        locale: &UserLocale,
+        link: String,
+        recipient: EmailAddress,
+    ) -> Result<(), Error> {
+        let email = self.templates.render(&HistoryLinkEmail {
+            t: self.catalogs.get(&locale.language).email.clone(),
+            link,
+        })?;
+        self.send(&recipient, email, Vec::new()).await?;
+        Ok(())
+    }
+
+    pub async fn notify_batch_summary(&self, batch: &Batch) -> Result<(), Error> {
+        let email = self.templates.render(&BatchSummaryEmail::new(
+            self.catalogs.get(&batch.locale.language),
+            batch,
+        ))?;
+        self.send(&batch.coordinator, email, Vec::new()).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,217 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use plotters::style::RGBColor;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    Error,
+    i18n::DEFAULT_LANGUAGE,
+    messages::{DetectionResult, TestResult},
+    qc::Limits,
+};
+
+// The device reports subgroups and channels by code (e.g. IC) and the cartridge only by a numeric detection type, so
+// what they mean lives in a registry of panels: panels.toml, compiled in, unless PANELS_FILE points at a replacement.
+// See that file for the format. A test which matches no panel is still reported, just without the friendlier wording.
+
+const BUILTIN: &str = include_str!("../panels.toml");
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Panels {
+    pub panels: Vec<Panel>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Panel {
+    pub name: Localised,
+    #[serde(default)]
+    pub detection_types: Vec<i64>,
+    #[serde(default)]
+    pub subgroups: Vec<String>,
+    #[serde(default)]
+    pub subgroup_names: BTreeMap<String, Localised>,
+    #[serde(default)]
+    pub results: HashMap<DetectionResult, Localised>,
+    #[serde(default)]
+    pub channels: Vec<Channel>,
+    pub guidance: Option<Localised>,
+    #[serde(default)]
+    pub qc: Limits,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Channel {
+    pub label: String,
+    pub colour: Colour,
+}
+
+/// Text keyed by language.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Localised(BTreeMap<String, String>);
+
+impl Localised {
+    /// Falls back to English, then to any language at all.
+    pub fn get(&self, language: &str) -> &str {
+        self.0
+            .get(language)
+            .or_else(|| self.0.get(DEFAULT_LANGUAGE))
+            .or_else(|| self.0.values().next())
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+}
+
+/// A colour written as #rrggbb.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Colour(pub RGBColor);
+
+impl TryFrom<String> for Colour {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Expected a colour like #a6cee3 but was '{}'", value);
+        let hex = value
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6)
+            .ok_or_else(invalid)?;
+        let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+        Ok(Colour(RGBColor(
+            component(0)?,
+            component(2)?,
+            component(4)?,
+        )))
+    }
+}
+
+impl From<Colour> for String {
+    fn from(colour: Colour) -> String {
+        let RGBColor(r, g, b) = colour.0;
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+impl Panels {
+    pub fn builtin() -> Result<Panels, Error> {
+        Self::parse(BUILTIN, "builtin")
+    }
+
+    pub fn from_file(path: &Path) -> Result<Panels, Error> {
+        info!(path = %path.display(), "Loading panels");
+        let source = std::fs::read_to_string(path).map_err(|err| Error::InvalidPanels {
+            name: path.display().to_string(),
+            cause: Box::new(err),
+        })?;
+        Self::parse(&source, &path.display().to_string())
+    }
+
+    fn parse(source: &str, name: &str) -> Result<Panels, Error> {
+        toml::from_str(source).map_err(|err| Error::InvalidPanels {
+            name: name.to_owned(),
+            cause: Box::new(err),
+        })
+    }
+
+    pub fn find(&self, result: &TestResult) -> Option<&Panel> {
+        self.panels
+            .iter()
+            .find(|panel| panel.detection_types.contains(&result.detection_type))
+            .or_else(|| {
+                let mut reported: Vec<&str> = result
+                    .subgroup_results
+                    .iter()
+                    .map(|subgroup| subgroup.name.as_str())
+                    .collect();
+                reported.sort_unstable();
+                self.panels.iter().find(|panel| {
+                    let mut subgroups: Vec<&str> =
+                        panel.subgroups.iter().map(String::as_str).collect();
+                    subgroups.sort_unstable();
+                    !subgroups.is_empty() && subgroups == reported
+                })
+            })
+    }
+}
+
+impl Panel {
+    pub fn subgroup_name(&self, name: &str, language: &str) -> Option<&str> {
+        self.subgroup_names
+            .get(name)
+            .map(|localised| localised.get(language))
+    }
+
+    pub fn result(&self, result: DetectionResult, language: &str) -> Option<&str> {
+        self.results
+            .get(&result)
+            .map(|localised| localised.get(language))
+    }
+
+    /// The colour and label of each channel, for drawing the graph.
+    pub fn graph_lines(&self) -> Vec<(RGBColor, Option<String>)> {
+        self.channels
+            .iter()
+            .map(|channel| (channel.colour.0, Some(channel.label.clone())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Panels;
+    use crate::messages::{Message, ParseMode};
+
+    #[test]
+    fn matches_builtin_panels_by_detection_type_or_subgroups() {
+        let raw = serde_json::from_str(include_str!(
+            "../tests/fixtures/synthetic/test-finished.json"
+        ))
+        .unwrap();
+        let (message, _) = Message::parse(&raw, ParseMode::Strict).unwrap();
+        let mut result = message.test.result.unwrap();
+        let panels = Panels::builtin().unwrap();
+
+        let panel = panels.find(&result).unwrap();
+        assert_eq!("COVID-19", panel.name.get("de"));
+        assert_eq!(7, panel.graph_lines().len());
+        assert_eq!(Some("Interne Kontrolle"), panel.subgroup_name("IC", "de"));
+        assert_eq!(
+            Some("Not detected"),
+            panel.result(result.subgroup_results[0].result, "fr")
+        );
+
+        result.detection_type = 99;
+        assert!(panels.find(&result).is_some());
+        result.subgroup_results.pop();
+        assert!(panels.find(&result).is_none());
+    }
+
+    #[test]
+    fn reads_qc_limits_per_panel() {
+        let panels = Panels::parse(
+            r#"
+            [[panels]]
+            name = { en = "Measured" }
+            qc = { expected_samples = 40, min_temperature = 62.5 }
+            "#,
+            "test",
+        )
+        .unwrap();
+        let limits = &panels.panels[0].qc;
+        assert_eq!(Some(40), limits.expected_samples);
+        assert_eq!(Some(62.5), limits.min_temperature.map(|limit| limit.0));
+        assert_eq!(Some(66.0), limits.max_temperature.map(|limit| limit.0));
+        assert_eq!(Some(120), limits.max_sample_gap_seconds);
+        assert!(
+            Panels::parse(
+                "[[panels]]\nname = { en = \"Typo\" }\nqc = { expected = 40 }",
+                "test"
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use std::{io, net::IpAddr, path::Path};
+
+use email_address::EmailAddress;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    Error,
+    sessions::{RawMessage, RejectedDevice, Viewer},
+};
+
+// On shutdown, sessions which are still waiting for a result are written to a single JSON file, and read back on the
+// next startup. Test state isn't saved directly: the raw messages are, and replaying them rebuilds it. Completed
+// sessions aren't saved, as their results have already been sent.
+
+#[derive(Deserialize, Serialize)]
+pub struct PersistedSession {
+    pub id: Uuid,
+    pub ingest_token: String,
+    pub created: Timestamp,
+    /// When the session is due to expire, which is later than `created` plus the cleanup period if data kept arriving.
+    /// Files saved before this was recorded fall back to that.
+    #[serde(default)]
+    pub expires: Option<Timestamp>,
+    pub recipients: Vec<PersistedRecipient>,
+    pub language: String,
+    pub time_zone: Option<String>,
+    pub creator_ip: Option<IpAddr>,
+    pub viewers: Vec<Viewer>,
+    pub archive: bool,
+    #[serde(default)]
+    pub history: bool,
+    pub raw_messages: Vec<RawMessage>,
+    #[serde(default)]
+    pub rejected_device: Option<RejectedDevice>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PersistedRecipient {
+    pub email: EmailAddress,
+    pub verified: bool,
+    pub verification_token: Option<String>,
+}
+
+/// Writes the sessions to a temporary file first, so a crash part way through can't leave a truncated file behind.
+pub fn save(path: &Path, sessions: &[PersistedSession]) -> Result<(), Error> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, serde_json::to_vec(sessions)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Reads and deletes the saved sessions, so they can't be restored twice.
+pub fn take(path: &Path) -> Result<Vec<PersistedSession>, Error> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let sessions = serde_json::from_slice(&contents)?;
+    std::fs::remove_file(path)?;
+    Ok(sessions)
+}
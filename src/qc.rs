@@ -0,0 +1,343 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::messages::{DegreesC, DetectionResult, TestData, TestResult};
+
+// Quality control runs over every completed test before anyone is notified. The device's own verdict is taken at face
+// value, but a run which looks wrong (the internal control didn't amplify, the per-channel results don't add up to the
+// subgroup results, the run was cut short or had gaps, or the heater wandered off temperature) shouldn't be reported
+// as a confident result. Any failed check turns the result email into a request to retest. The limits for the run
+// length, gaps and temperature come from the panel the test matched, falling back to defaults for gaps and temperature.
+
+pub const INTERNAL_CONTROL: &str = "IC";
+
+// The device samples steadily through a run and holds the reaction at 63°C, so a two minute silence or a few degrees of
+// drift either way means the run can't be trusted, whatever the cartridge.
+const DEFAULT_MAX_SAMPLE_GAP_SECONDS: u64 = 120;
+const DEFAULT_MIN_TEMPERATURE: DegreesC = DegreesC(60.0);
+const DEFAULT_MAX_TEMPERATURE: DegreesC = DegreesC(66.0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, strum_macros::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub reason: Reason,
+    /// Values for the reason's placeholders, already formatted.
+    pub params: Vec<(&'static str, String)>,
+}
+
+/// Why a check came out the way it did, as a key into the qc section of the message catalog.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    ControlAmplified,
+    ControlSuppressed,
+    ControlFailed,
+    ControlMissing,
+    SubgroupsExceedChannels,
+    PositiveWithoutTarget,
+    NegativeWithTarget,
+    ResultsAgree,
+    Samples,
+    SamplesOfExpected,
+    LargestGap,
+    NoTemperatures,
+    TemperatureRange,
+    TooCold,
+    TooHot,
+}
+
+impl Check {
+    fn new(name: &'static str, outcome: Outcome, reason: Reason) -> Check {
+        Check {
+            name,
+            outcome,
+            reason,
+            params: Vec::new(),
+        }
+    }
+
+    fn with(mut self, param: &'static str, value: impl ToString) -> Check {
+        self.params.push((param, value.to_string()));
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// The worst outcome of any check.
+    pub fn outcome(&self) -> Outcome {
+        self.checks
+            .iter()
+            .map(|check| check.outcome)
+            .max()
+            .unwrap_or(Outcome::Pass)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.checks
+            .iter()
+            .filter(|check| check.outcome == Outcome::Fail)
+    }
+}
+
+/// What a run of a panel should look like, set per panel in the panel registry. The gap and temperature limits default
+/// to bounds any isothermal run should stay within, and a panel can tighten them from real runs of its cartridge. The
+/// run length depends entirely on the cartridge, so it is only checked where a panel sets it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// How many samples a full run produces. Runs with fewer only warn, as the device's own verdict may still stand.
+    pub expected_samples: Option<usize>,
+    /// The longest time between consecutive samples before the run is considered to have gaps.
+    pub max_sample_gap_seconds: Option<u64>,
+    /// The range the reaction temperature must stay within.
+    pub min_temperature: Option<DegreesC>,
+    pub max_temperature: Option<DegreesC>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            expected_samples: None,
+            max_sample_gap_seconds: Some(DEFAULT_MAX_SAMPLE_GAP_SECONDS),
+            min_temperature: Some(DEFAULT_MIN_TEMPERATURE),
+            max_temperature: Some(DEFAULT_MAX_TEMPERATURE),
+        }
+    }
+}
+
+pub fn check(result: &TestResult, data: &TestData, limits: &Limits) -> Report {
+    Report {
+        checks: vec![
+            internal_control(result),
+            consistency(result),
+            sample_count(data, limits),
+            sample_gaps(data, limits),
+            thermal_profile(data, limits),
+        ],
+    }
+}
+
+fn internal_control(result: &TestResult) -> Check {
+    const NAME: &str = "internal_control";
+    let internal_control = result
+        .subgroup_results
+        .iter()
+        .find(|subgroup| subgroup.name.eq_ignore_ascii_case(INTERNAL_CONTROL));
+    // A strong positive target often outcompetes the internal control, so its absence only means the run failed
+    // when nothing else amplified either.
+    let target_amplified = result.subgroup_results.iter().any(|subgroup| {
+        subgroup.result == DetectionResult::Positive
+            && !subgroup.name.eq_ignore_ascii_case(INTERNAL_CONTROL)
+    });
+    match internal_control.map(|subgroup| subgroup.result) {
+        Some(DetectionResult::Positive) => {
+            Check::new(NAME, Outcome::Pass, Reason::ControlAmplified)
+        }
+        Some(_) if target_amplified => Check::new(NAME, Outcome::Warn, Reason::ControlSuppressed),
+        Some(_) => Check::new(NAME, Outcome::Fail, Reason::ControlFailed),
+        None => Check::new(NAME, Outcome::Warn, Reason::ControlMissing),
+    }
+}
+
+fn consistency(result: &TestResult) -> Check {
+    const NAME: &str = "result_consistency";
+    let positive_channels = result
+        .channel_results
+        .iter()
+        .filter(|result| **result == DetectionResult::Positive)
+        .count();
+    let positive_subgroups = result
+        .subgroup_results
+        .iter()
+        .filter(|subgroup| subgroup.result == DetectionResult::Positive)
+        .count();
+    let positive_targets = result.subgroup_results.iter().any(|subgroup| {
+        subgroup.result == DetectionResult::Positive
+            && !subgroup.name.eq_ignore_ascii_case(INTERNAL_CONTROL)
+    });
+    if positive_subgroups > positive_channels {
+        return Check::new(NAME, Outcome::Fail, Reason::SubgroupsExceedChannels)
+            .with("subgroups", positive_subgroups)
+            .with("channels", positive_channels);
+    }
+    match (result.overall, positive_targets) {
+        (DetectionResult::Positive, false) => {
+            Check::new(NAME, Outcome::Fail, Reason::PositiveWithoutTarget)
+        }
+        (DetectionResult::Negative, true) => {
+            Check::new(NAME, Outcome::Fail, Reason::NegativeWithTarget)
+        }
+        _ => Check::new(NAME, Outcome::Pass, Reason::ResultsAgree),
+    }
+}
+
+fn sample_count(data: &TestData, limits: &Limits) -> Check {
+    const NAME: &str = "sample_count";
+    let samples = data.samples.len();
+    let Some(expected) = limits.expected_samples else {
+        return Check::new(NAME, Outcome::Pass, Reason::Samples).with("samples", samples);
+    };
+    let outcome = if samples < expected {
+        Outcome::Warn
+    } else {
+        Outcome::Pass
+    };
+    Check::new(NAME, outcome, Reason::SamplesOfExpected)
+        .with("samples", samples)
+        .with("expected", expected)
+}
+
+fn sample_gaps(data: &TestData, limits: &Limits) -> Check {
+    const NAME: &str = "sample_gaps";
+    let mut elapsed: Vec<Duration> = data.samples.iter().map(|sample| sample.elapsed).collect();
+    elapsed.sort();
+    let largest_gap = elapsed
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .max()
+        .unwrap_or_default();
+    let outcome = match limits.max_sample_gap_seconds {
+        Some(max) if largest_gap > Duration::from_secs(max) => Outcome::Warn,
+        _ => Outcome::Pass,
+    };
+    Check::new(NAME, outcome, Reason::LargestGap).with("seconds", largest_gap.as_secs())
+}
+
+fn thermal_profile(data: &TestData, limits: &Limits) -> Check {
+    const NAME: &str = "thermal_profile";
+    let temperatures = data
+        .samples
+        .iter()
+        .map(|sample| sample.temperature.0)
+        .chain(data.temperature_samples.iter().map(|sample| sample.temp.0));
+    let Some((min, max)) = temperatures.fold(None, |range: Option<(f64, f64)>, temp| {
+        Some(range.map_or((temp, temp), |(min, max)| (min.min(temp), max.max(temp))))
+    }) else {
+        return Check::new(NAME, Outcome::Warn, Reason::NoTemperatures);
+    };
+    let format = |temp: f64| format!("{:.1}", temp);
+    if let Some(limit) = limits.min_temperature.filter(|limit| min < limit.0) {
+        return Check::new(NAME, Outcome::Fail, Reason::TooCold)
+            .with("min", format(min))
+            .with("limit", format(limit.0));
+    }
+    if let Some(limit) = limits.max_temperature.filter(|limit| max > limit.0) {
+        return Check::new(NAME, Outcome::Fail, Reason::TooHot)
+            .with("max", format(max))
+            .with("limit", format(limit.0));
+    }
+    Check::new(NAME, Outcome::Pass, Reason::TemperatureRange)
+        .with("min", format(min))
+        .with("max", format(max))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Limits, Outcome, Reason, check};
+    use crate::messages::{DegreesC, DetectionResult, Message, ParseMode};
+
+    fn limits() -> Limits {
+        Limits {
+            expected_samples: Some(21),
+            max_sample_gap_seconds: Some(60),
+            min_temperature: Some(DegreesC(60.0)),
+            max_temperature: Some(DegreesC(68.0)),
+        }
+    }
+
+    fn outcomes(report: &super::Report) -> Vec<(&str, Outcome)> {
+        report
+            .checks
+            .iter()
+            .map(|check| (check.name, check.outcome))
+            .collect()
+    }
+
+    #[test]
+    fn checks_completed_runs() {
+        let raw = serde_json::from_str(include_str!(
+            "../tests/fixtures/synthetic/test-finished.json"
+        ))
+        .unwrap();
+        let (message, _) = Message::parse(&raw, ParseMode::Strict).unwrap();
+        let mut result = message.test.result.unwrap();
+        let mut data = message.test.data;
+        assert_eq!(Outcome::Pass, check(&result, &data, &limits()).outcome());
+        assert_eq!(
+            Outcome::Pass,
+            check(&result, &data, &Limits::default()).outcome()
+        );
+
+        result.subgroup_results[1].result = DetectionResult::Negative;
+        result.overall = DetectionResult::Positive;
+        data.samples.truncate(10);
+        data.samples[9].elapsed += std::time::Duration::from_secs(120);
+        data.temperature_samples[0].temp.0 = 55.0;
+        let report = check(&result, &data, &limits());
+        assert_eq!(
+            vec![
+                ("internal_control", Outcome::Fail),
+                ("result_consistency", Outcome::Fail),
+                ("sample_count", Outcome::Warn),
+                ("sample_gaps", Outcome::Warn),
+                ("thermal_profile", Outcome::Fail),
+            ],
+            outcomes(&report)
+        );
+        assert_eq!(Outcome::Fail, report.outcome());
+        // Without a panel's own limits, gaps and temperature are still held to the defaults.
+        assert_eq!(
+            vec![
+                ("sample_count", Outcome::Pass),
+                ("sample_gaps", Outcome::Warn),
+                ("thermal_profile", Outcome::Fail),
+            ],
+            outcomes(&check(&result, &data, &Limits::default()))[2..]
+        );
+        let failure = report.failures().last().unwrap();
+        assert_eq!(Reason::TooCold, failure.reason);
+        assert_eq!(
+            vec![("min", "55.0".to_owned()), ("limit", "60.0".to_owned())],
+            failure.params
+        );
+    }
+
+    #[test]
+    fn a_positive_target_without_the_internal_control_is_still_a_result() {
+        let raw = serde_json::from_str(include_str!(
+            "../tests/fixtures/synthetic/test-finished.json"
+        ))
+        .unwrap();
+        let (message, _) = Message::parse(&raw, ParseMode::Strict).unwrap();
+        let mut result = message.test.result.unwrap();
+        result.overall = DetectionResult::Positive;
+        result.channel_results[0] = DetectionResult::Positive;
+        result.channel_results[6] = DetectionResult::Negative;
+        result.subgroup_results[0].result = DetectionResult::Positive;
+        result.subgroup_results[1].result = DetectionResult::Negative;
+        let report = check(&result, &message.test.data, &limits());
+        assert_eq!(("internal_control", Outcome::Warn), outcomes(&report)[0]);
+        assert_eq!(Outcome::Warn, report.outcome());
+
+        result.subgroup_results[0].result = DetectionResult::Negative;
+        result.overall = DetectionResult::Negative;
+        let report = check(&result, &message.test.data, &limits());
+        assert_eq!(("internal_control", Outcome::Fail), outcomes(&report)[0]);
+    }
+}
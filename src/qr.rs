@@ -0,0 +1,36 @@
+use qrcode::{EcLevel, QrCode, render::svg};
+
+// QR codes for the printable batch sheet, so a phone can scan a webhook URL instead of someone typing it. Level M
+// tolerates a smudged or creased printout while keeping a full webhook URL to a size that scans from a 4cm square.
+
+/// A black-on-white SVG of the data, or None if it's too long to encode. Its size is in modules, so the page's CSS
+/// decides how big it's printed.
+pub fn to_svg(data: &[u8]) -> Option<String> {
+    let code = QrCode::with_error_correction_level(data, EcLevel::M).ok()?;
+    let svg = code.render::<svg::Color>().module_dimensions(1, 1).build();
+    // The renderer writes a standalone document, but this is embedded in HTML, where an XML declaration doesn't belong.
+    Some(svg[svg.find("<svg")?..].to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use qrcode::{EcLevel, QrCode, Version};
+
+    use super::to_svg;
+
+    #[test]
+    fn encodes_webhook_urls() {
+        let url = format!(
+            "https://results.wang/session/{}/data/{}",
+            uuid::Uuid::nil(),
+            "0".repeat(64)
+        );
+        let code = QrCode::with_error_correction_level(&url, EcLevel::M).unwrap();
+        assert_eq!(Version::Normal(6), code.version());
+        let svg = to_svg(url.as_bytes()).unwrap();
+        assert!(svg.starts_with("<svg"));
+        // Version 6 is 41 modules across, plus a quiet zone of 4 on each side.
+        assert!(svg.contains("viewBox=\"0 0 49 49\""));
+        assert!(to_svg(&[0; 3000]).is_none());
+    }
+}
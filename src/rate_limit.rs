@@ -0,0 +1,169 @@
+use std::{collections::HashMap, hash::Hash, str::FromStr, time::Duration};
+
+use tokio::time::Instant;
+
+// Once this many keys are being tracked, buckets which have refilled completely are dropped, as they're
+// indistinguishable from a fresh bucket.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// A token bucket holding up to `capacity` tokens, refilling completely over `period`.
+///
+/// Parsed from strings like "10/1h", meaning a burst of up to 10, refilling at 10 per hour.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TokenBucket {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl TokenBucket {
+    fn refill_per_second(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for TokenBucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (capacity, period) = s
+            .split_once('/')
+            .ok_or_else(|| format!("Expected a rate like '10/1h' but was '{}'", s))?;
+        let capacity = capacity
+            .trim()
+            .parse()
+            .map_err(|err| format!("Invalid capacity '{}': {}", capacity, err))?;
+        let period = duration_str::parse(period.trim())
+            .map_err(|err| format!("Invalid period '{}': {}", period, err))?;
+        if capacity == 0 || period.is_zero() {
+            return Err(format!("Rate '{}' must allow at least one request", s));
+        }
+        Ok(TokenBucket { capacity, period })
+    }
+}
+
+/// Why tokens couldn't be taken.
+#[derive(Debug, PartialEq)]
+pub enum Exceeded {
+    /// Enough tokens will have refilled after this long.
+    RetryAfter(Duration),
+    /// More tokens were asked for than the bucket ever holds, so waiting won't help.
+    Capacity(u32),
+}
+
+pub struct RateLimiter<K> {
+    bucket: TokenBucket,
+    buckets: HashMap<K, BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(bucket: TokenBucket) -> RateLimiter<K> {
+        RateLimiter {
+            bucket,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for `key` if one is available, otherwise returns how long until one will be.
+    pub fn check(&mut self, key: K) -> Result<(), Exceeded> {
+        self.check_n(key, 1)
+    }
+
+    /// Takes `n` tokens for `key` if that many are available, otherwise takes none and returns why not.
+    pub fn check_n(&mut self, key: K, n: usize) -> Result<(), Exceeded> {
+        let now = Instant::now();
+        if self.buckets.len() >= PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+        self.peek_at(&key, n, now)?;
+        let tokens = self.tokens_at(&key, now) - n as f64;
+        self.buckets.insert(
+            key,
+            BucketState {
+                tokens,
+                last_refill: now,
+            },
+        );
+        Ok(())
+    }
+
+    /// Whether `check_n` would take `n` tokens for `key`, without taking them. This lets a caller check several
+    /// limits and only charge any of them once all of them pass.
+    pub fn peek_n(&self, key: &K, n: usize) -> Result<(), Exceeded> {
+        self.peek_at(key, n, Instant::now())
+    }
+
+    fn peek_at(&self, key: &K, n: usize, now: Instant) -> Result<(), Exceeded> {
+        if n > self.bucket.capacity as usize {
+            return Err(Exceeded::Capacity(self.bucket.capacity));
+        }
+        let tokens = self.tokens_at(key, now);
+        let n = n as f64;
+        if tokens >= n {
+            Ok(())
+        } else {
+            Err(Exceeded::RetryAfter(Duration::from_secs_f64(
+                (n - tokens) / self.bucket.refill_per_second(),
+            )))
+        }
+    }
+
+    fn tokens_at(&self, key: &K, now: Instant) -> f64 {
+        let capacity = self.bucket.capacity as f64;
+        self.buckets.get(key).map_or(capacity, |state| {
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            f64::min(
+                capacity,
+                state.tokens + elapsed * self.bucket.refill_per_second(),
+            )
+        })
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let capacity = self.bucket.capacity as f64;
+        let refill_per_second = self.bucket.refill_per_second();
+        self.buckets.retain(|_, state| {
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            state.tokens + elapsed * refill_per_second < capacity
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Exceeded, RateLimiter, TokenBucket};
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_over_period() {
+        let mut limiter = RateLimiter::new("2/1m".parse::<TokenBucket>().unwrap());
+        assert_eq!(Ok(()), limiter.check("a"));
+        assert_eq!(Ok(()), limiter.check("a"));
+        assert_eq!(
+            Err(Exceeded::RetryAfter(Duration::from_secs(30))),
+            limiter.check("a")
+        );
+        assert_eq!(Ok(()), limiter.check("b"));
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(Ok(()), limiter.check("a"));
+        assert!(limiter.check("a").is_err());
+
+        // Either every token is taken or none are, and asking for more than the bucket holds can never succeed.
+        assert_eq!(Ok(()), limiter.check("c"));
+        assert_eq!(
+            Err(Exceeded::RetryAfter(Duration::from_secs(30))),
+            limiter.check_n("c", 2)
+        );
+        assert_eq!(Err(Exceeded::Capacity(2)), limiter.check_n("c", 3));
+        assert_eq!(Err(Exceeded::Capacity(2)), limiter.peek_n(&"d", 3));
+        assert_eq!(Ok(()), limiter.peek_n(&"c", 1));
+        assert_eq!(Ok(()), limiter.check("c"));
+        assert!(limiter.check("c").is_err());
+    }
+}
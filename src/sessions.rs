@@ -1,90 +1,957 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    path::PathBuf,
     str::FromStr,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use email_address::EmailAddress;
 use jiff::Timestamp;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{Instrument, Span, error, info, info_span, warn};
 use uuid::Uuid;
 
-use crate::{Error, state::State, websockets::SessionSockets};
+use crate::{
+    Error,
+    archive::Archive,
+    batches::{Batch, BatchOutcome, BatchTest, Batches},
+    config::Config,
+    devices::DeviceRegistry,
+    emails::{
+        BatchSummaryEmail, EmailTemplate, ErrorEmail, ExpiredEmail, HistoryLinkEmail, ResultEmail,
+        RetestEmail, SilentEmail, VerifyEmail,
+    },
+    expiry::ExpiryScheduler,
+    history::{History, HistoryRecord},
+    i18n::{Catalogs, DEFAULT_LANGUAGE, UserLocale},
+    mailgun::Mailgun,
+    messages::{DetectionResult, Event, Message, ParseMode},
+    metrics::Metrics,
+    notifier::Mailer,
+    panels::Panels,
+    persistence::{self, PersistedRecipient, PersistedSession},
+    rate_limit::RateLimiter,
+    state::{CompletedTest, State},
+    tokens::{random_token, tokens_match},
+    verification::VerifiedEmails,
+    websockets::SessionSockets,
+};
+
+pub const MAX_RECIPIENTS: usize = 10;
+pub const MAX_VIEWERS: usize = 20;
+// Each NewData message repeats every sample before it, so only the most recent messages are kept in memory.
+pub const MAX_RAW_MESSAGE_BYTES: usize = 4 * 1024 * 1024;
 
 #[derive(Clone)]
 pub struct ServerState {
     pub sessions: Arc<Mutex<Sessions>>,
     pub base_url: String,
     pub websocket_base_url: String,
-    pub sender_email: EmailAddress,
-    pub mailgun_domain: String,
-    pub mailgun_api_key: String,
+    pub mailer: Arc<Mailer>,
     pub cleanup_period: Duration,
+    pub completed_retention: Duration,
+    pub inactivity_warning: Duration,
+    pub require_email_verification: bool,
+    pub verification_memory: Duration,
+    pub verified_emails: Arc<Mutex<VerifiedEmails>>,
+    pub catalogs: Arc<Catalogs>,
+    pub panels: Arc<Panels>,
+    pub session_rate_limit_per_ip: Arc<Mutex<RateLimiter<IpAddr>>>,
+    pub session_rate_limit_per_email: Arc<Mutex<RateLimiter<EmailAddress>>>,
+    pub max_active_sessions_per_ip: usize,
+    pub max_active_sessions_per_email: usize,
+    pub max_data_body_bytes: usize,
+    pub trust_forwarded_for: bool,
+    pub parse_mode: ParseMode,
+    pub dump_mode: DumpMode,
+    pub admin_token: Option<String>,
+    pub metrics: Arc<Metrics>,
+    pub archive: Option<Arc<Archive>>,
+    /// Past results of addresses which opted in, if a history file is configured.
+    pub history: Option<Arc<Mutex<History>>>,
+    pub history_link_rate_limit: Arc<Mutex<RateLimiter<EmailAddress>>>,
+    pub devices: Arc<Mutex<DeviceRegistry>>,
+    pub batches: Arc<Mutex<Batches>>,
+    pub state_file: Option<PathBuf>,
+    /// Notifications which are being sent, so shutdown can wait for them.
+    pub tasks: TaskTracker,
+    /// Cancelled when the server starts shutting down.
+    pub shutdown: CancellationToken,
+    pub expiry: ExpiryScheduler,
+    /// Removes finished batches once their retention has passed.
+    pub batch_expiry: ExpiryScheduler,
+    /// Warns recipients when a session has gone quiet, long before it expires.
+    pub watchdog: ExpiryScheduler,
 }
 
 impl ServerState {
-    pub fn try_from_env() -> Result<ServerState, Error> {
-        let base_url = Self::env_var("BASE_URL")?;
-        let websocket_base_url = if let Some(suffix) = base_url.strip_prefix("http") {
-            "ws".to_string() + suffix
-        } else {
-            return Err(Error::InvalidEnvVar {
-                name: "BASE_URL".to_owned(),
-                cause: format!(
-                    "Expected BASE_URL to start with http but was '{}'",
-                    base_url
-                )
-                .into(),
-            });
+    pub fn from_config(config: &Config) -> Result<ServerState, Error> {
+        let panels = match &config.panels_file {
+            Some(path) => Panels::from_file(path)?,
+            None => Panels::builtin()?,
         };
-        let sender_email = Self::env_var("SENDER_EMAIL")?;
-        let sender_email =
-            EmailAddress::from_str(&sender_email).map_err(|err| Error::InvalidEnvVar {
-                name: "SENDER_EMAIL".to_owned(),
-                cause: Box::new(err),
-            })?;
-        let mailgun_domain = Self::env_var("MAILGUN_DOMAIN")?;
-        let mailgun_api_key = Self::env_var("MAILGUN_API_KEY")?;
-        let cleanup_period = Self::env_var("CLEANUP_PERIOD")?;
-        let cleanup_period =
-            duration_str::parse(&cleanup_period).map_err(|err| Error::InvalidEnvVar {
-                name: "CLEANUP_PERIOD".to_owned(),
-                cause: format!("Failed to parse duration {}: {}", cleanup_period, err).into(),
-            })?;
+        let catalogs = Arc::new(Catalogs::load()?);
         Ok(ServerState {
             sessions: Arc::new(Mutex::new(Sessions::default())),
-            base_url,
-            websocket_base_url,
-            sender_email,
-            mailgun_domain,
-            mailgun_api_key,
-            cleanup_period,
+            base_url: config.base_url.clone(),
+            websocket_base_url: config.websocket_base_url.clone(),
+            mailer: Arc::new(Mailer {
+                sender_email: config.sender_email.clone(),
+                transport: Arc::new(Mailgun {
+                    domain: config.mailgun_domain.clone(),
+                    region: config.mailgun_region,
+                    api_key: config.mailgun_api_key.clone(),
+                }),
+                templates: config.email_templates.clone(),
+                catalogs: catalogs.clone(),
+            }),
+            cleanup_period: config.cleanup_period,
+            completed_retention: config.completed_retention,
+            inactivity_warning: config.inactivity_warning,
+            require_email_verification: config.require_email_verification,
+            verification_memory: config.verification_memory,
+            verified_emails: Arc::new(Mutex::new(VerifiedEmails::default())),
+            catalogs,
+            panels: Arc::new(panels),
+            session_rate_limit_per_ip: Arc::new(Mutex::new(RateLimiter::new(
+                config.session_rate_limit_per_ip,
+            ))),
+            session_rate_limit_per_email: Arc::new(Mutex::new(RateLimiter::new(
+                config.session_rate_limit_per_email,
+            ))),
+            max_active_sessions_per_ip: config.max_active_sessions_per_ip,
+            max_active_sessions_per_email: config.max_active_sessions_per_email,
+            max_data_body_bytes: config.max_data_body_bytes,
+            trust_forwarded_for: config.trust_forwarded_for,
+            parse_mode: config.parse_mode,
+            dump_mode: config.dump_mode,
+            admin_token: config.admin_token.clone(),
+            metrics: Arc::new(Metrics::new()),
+            archive: match &config.archive_dir {
+                Some(dir) => Some(Arc::new(Archive::new(dir, config.archive_retention)?)),
+                None => None,
+            },
+            history: match &config.history_file {
+                Some(path) => Some(Arc::new(Mutex::new(History::load(
+                    path,
+                    config.history_retention,
+                )?))),
+                None => None,
+            },
+            history_link_rate_limit: Arc::new(Mutex::new(RateLimiter::new(
+                config.history_link_rate_limit,
+            ))),
+            devices: Arc::new(Mutex::new(DeviceRegistry::load(
+                config.device_registry_file.as_deref(),
+                config.known_good_firmware.clone(),
+            )?)),
+            batches: Arc::new(Mutex::new(Batches::default())),
+            state_file: config.state_file.clone(),
+            tasks: TaskTracker::new(),
+            shutdown: CancellationToken::new(),
+            expiry: ExpiryScheduler::new(),
+            batch_expiry: ExpiryScheduler::new(),
+            watchdog: ExpiryScheduler::new(),
         })
     }
 
-    fn env_var(name: &str) -> Result<String, Error> {
-        std::env::var(name).map_err(|err| Error::InvalidEnvVar {
-            name: name.to_owned(),
-            cause: Box::new(err),
-        })
+    /// Checks everything from_config would load, without creating or changing any files, so the configuration of a
+    /// running server can be checked safely.
+    pub fn check_config(config: &Config) -> Result<(), Vec<Error>> {
+        let mut errors = Vec::new();
+        let panels = match &config.panels_file {
+            Some(path) => Panels::from_file(path),
+            None => Panels::builtin(),
+        };
+        if let Err(err) = panels {
+            errors.push(err);
+        }
+        if let Err(err) = Catalogs::load() {
+            errors.push(err);
+        }
+        if let Some(dir) = &config.archive_dir
+            && dir.exists()
+            && !dir.is_dir()
+        {
+            errors.push(Error::InvalidConfig {
+                name: "archive_dir".to_owned(),
+                cause: format!("{} is not a directory", dir.display()).into(),
+            });
+        }
+        if let Some(path) = &config.history_file
+            && let Err(err) = History::check(path)
+        {
+            errors.push(err);
+        }
+        // Unlike the history, the device registry isn't written to when it's loaded.
+        if let Err(err) = DeviceRegistry::load(config.device_registry_file.as_deref(), None) {
+            errors.push(err);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Refuses `sessions` more sessions from the creator, or one more for each address (counting repeats), if that would
+    /// take either over its cap on active sessions. Callers hold the sessions lock through to the insert, so concurrent
+    /// requests can't all pass the check and exceed the caps between them.
+    fn check_active_sessions(
+        &self,
+        sessions: &Sessions,
+        creator_ip: Option<IpAddr>,
+        new_sessions: usize,
+        addresses: &[EmailAddress],
+    ) -> Result<(), Error> {
+        if let Some(creator_ip) = creator_ip
+            && sessions.count_active(|session| session.creator_ip == Some(creator_ip))
+                + new_sessions
+                > self.max_active_sessions_per_ip
+        {
+            return Err(Error::TooManyActiveSessions);
+        }
+        for email in addresses {
+            let new_sessions = addresses.iter().filter(|other| *other == email).count();
+            if sessions.count_active(|session| session.has_recipient(email)) + new_sessions
+                > self.max_active_sessions_per_email
+            {
+                return Err(Error::TooManyActiveSessions);
+            }
+        }
+        Ok(())
     }
 
-    pub fn create_session(&self, email_to_notify: EmailAddress) -> Uuid {
-        let id = {
+    /// Charges the creator's IP for `sessions` new sessions and each address for one, but only once every one of those
+    /// limits has been checked, so a request refused by any of them doesn't use up the others.
+    fn charge_session_rate_limits(
+        &self,
+        creator_ip: Option<IpAddr>,
+        sessions: usize,
+        addresses: &[EmailAddress],
+    ) -> Result<(), Error> {
+        let mut addresses: Vec<&EmailAddress> = addresses.iter().collect();
+        addresses.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        addresses.dedup();
+        let mut per_ip = self.session_rate_limit_per_ip.lock().unwrap();
+        let mut per_email = self.session_rate_limit_per_email.lock().unwrap();
+        if let Some(creator_ip) = &creator_ip {
+            per_ip.peek_n(creator_ip, sessions)?;
+        }
+        for email in &addresses {
+            per_email.peek_n(email, 1)?;
+        }
+        if let Some(creator_ip) = creator_ip {
+            per_ip.check_n(creator_ip, sessions)?;
+        }
+        for email in addresses {
+            per_email.check(email.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Creates a session if the creator and recipients are under their caps on active sessions and their rate limits,
+    /// consuming rate limit tokens if so.
+    pub fn create_session(
+        &self,
+        recipients: Vec<EmailAddress>,
+        locale: UserLocale,
+        creator_ip: Option<IpAddr>,
+        archive: bool,
+        history: bool,
+    ) -> Result<NewSession, Error> {
+        if self.shutdown.is_cancelled() {
+            return Err(Error::ShuttingDown);
+        }
+        let addresses = recipients.clone();
+        let recipients: Vec<Recipient> = recipients
+            .into_iter()
+            .map(|email| self.new_recipient(email))
+            .collect();
+        let (new_session, span) = {
             let mut sessions = self.sessions.lock().unwrap();
-            sessions.create(email_to_notify)
+            self.check_active_sessions(&sessions, creator_ip, 1, &addresses)?;
+            self.charge_session_rate_limits(creator_ip, 1, &addresses)?;
+            self.insert_session(
+                &mut sessions,
+                recipients.clone(),
+                &locale,
+                creator_ip,
+                archive,
+                history,
+            )
+        };
+        self.start_session(&new_session, &span, &recipients, &locale);
+        Ok(new_session)
+    }
+
+    fn insert_session(
+        &self,
+        sessions: &mut Sessions,
+        recipients: Vec<Recipient>,
+        locale: &UserLocale,
+        creator_ip: Option<IpAddr>,
+        archive: bool,
+        history: bool,
+    ) -> (NewSession, Span) {
+        let new_session = sessions.create(
+            recipients,
+            locale.clone(),
+            creator_ip,
+            archive && self.archive.is_some(),
+            history && self.history.is_some(),
+        );
+        let span = sessions.get(&new_session.id).unwrap().span.clone();
+        (new_session, span)
+    }
+
+    /// Everything which follows creating a session but needn't hold the sessions lock.
+    fn start_session(
+        &self,
+        new_session: &NewSession,
+        span: &Span,
+        recipients: &[Recipient],
+        locale: &UserLocale,
+    ) {
+        let id = new_session.id;
+        self.metrics.session_created();
+        let _entered = span.enter();
+        for recipient in recipients {
+            self.send_verification(id, recipient, locale);
+        }
+        self.expiry.schedule(id, self.cleanup_period);
+    }
+
+    /// Creates a session for each (label, recipient) pair. Each test counts against the creator's active session cap
+    /// and per-IP rate limit just as a separately created session would, and nothing is created unless they all fit.
+    /// Each address (including the coordinator's, who is emailed the summary) is rate limited once per batch.
+    pub fn create_batch(
+        &self,
+        coordinator: EmailAddress,
+        tests: Vec<(String, EmailAddress)>,
+        locale: UserLocale,
+        creator_ip: Option<IpAddr>,
+    ) -> Result<Batch, Error> {
+        if self.shutdown.is_cancelled() {
+            return Err(Error::ShuttingDown);
+        }
+        let mut addresses: Vec<EmailAddress> =
+            tests.iter().map(|(_, email)| email.clone()).collect();
+        let recipients: Vec<Recipient> = addresses
+            .iter()
+            .map(|email| self.new_recipient(email.clone()))
+            .collect();
+        let created: Vec<(NewSession, Span)> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            self.check_active_sessions(&sessions, creator_ip, tests.len(), &addresses)?;
+            addresses.push(coordinator.clone());
+            self.charge_session_rate_limits(creator_ip, tests.len(), &addresses)?;
+            recipients
+                .iter()
+                .map(|recipient| {
+                    self.insert_session(
+                        &mut sessions,
+                        vec![recipient.clone()],
+                        &locale,
+                        creator_ip,
+                        false,
+                        false,
+                    )
+                })
+                .collect()
+        };
+        let tests = tests
+            .into_iter()
+            .zip(recipients)
+            .zip(created)
+            .map(|(((label, email), recipient), (new_session, span))| {
+                self.start_session(
+                    &new_session,
+                    &span,
+                    std::slice::from_ref(&recipient),
+                    &locale,
+                );
+                BatchTest {
+                    label,
+                    recipient: email,
+                    session_id: new_session.id,
+                    ingest_token: new_session.ingest_token,
+                    viewer_token: new_session.viewer_token,
+                    outcome: None,
+                }
+            })
+            .collect();
+        let batch = Batch::new(coordinator, locale, tests);
+        info!(id = %batch.id, tests = batch.tests.len(), "Created batch");
+        self.batches.lock().unwrap().insert(batch.clone());
+        Ok(batch)
+    }
+
+    /// Records how a session ended, if it belongs to a batch. Once every test in the batch has ended, the coordinator
+    /// is sent a summary and the batch is removed after the same retention as a completed session.
+    pub fn finish_batch_test(&self, session_id: &Uuid, outcome: BatchOutcome) {
+        let Some(batch) = self
+            .batches
+            .lock()
+            .unwrap()
+            .finish(session_id, outcome)
+            .cloned()
+        else {
+            return;
+        };
+        info!(id = %batch.id, "Batch finished");
+        self.batch_expiry
+            .schedule(batch.id, self.completed_retention);
+        self.notify_batch_summary(batch);
+    }
+
+    /// Starts removing sessions and finished batches once their deadline passes, and warning about sessions which have
+    /// gone quiet.
+    pub fn spawn_expiry(&self) {
+        let server_state = self.clone();
+        self.expiry.run(move |id| server_state.expire_due(id));
+        let batches = self.batches.clone();
+        self.batch_expiry.run(move |id| {
+            if let Some(batch) = batches.lock().unwrap().remove(&id) {
+                info!("Removed finished batch {}", batch.id);
+            }
+        });
+        let server_state = self.clone();
+        self.watchdog.run(move |id| server_state.warn_silent(id));
+    }
+
+    /// Pushes back the deadline of a session which is still receiving data, so a long test isn't cut off mid-run.
+    /// The watchdog is only armed once samples arrive: a session nobody has started a test on isn't silent, it's idle.
+    pub fn extend_session(&self, session: &Session) {
+        self.expiry.schedule(session.id, self.cleanup_period);
+        if session.samples() > 0 {
+            self.watchdog.schedule(session.id, self.inactivity_warning);
+        }
+    }
+
+    /// Only warns once per silence: the watchdog isn't rescheduled until more data arrives.
+    fn warn_silent(&self, id: Uuid) {
+        let sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get(&id) else {
+            return;
+        };
+        if !matches!(session.state, State::IncompleteTest(_)) {
+            return;
+        }
+        let _entered = session.span.enter();
+        warn!(silence = ?self.inactivity_warning, "No data received for session {}", id);
+        self.metrics.session_silent();
+        self.notify_silent(id, session.recipient_emails(), &session.locale);
+    }
+
+    fn expire_due(&self, id: Uuid) {
+        let Some(removed) = self.sessions.lock().unwrap().remove(&id) else {
+            return;
+        };
+        self.watchdog.cancel(id);
+        let _entered = removed.span.enter();
+        match removed.state {
+            State::CompletedTest(_) => info!("Removed completed session {}", removed.id),
+            State::IncompleteTest(_) => {
+                info!("Expired session {} without a result", removed.id);
+                self.metrics.session_expired();
+                self.finish_batch_test(&id, BatchOutcome::Expired);
+                self.notify_expired(
+                    id,
+                    removed.created,
+                    removed.recipient_emails(),
+                    &removed.locale,
+                );
+            }
+        }
+    }
+
+    fn new_recipient(&self, email: EmailAddress) -> Recipient {
+        if !self.require_email_verification
+            || self.verified_emails.lock().unwrap().is_verified(&email)
+        {
+            Recipient::verified(email)
+        } else {
+            Recipient::unverified(email)
+        }
+    }
+
+    fn send_verification(&self, id: Uuid, recipient: &Recipient, locale: &UserLocale) {
+        let Some(token) = &recipient.verification_token else {
+            return;
+        };
+        let server_state = self.clone();
+        let link = format!("{}/session/{}/verify/{}", self.base_url, id, token);
+        let email = recipient.email.clone();
+        let locale = locale.clone();
+        self.spawn_notification(VerifyEmail::NAME, &recipient.email, async move {
+            server_state
+                .mailer
+                .notify_verification(&locale, link, email)
+                .await
+        });
+    }
+
+    /// Adds a recipient to a session, doing nothing if they're already on it. The sessions lock is held from the
+    /// checks through to the insert, so concurrent requests can't push a session over MAX_RECIPIENTS between them.
+    pub fn add_recipient(&self, id: &Uuid, email: EmailAddress) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(id).ok_or(Error::UnknownSession)?;
+        if session.has_recipient(&email) {
+            return Ok(());
+        }
+        if session.recipients.len() >= MAX_RECIPIENTS {
+            return Err(Error::TooManyRecipients(MAX_RECIPIENTS));
+        }
+        self.session_rate_limit_per_email
+            .lock()
+            .unwrap()
+            .check(email.clone())?;
+        let recipient = self.new_recipient(email);
+        session.recipients.push(recipient.clone());
+        let _entered = session.span.enter();
+        self.send_verification(*id, &recipient, &session.locale);
+        // Someone added after the result came in still wants to hear about it.
+        if recipient.verified
+            && let State::CompletedTest(completed_test) = &session.state
+        {
+            self.notify_result(*id, completed_test, vec![recipient.email], &session.locale);
+        }
+        Ok(())
+    }
+
+    /// Marks the recipient holding this verification token as verified, returning their address.
+    pub fn verify_recipient(&self, id: &Uuid, token: &str) -> Result<EmailAddress, Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(id).ok_or(Error::UnknownSession)?;
+        let recipient = session
+            .recipients
+            .iter_mut()
+            .find(|recipient| {
+                recipient
+                    .verification_token
+                    .as_deref()
+                    .is_some_and(|expected| tokens_match(token, expected))
+            })
+            .ok_or(Error::UnknownRecipient)?;
+        recipient.verified = true;
+        recipient.verification_token = None;
+        let email = recipient.email.clone();
+        let _entered = session.span.enter();
+        self.verified_emails
+            .lock()
+            .unwrap()
+            .remember(email.clone(), self.verification_memory);
+        if let State::CompletedTest(completed_test) = &session.state {
+            self.notify_result(*id, completed_test, vec![email.clone()], &session.locale);
+        }
+        Ok(email)
+    }
+
+    /// What to add to the history of each verified recipient, if the session opted in. It's saved separately by
+    /// record_history, so the file isn't written while the sessions lock is held.
+    pub fn history_record(
+        &self,
+        session: &Session,
+        completed_test: &CompletedTest,
+    ) -> Option<(Vec<EmailAddress>, HistoryRecord)> {
+        self.history.as_ref().filter(|_| session.history)?;
+        let record = HistoryRecord {
+            session_id: session.id,
+            completed: completed_test.completed,
+            serial_number: completed_test.serial_number,
+            panel: completed_test
+                .panel
+                .as_ref()
+                .map(|panel| panel.name.get(DEFAULT_LANGUAGE).to_owned()),
+            overall: completed_test.overall,
+            subgroup_results: completed_test.subgroup_results.clone(),
+            time_zone: session.locale.time_zone.iana_name().map(str::to_owned),
+        };
+        Some((session.recipient_emails(), record))
+    }
+
+    pub fn record_history(&self, emails: &[EmailAddress], record: HistoryRecord) {
+        let Some(history) = &self.history else {
+            return;
         };
-        let sessions = self.sessions.clone();
-        let cleanup_period = self.cleanup_period;
+        let id = record.session_id;
+        if let Err(err) = history.lock().unwrap().record(emails, record) {
+            self.metrics.error(&err);
+            error!(%id, ?err, "Failed to record history");
+        }
+    }
+
+    /// Forgets everything recorded for the address, returning how many records there were. This rewrites the history
+    /// file, so call it from a blocking task.
+    pub fn delete_history(&self, email: &EmailAddress) -> Result<usize, Error> {
+        match &self.history {
+            Some(history) => history.lock().unwrap().delete(email),
+            None => Ok(0),
+        }
+    }
+
+    /// Emails a link to the address's history, if it has one. Nothing is sent otherwise, but callers shouldn't say
+    /// so, or the response would reveal whose tests are recorded.
+    pub fn send_history_link(&self, email: EmailAddress, locale: UserLocale) -> Result<(), Error> {
+        let Some(history) = &self.history else {
+            return Ok(());
+        };
+        self.history_link_rate_limit
+            .lock()
+            .unwrap()
+            .check(email.clone())?;
+        let Some(token) = history.lock().unwrap().create_link(&email) else {
+            info!(%email, "No history to link to");
+            return Ok(());
+        };
+        let server_state = self.clone();
+        let link = format!(
+            "{}/history/{}?lang={}",
+            self.base_url, token, locale.language
+        );
+        self.spawn_notification(HistoryLinkEmail::NAME, &email.clone(), async move {
+            server_state
+                .mailer
+                .notify_history_link(&locale, link, email)
+                .await
+        });
+        Ok(())
+    }
+
+    /// Periodically deletes history records which are older than the retention period.
+    pub fn spawn_history_pruning(&self) {
+        let Some(history) = self.history.clone() else {
+            return;
+        };
+        let metrics = self.metrics.clone();
         tokio::spawn(async move {
-            tokio::time::sleep(cleanup_period).await;
-            let mut sessions = sessions.lock().unwrap();
-            if let Some(removed) = sessions.remove(&id) {
-                info!("Expired session {}", removed.id);
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                let history = history.clone();
+                let pruned = tokio::task::spawn_blocking(move || {
+                    history.lock().unwrap().prune(Timestamp::now())
+                })
+                .await;
+                match pruned {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => {
+                        metrics.error(&err);
+                        error!(?err, "Error pruning history");
+                    }
+                    Err(err) => error!(?err, "History pruning panicked"),
+                }
+            }
+        });
+    }
+
+    /// Periodically deletes archived messages which are older than the retention period.
+    pub fn spawn_archive_pruning(&self) {
+        let Some(archive) = self.archive.clone() else {
+            return;
+        };
+        let metrics = self.metrics.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                let archive = archive.clone();
+                match tokio::task::spawn_blocking(move || archive.prune(Timestamp::now())).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => {
+                        let err = Error::from(err);
+                        metrics.error(&err);
+                        error!(?err, "Error pruning archived messages");
+                    }
+                    Err(err) => error!(?err, "Archive pruning panicked"),
+                }
+            }
+        });
+    }
+
+    /// Stops new sessions being created and disconnects every live graph viewer.
+    pub async fn begin_shutdown(&self) {
+        self.shutdown.cancel();
+        let websockets: Vec<SessionSockets> = {
+            let sessions = self.sessions.lock().unwrap();
+            sessions
+                .states
+                .values()
+                .map(|session| session.websockets.clone())
+                .collect()
+        };
+        for websockets in websockets {
+            websockets.close_all().await;
+        }
+    }
+
+    /// Waits up to `timeout` for notifications which are still being sent, then saves any sessions still waiting for
+    /// a result if a state file is configured, and the device registry.
+    pub async fn finish_shutdown(&self, timeout: Duration) {
+        self.tasks.close();
+        if tokio::time::timeout(timeout, self.tasks.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                remaining = self.tasks.len(),
+                "Gave up waiting for notifications to be sent"
+            );
+        }
+        match self.persist_sessions() {
+            Ok(Some(count)) => info!(count, "Saved sessions"),
+            Ok(None) => {}
+            Err(err) => {
+                self.metrics.error(&err);
+                error!(?err, "Error saving sessions");
+            }
+        }
+        if let Err(err) = self.devices.lock().unwrap().save() {
+            self.metrics.error(&err);
+            error!(?err, "Error saving device registry");
+        }
+    }
+
+    /// Saves sessions which are still waiting for a result, returning how many were saved, or None if there's no
+    /// state file to save them to.
+    pub fn persist_sessions(&self) -> Result<Option<usize>, Error> {
+        let Some(state_file) = &self.state_file else {
+            return Ok(None);
+        };
+        let persisted: Vec<PersistedSession> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .states
+            .values()
+            .filter(|session| matches!(session.state, State::IncompleteTest(_)))
+            .map(|session| session.to_persisted(self.expiry.deadline(&session.id)))
+            .collect();
+        persistence::save(state_file, &persisted)?;
+        Ok(Some(persisted.len()))
+    }
+
+    /// Restores sessions saved by a previous shutdown with the deadlines they had, dropping any which have expired in
+    /// the meantime.
+    pub fn restore_sessions(&self) -> Result<usize, Error> {
+        let Some(state_file) = &self.state_file else {
+            return Ok(0);
+        };
+        let now = Timestamp::now();
+        let mut restored = 0;
+        for persisted in persistence::take(state_file)? {
+            let Some(remaining) = persisted
+                .expires
+                .or_else(|| persisted.created.checked_add(self.cleanup_period).ok())
+                .and_then(|expires| Duration::try_from(now.duration_until(expires)).ok())
+            else {
+                continue;
+            };
+            let session = Session::restore(persisted, &self.metrics, &self.panels);
+            let id = session.id;
+            session
+                .span
+                .in_scope(|| info!(samples = session.samples(), "Restored session"));
+            let receiving =
+                matches!(session.state, State::IncompleteTest(_)) && session.samples() > 0;
+            self.sessions.lock().unwrap().insert(id, session);
+            self.expiry.schedule(id, remaining);
+            if receiving {
+                self.watchdog.schedule(id, self.inactivity_warning);
             }
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// Removes a session before its time is up, e.g. because an operator asked to.
+    pub fn expire_session(&self, id: &Uuid) -> Result<(), Error> {
+        let removed = self
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or(Error::UnknownSession)?;
+        self.expiry.cancel(*id);
+        self.watchdog.cancel(*id);
+        removed
+            .span
+            .in_scope(|| info!("Force-expired session {}", removed.id));
+        if matches!(removed.state, State::IncompleteTest(_)) {
+            self.metrics.session_expired();
+            self.finish_batch_test(id, BatchOutcome::Expired);
+        }
+        Ok(())
+    }
+
+    /// Sends the result to every verified recipient again.
+    pub fn resend_result(&self, id: &Uuid) -> Result<(), Error> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id).ok_or(Error::UnknownSession)?;
+        let State::CompletedTest(completed_test) = &session.state else {
+            return Err(Error::TestNotFinished);
+        };
+        let _entered = session.span.enter();
+        info!(%id, "Resending result");
+        self.notify_result(
+            *id,
+            completed_test,
+            session.recipient_emails(),
+            &session.locale,
+        );
+        Ok(())
+    }
+
+    pub fn remove_completed_after_retention(&self, id: Uuid) {
+        self.watchdog.cancel(id);
+        self.expiry.schedule(id, self.completed_retention);
+    }
+
+    /// Emails the result to each recipient separately, so one bad address doesn't stop the others being notified,
+    /// and records each delivery's outcome on the session.
+    pub fn notify_result(
+        &self,
+        id: Uuid,
+        completed_test: &CompletedTest,
+        recipients: Vec<EmailAddress>,
+        locale: &UserLocale,
+    ) {
+        for recipient in recipients {
+            let server_state = self.clone();
+            let completed_test = completed_test.clone();
+            let locale = locale.clone();
+            let kind = if completed_test.needs_retest() {
+                RetestEmail::NAME
+            } else {
+                ResultEmail::NAME
+            };
+            self.spawn_notification(kind, &recipient.clone(), async move {
+                let result = server_state
+                    .mailer
+                    .notify(&locale, completed_test, recipient.clone())
+                    .await;
+                let delivery = match &result {
+                    Ok(response) => {
+                        info!(%id, %recipient, message_id = response.id, "Notified of result");
+                        DeliveryStatus::Sent {
+                            at: Timestamp::now(),
+                            message_id: response.id.clone(),
+                        }
+                    }
+                    Err(err) => {
+                        server_state.notify_error(
+                            id,
+                            format!("Error notifying of result: {:?}", err),
+                            vec![recipient.clone()],
+                            &locale,
+                        );
+                        DeliveryStatus::Failed {
+                            at: Timestamp::now(),
+                            error: format!("{:?}", err),
+                        }
+                    }
+                };
+                server_state.set_delivery_status(&id, &recipient, delivery);
+                result
+            });
+        }
+    }
+
+    pub fn notify_error(
+        &self,
+        id: Uuid,
+        error: String,
+        recipients: Vec<EmailAddress>,
+        locale: &UserLocale,
+    ) {
+        for recipient in recipients {
+            let server_state = self.clone();
+            let error = error.clone();
+            let locale = locale.clone();
+            self.spawn_notification(ErrorEmail::NAME, &recipient.clone(), async move {
+                server_state
+                    .mailer
+                    .notify_error(&locale, &id, &error, recipient)
+                    .await
+            });
+        }
+    }
+
+    fn notify_silent(&self, id: Uuid, recipients: Vec<EmailAddress>, locale: &UserLocale) {
+        for recipient in recipients {
+            let server_state = self.clone();
+            let locale = locale.clone();
+            self.spawn_notification(SilentEmail::NAME, &recipient.clone(), async move {
+                server_state
+                    .mailer
+                    .notify_silent(&locale, &id, server_state.inactivity_warning, recipient)
+                    .await
+            });
+        }
+    }
+
+    fn notify_expired(
+        &self,
+        id: Uuid,
+        created: Timestamp,
+        recipients: Vec<EmailAddress>,
+        locale: &UserLocale,
+    ) {
+        for recipient in recipients {
+            let server_state = self.clone();
+            let locale = locale.clone();
+            self.spawn_notification(ExpiredEmail::NAME, &recipient.clone(), async move {
+                server_state
+                    .mailer
+                    .notify_expired(&locale, &id, created, recipient)
+                    .await
+            });
+        }
+    }
+
+    fn notify_batch_summary(&self, batch: Batch) {
+        let server_state = self.clone();
+        let coordinator = batch.coordinator.clone();
+        self.spawn_notification(BatchSummaryEmail::NAME, &coordinator, async move {
+            server_state.mailer.notify_batch_summary(&batch).await
         });
-        id
+    }
+
+    /// Sends a notification in the background, recording how long it took and whether it failed. Shutdown waits for
+    /// it to finish.
+    fn spawn_notification<T>(
+        &self,
+        kind: &'static str,
+        recipient: &EmailAddress,
+        notification: impl Future<Output = Result<T, Error>> + Send + 'static,
+    ) {
+        let metrics = self.metrics.clone();
+        self.tasks.spawn(
+            async move {
+                let started = Instant::now();
+                let result = notification.await;
+                metrics.notification_sent("email", kind, started, &result);
+                if let Err(err) = result {
+                    metrics.error(&err);
+                    error!(?err, "Error sending notification");
+                }
+            }
+            .instrument(info_span!("notify", kind, %recipient)),
+        );
+    }
+
+    fn set_delivery_status(&self, id: &Uuid, email: &EmailAddress, delivery: DeliveryStatus) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(recipient) = sessions
+            .get_mut(id)
+            .and_then(|session| session.recipient_mut(email))
+        {
+            recipient.delivery = delivery;
+        }
     }
 }
 
@@ -95,24 +962,53 @@ pub struct Sessions {
 
 #[allow(clippy::len_without_is_empty)]
 impl Sessions {
-    fn create(&mut self, email_to_notify: EmailAddress) -> Uuid {
+    fn create(
+        &mut self,
+        recipients: Vec<Recipient>,
+        locale: UserLocale,
+        creator_ip: Option<IpAddr>,
+        archive: bool,
+        history: bool,
+    ) -> NewSession {
         let id = Uuid::new_v4();
+        let ingest_token = random_token();
         let timestamp = Timestamp::now();
         let session = Session {
             state: State::started(),
             created: timestamp,
-            email_to_notify,
+            recipients,
+            locale,
+            creator_ip,
             id,
+            ingest_token: ingest_token.clone(),
+            span: info_span!(parent: None, "session", session_id = %id),
+            viewers: vec![Viewer::new(None, None)],
             websockets: SessionSockets::new(),
+            last_event: None,
+            device_serial_number: None,
+            rejected_device: None,
+            archive,
+            history,
+            raw_messages: VecDeque::new(),
+            raw_message_bytes: 0,
         };
+        let viewer_token = session.viewers[0].token.clone();
         self.insert(id, session);
-        id
+        NewSession {
+            id,
+            ingest_token,
+            viewer_token,
+        }
     }
 
     pub fn get(&self, id: &Uuid) -> Option<&Session> {
         self.states.get(id)
     }
 
+    pub fn get_mut(&mut self, id: &Uuid) -> Option<&mut Session> {
+        self.states.get_mut(id)
+    }
+
     pub fn remove(&mut self, id: &Uuid) -> Option<Session> {
         self.states.remove(id)
     }
@@ -124,12 +1020,521 @@ impl Sessions {
     pub fn len(&self) -> usize {
         self.states.len()
     }
+
+    pub fn ids(&self) -> Vec<Uuid> {
+        self.states.keys().copied().collect()
+    }
+
+    pub fn websocket_count(&self) -> usize {
+        self.states
+            .values()
+            .map(|session| session.websockets.count())
+            .sum()
+    }
+
+    pub fn summaries(&self) -> Vec<SessionSummary> {
+        let now = Timestamp::now();
+        let mut summaries: Vec<SessionSummary> = self
+            .states
+            .values()
+            .map(|session| session.summary(now))
+            .collect();
+        summaries.sort_by_key(|summary| summary.created);
+        summaries
+    }
+
+    fn count_active(&self, matches: impl Fn(&Session) -> bool) -> usize {
+        self.states
+            .values()
+            .filter(|session| matches!(session.state, State::IncompleteTest(_)) && matches(session))
+            .count()
+    }
 }
 
 pub struct Session {
     pub state: State,
     pub created: Timestamp,
-    pub email_to_notify: EmailAddress,
+    pub recipients: Vec<Recipient>,
+    pub locale: UserLocale,
+    pub creator_ip: Option<IpAddr>,
     pub id: Uuid,
+    /// The secret which must be presented to post data to, or manage, the session.
+    /// Unlike the id, which is shared with anyone viewing the live graph, it is only shown to the session's creator.
+    pub ingest_token: String,
+    /// Covers the whole life of the session, so everything done for it (ingesting data, rendering graphs,
+    /// sending notifications) is grouped together in logs and traces.
+    pub span: Span,
+    /// Read-only links to the live graph. The first is created with the session for its creator; more can be minted
+    /// to share with others, and each can be revoked without affecting the rest.
+    pub viewers: Vec<Viewer>,
     pub websockets: SessionSockets,
+    pub last_event: Option<LastEvent>,
+    /// The device which sent the session's first message. Data from any other device is rejected, as interleaving
+    /// two devices' samples would corrupt the test.
+    pub device_serial_number: Option<u64>,
+    /// The most recent message rejected for coming from a device other than the one the session is bound to.
+    pub rejected_device: Option<RejectedDevice>,
+    /// Whether the creator asked for incoming messages to be kept in the archive for later debugging.
+    pub archive: bool,
+    /// Whether the result should be kept in the recipients' history.
+    pub history: bool,
+    /// Messages as they were received, whether or not they could be parsed, oldest first.
+    pub raw_messages: VecDeque<RawMessage>,
+    raw_message_bytes: usize,
+}
+
+pub struct NewSession {
+    pub id: Uuid,
+    pub ingest_token: String,
+    pub viewer_token: String,
+}
+
+impl Session {
+    pub fn ingest_token_matches(&self, token: &str) -> bool {
+        tokens_match(token, &self.ingest_token)
+    }
+
+    /// Whether the token grants read-only access to the live graph, i.e. it belongs to an unexpired viewer.
+    pub fn viewer_token_matches(&self, token: &str) -> bool {
+        let now = Timestamp::now();
+        self.viewers
+            .iter()
+            .any(|viewer| viewer.is_valid_at(now) && tokens_match(token, &viewer.token))
+    }
+
+    pub fn add_viewer(
+        &mut self,
+        label: Option<String>,
+        expires: Option<Timestamp>,
+    ) -> Result<Viewer, Error> {
+        let now = Timestamp::now();
+        self.viewers.retain(|viewer| viewer.is_valid_at(now));
+        if self.viewers.len() >= MAX_VIEWERS {
+            return Err(Error::TooManyViewers(MAX_VIEWERS));
+        }
+        let viewer = Viewer::new(label, expires);
+        self.viewers.push(viewer.clone());
+        Ok(viewer)
+    }
+
+    /// Revokes a viewer token, disconnecting anyone currently watching with it.
+    pub fn revoke_viewer(&mut self, token: &str) -> Result<(), Error> {
+        let Some(index) = self
+            .viewers
+            .iter()
+            .position(|viewer| tokens_match(token, &viewer.token))
+        else {
+            return Err(Error::UnknownViewer);
+        };
+        let viewer = self.viewers.remove(index);
+        self.websockets.disconnect(&viewer.token);
+        Ok(())
+    }
+
+    /// Keeps a received message for operators to download, dropping the oldest once over MAX_RAW_MESSAGE_BYTES.
+    pub fn record_raw_message(
+        &mut self,
+        message: serde_json::Value,
+        size: usize,
+        schema_drift: Vec<String>,
+    ) -> &RawMessage {
+        self.raw_messages.push_back(RawMessage {
+            schema_drift,
+            ..RawMessage::new(Timestamp::now(), message, size)
+        });
+        self.raw_message_bytes += size;
+        while self.raw_message_bytes > MAX_RAW_MESSAGE_BYTES
+            && self.raw_messages.len() > 1
+            && let Some(dropped) = self.raw_messages.pop_front()
+        {
+            self.raw_message_bytes -= dropped.size;
+        }
+        self.raw_messages.back().unwrap()
+    }
+
+    /// Binds the session to the device on its first message, and rejects messages from any other device after that,
+    /// noting the last one rejected so it can be shown alongside the session.
+    pub fn check_device(&mut self, serial_number: u64) -> Result<(), Error> {
+        match *self.device_serial_number.get_or_insert(serial_number) {
+            expected if expected == serial_number => Ok(()),
+            expected => {
+                self.rejected_device = Some(RejectedDevice {
+                    serial_number,
+                    at: Timestamp::now(),
+                });
+                Err(Error::WrongDevice {
+                    expected,
+                    received: serial_number,
+                })
+            }
+        }
+    }
+
+    pub fn record_event(&mut self, event: Event) {
+        self.last_event = Some(LastEvent {
+            event,
+            at: Timestamp::now(),
+        });
+    }
+
+    fn to_persisted(&self, expires: Option<Timestamp>) -> PersistedSession {
+        PersistedSession {
+            id: self.id,
+            ingest_token: self.ingest_token.clone(),
+            created: self.created,
+            expires,
+            recipients: self
+                .recipients
+                .iter()
+                .map(|recipient| PersistedRecipient {
+                    email: recipient.email.clone(),
+                    verified: recipient.verified,
+                    verification_token: recipient.verification_token.clone(),
+                })
+                .collect(),
+            language: self.locale.language.clone(),
+            time_zone: self.locale.time_zone.iana_name().map(str::to_owned),
+            creator_ip: self.creator_ip,
+            viewers: self.viewers.clone(),
+            archive: self.archive,
+            history: self.history,
+            raw_messages: self.raw_messages.iter().cloned().collect(),
+            rejected_device: self.rejected_device.clone(),
+        }
+    }
+
+    /// Rebuilds a saved session, replaying its messages to recover the state of the test.
+    fn restore(persisted: PersistedSession, metrics: &Metrics, panels: &Panels) -> Session {
+        let mut session = Session {
+            state: State::started(),
+            created: persisted.created,
+            recipients: persisted
+                .recipients
+                .into_iter()
+                .map(|recipient| Recipient {
+                    email: recipient.email,
+                    verified: recipient.verified,
+                    verification_token: recipient.verification_token,
+                    delivery: DeliveryStatus::Pending,
+                })
+                .collect(),
+            locale: UserLocale::new(persisted.language, persisted.time_zone.as_deref()),
+            creator_ip: persisted.creator_ip,
+            id: persisted.id,
+            ingest_token: persisted.ingest_token,
+            span: info_span!(parent: None, "session", session_id = %persisted.id),
+            viewers: persisted.viewers,
+            websockets: SessionSockets::new(),
+            last_event: None,
+            device_serial_number: None,
+            rejected_device: persisted.rejected_device,
+            archive: persisted.archive,
+            history: persisted.history,
+            raw_messages: VecDeque::new(),
+            raw_message_bytes: 0,
+        };
+        for raw in persisted.raw_messages {
+            if let Ok((message, _)) = Message::parse(&raw.message, ParseMode::Lenient) {
+                session.last_event = Some(LastEvent {
+                    event: message.event,
+                    at: raw.timestamp,
+                });
+                session
+                    .device_serial_number
+                    .get_or_insert(message.device.serial_number);
+                let state = std::mem::replace(&mut session.state, State::started());
+                session.state = match state.update(message, &session.websockets, metrics, panels) {
+                    Ok(state) => state,
+                    Err(err) => err.get_state().cloned().unwrap_or_else(State::started),
+                };
+            }
+            let size = serde_json::to_vec(&raw.message).map_or(0, |bytes| bytes.len());
+            session.record_raw_message(raw.message, size, raw.schema_drift);
+        }
+        session
+    }
+
+    fn samples(&self) -> usize {
+        match &self.state {
+            State::IncompleteTest(test) => test.data.samples.len(),
+            State::CompletedTest(test) => test.samples,
+        }
+    }
+
+    pub fn summary(&self, now: Timestamp) -> SessionSummary {
+        SessionSummary {
+            id: self.id,
+            created: self.created,
+            age_seconds: now.duration_since(self.created).as_secs(),
+            state: match &self.state {
+                State::IncompleteTest(test) => StateSummary::Incomplete {
+                    samples: test.data.samples.len(),
+                },
+                State::CompletedTest(test) => StateSummary::Completed {
+                    overall: test.overall,
+                    completed: test.completed,
+                },
+            },
+            recipients: self.recipients.len(),
+            connected_viewers: self.websockets.count(),
+            last_event: self.last_event.clone(),
+            raw_messages: self.raw_messages.len(),
+            archived: self.archive,
+            device_serial_number: self.device_serial_number,
+            rejected_device: self.rejected_device.clone(),
+        }
+    }
+
+    /// A session only accepts data once at least one recipient has confirmed their address.
+    pub fn is_active(&self) -> bool {
+        self.recipients.iter().any(|recipient| recipient.verified)
+    }
+
+    /// The addresses we may send results to, i.e. those which have been verified (or didn't need to be).
+    pub fn recipient_emails(&self) -> Vec<EmailAddress> {
+        self.recipients
+            .iter()
+            .filter(|recipient| recipient.verified)
+            .map(|recipient| recipient.email.clone())
+            .collect()
+    }
+
+    pub fn has_recipient(&self, email: &EmailAddress) -> bool {
+        self.recipients
+            .iter()
+            .any(|recipient| &recipient.email == email)
+    }
+
+    fn recipient_mut(&mut self, email: &EmailAddress) -> Option<&mut Recipient> {
+        self.recipients
+            .iter_mut()
+            .find(|recipient| &recipient.email == email)
+    }
+
+    pub fn remove_recipient(&mut self, email: &EmailAddress) -> Result<(), Error> {
+        if self.recipient_mut(email).is_none() {
+            return Err(Error::UnknownRecipient);
+        }
+        if self.recipients.len() == 1 {
+            return Err(Error::LastRecipient);
+        }
+        self.recipients
+            .retain(|recipient| &recipient.email != email);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Recipient {
+    pub email: EmailAddress,
+    pub verified: bool,
+    #[serde(skip)]
+    pub verification_token: Option<String>,
+    pub delivery: DeliveryStatus,
+}
+
+impl Recipient {
+    fn verified(email: EmailAddress) -> Recipient {
+        Recipient {
+            email,
+            verified: true,
+            verification_token: None,
+            delivery: DeliveryStatus::Pending,
+        }
+    }
+
+    fn unverified(email: EmailAddress) -> Recipient {
+        Recipient {
+            email,
+            verified: false,
+            verification_token: Some(random_token()),
+            delivery: DeliveryStatus::Pending,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LastEvent {
+    pub event: Event,
+    pub at: Timestamp,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RejectedDevice {
+    pub serial_number: u64,
+    pub at: Timestamp,
+}
+
+/// Serialized in the same shape as [`crate::LogWrapper`], so downloaded messages can be replayed like /dump output.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RawMessage {
+    pub timestamp: Timestamp,
+    pub message: serde_json::Value,
+    /// Anything in the message which this server doesn't understand, see SchemaDrift::describe.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schema_drift: Vec<String>,
+    #[serde(skip)]
+    size: usize,
+}
+
+impl RawMessage {
+    pub fn new(timestamp: Timestamp, message: serde_json::Value, size: usize) -> RawMessage {
+        RawMessage {
+            timestamp,
+            message,
+            schema_drift: Vec::new(),
+            size,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionSummary {
+    pub id: Uuid,
+    pub created: Timestamp,
+    pub age_seconds: i64,
+    pub state: StateSummary,
+    pub recipients: usize,
+    pub connected_viewers: usize,
+    pub last_event: Option<LastEvent>,
+    pub raw_messages: usize,
+    pub archived: bool,
+    pub device_serial_number: Option<u64>,
+    pub rejected_device: Option<RejectedDevice>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum StateSummary {
+    Incomplete {
+        samples: usize,
+    },
+    Completed {
+        overall: DetectionResult,
+        completed: Timestamp,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Viewer {
+    pub token: String,
+    pub label: Option<String>,
+    pub created: Timestamp,
+    pub expires: Option<Timestamp>,
+}
+
+impl Viewer {
+    fn new(label: Option<String>, expires: Option<Timestamp>) -> Viewer {
+        Viewer {
+            token: random_token(),
+            label,
+            created: Timestamp::now(),
+            expires,
+        }
+    }
+
+    fn is_valid_at(&self, now: Timestamp) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Sent { at: Timestamp, message_id: String },
+    Failed { at: Timestamp, error: String },
+}
+
+/// Controls the /dump debugging endpoint, which prints whatever is posted to it to stdout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpMode {
+    Open,
+    Admin,
+    Disabled,
+}
+
+impl FromStr for DumpMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(DumpMode::Open),
+            "admin" => Ok(DumpMode::Admin),
+            "disabled" => Ok(DumpMode::Disabled),
+            _ => Err(format!(
+                "Expected one of 'open', 'admin' or 'disabled' but was '{}'",
+                s
+            )),
+        }
+    }
+}
+
+/// Parses a comma or whitespace separated list of addresses, as submitted by an `<input type="email" multiple>`.
+pub fn parse_recipients(list: &str) -> Result<Vec<EmailAddress>, Error> {
+    let mut recipients: Vec<EmailAddress> = Vec::new();
+    for address in list
+        .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+        .filter(|address| !address.is_empty())
+    {
+        let address = EmailAddress::from_str(address)?;
+        if !recipients.contains(&address) {
+            recipients.push(address);
+        }
+    }
+    if recipients.is_empty() {
+        return Err(Error::NoRecipients);
+    }
+    if recipients.len() > MAX_RECIPIENTS {
+        return Err(Error::TooManyRecipients(MAX_RECIPIENTS));
+    }
+    Ok(recipients)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ServerState, parse_recipients};
+    use crate::config::{Config, Settings};
+
+    #[test]
+    fn parses_recipient_lists() {
+        let recipients =
+            parse_recipients("a@example.com, b@example.com\nc@example.com,a@example.com").unwrap();
+        assert_eq!(
+            vec!["a@example.com", "b@example.com", "c@example.com"],
+            recipients
+                .iter()
+                .map(|recipient| recipient.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(parse_recipients(" , ").is_err());
+        assert!(parse_recipients("a@example.com, not-an-address").is_err());
+    }
+
+    #[test]
+    fn checking_the_config_leaves_the_filesystem_alone() {
+        let dir = std::env::temp_dir().join(format!("check-config-{}", uuid::Uuid::new_v4()));
+        let config = Config::from_settings(Settings {
+            base_url: Some("https://pluslife.example.com".to_owned()),
+            sender_email: Some("results@example.com".to_owned()),
+            mailgun_domain: Some("example.com".to_owned()),
+            mailgun_api_key: Some("key".to_owned()),
+            port: Some("8000".to_owned()),
+            cleanup_period: Some("2h".to_owned()),
+            archive_dir: Some(dir.join("archive").display().to_string()),
+            history_file: Some(dir.join("history.json").display().to_string()),
+            device_registry_file: Some(dir.join("devices.json").display().to_string()),
+            ..Settings::default()
+        })
+        .ok()
+        .unwrap();
+        ServerState::check_config(&config).ok().unwrap();
+        assert!(!dir.exists());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("history.json"), "not json").unwrap();
+        let errors = ServerState::check_config(&config).err().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(1, errors.len());
+    }
 }
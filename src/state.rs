@@ -1,6 +1,11 @@
+use jiff::Timestamp;
+
 use crate::{
     Error,
     messages::{DetectionResult, Event, Message, SubgroupResult, TestData, TestResult},
+    metrics::Metrics,
+    panels::{Panel, Panels},
+    qc::{self, Outcome, Report},
     websockets::SessionSockets,
 };
 
@@ -15,14 +20,26 @@ impl State {
         State::IncompleteTest(IncompleteTest::new(TestData::empty()))
     }
 
-    pub fn update(self, message: Message, websockets: &SessionSockets) -> Result<State, Error> {
+    pub fn update(
+        self,
+        message: Message,
+        websockets: &SessionSockets,
+        metrics: &Metrics,
+        panels: &Panels,
+    ) -> Result<State, Error> {
         match self {
             State::IncompleteTest(incomplete_test) => match message.event {
                 Event::TestFinished => {
                     if let Some(result) = message.test.result {
-                        let completed_test = incomplete_test.complete(result, message.test.data)?;
+                        let completed_test = incomplete_test.complete(
+                            result,
+                            message.test.data,
+                            message.device.serial_number,
+                            metrics,
+                            panels,
+                        )?;
                         let new_state = State::CompletedTest(completed_test);
-                        websockets.notify(&new_state);
+                        websockets.notify(&new_state, metrics);
                         Ok(new_state)
                     } else {
                         Err(Error::TestFinishedMissingResult)
@@ -30,39 +47,44 @@ impl State {
                 }
                 Event::NewData => {
                     let new_state = State::incomplete(message.test.data);
-                    websockets.notify(&new_state);
+                    websockets.notify(&new_state, metrics);
                     Ok(new_state)
                 }
                 Event::DeviceReady => Ok(State::incomplete(message.test.data)),
                 Event::TestStarted => Ok(State::incomplete(message.test.data)),
+                // Newer events are recorded as schema drift, and otherwise ignored.
+                Event::Unknown => Ok(State::IncompleteTest(incomplete_test)),
                 Event::AlreadyTesting | Event::ContinueTest => Err(Error::UnexpectedMessage(
-                    State::IncompleteTest(incomplete_test),
+                    Box::new(State::IncompleteTest(incomplete_test)),
                     Box::new(message),
                 )),
             },
             State::CompletedTest(completed_test) => Err(Error::UnexpectedMessage(
-                State::CompletedTest(completed_test),
+                Box::new(State::CompletedTest(completed_test)),
                 Box::new(message),
             )),
         }
     }
 
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::IncompleteTest(_) => "incomplete",
+            State::CompletedTest(_) => "completed",
+        }
+    }
+
     fn incomplete(data: TestData) -> State {
         State::IncompleteTest(IncompleteTest::new(data))
     }
 
-    pub fn current_graph_png(&self) -> Result<Option<Vec<u8>>, Error> {
+    pub fn current_graph_png(&self, metrics: &Metrics) -> Result<Option<Vec<u8>>, Error> {
         match self {
             State::IncompleteTest(test) => {
                 if test.data.samples.is_empty() {
                     Ok(None)
                 } else {
-                    Ok(Some(
-                        test.data
-                            .to_graph()?
-                            .normalise_values_to_zero()
-                            .plot_to_buffer()?,
-                    ))
+                    let graph = test.data.to_graph(None)?.normalise_values_to_zero();
+                    Ok(Some(metrics.time_graph_render(|| graph.plot_to_buffer())?))
                 }
             }
             State::CompletedTest(test) => Ok(Some(test.graph_png.clone())),
@@ -80,14 +102,30 @@ impl IncompleteTest {
         IncompleteTest { data }
     }
 
-    pub fn complete(self, result: TestResult, data: TestData) -> Result<CompletedTest, Error> {
+    pub fn complete(
+        self,
+        result: TestResult,
+        data: TestData,
+        serial_number: u64,
+        metrics: &Metrics,
+        panels: &Panels,
+    ) -> Result<CompletedTest, Error> {
+        let panel = panels.find(&result).cloned().map(Box::new);
+        let graph = data.to_graph(panel.as_deref())?.normalise_values_to_zero();
+        let limits = panel
+            .as_ref()
+            .map(|panel| panel.qc.clone())
+            .unwrap_or_default();
+        let qc = qc::check(&result, &data, &limits);
         Ok(CompletedTest {
-            overall: result.detection_result,
+            qc,
+            samples: data.samples.len(),
+            overall: result.overall,
             subgroup_results: result.subgroup_results,
-            graph_png: data
-                .to_graph()?
-                .normalise_values_to_zero()
-                .plot_to_buffer()?,
+            completed: Timestamp::now(),
+            serial_number,
+            panel,
+            graph_png: metrics.time_graph_render(|| graph.plot_to_buffer())?,
         })
     }
 }
@@ -96,5 +134,20 @@ impl IncompleteTest {
 pub struct CompletedTest {
     pub overall: DetectionResult,
     pub subgroup_results: Vec<SubgroupResult>,
+    pub completed: Timestamp,
+    /// The serial number of the device which ran the test.
+    pub serial_number: u64,
+    /// How many samples the device sent over the whole run.
+    pub samples: usize,
+    /// What the test was for, if it matched a known panel.
+    pub panel: Option<Box<Panel>>,
     pub graph_png: Vec<u8>,
+    pub qc: Report,
+}
+
+impl CompletedTest {
+    /// Whether the user should be told to retest rather than given the result.
+    pub fn needs_retest(&self) -> bool {
+        self.overall == DetectionResult::Invalid || self.qc.outcome() == Outcome::Fail
+    }
 }
@@ -0,0 +1,152 @@
+use std::str::FromStr;
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing::error;
+use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::Error;
+
+// Logs always go to stderr, either human-readable or as one JSON object per line for log shippers.
+// When an OTLP endpoint is configured, spans are additionally batched up and sent to that collector over OTLP/HTTP.
+
+const SERVICE_NAME: &str = "pluslife-notifier";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Expected one of 'pretty' or 'json' but was '{}'",
+                s
+            )),
+        }
+    }
+}
+
+pub struct TelemetryConfig {
+    /// A `RUST_LOG`-style filter, e.g. "info,pluslife_notifier=debug".
+    pub filter: String,
+    pub format: LogFormat,
+    /// The base URL of an OTLP/HTTP collector, e.g. "http://localhost:4318".
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Keeps the span exporter alive. Call [`Telemetry::shutdown`] before exiting so buffered spans are flushed.
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    pub fn init(config: &TelemetryConfig) -> Result<Telemetry, Error> {
+        let filter = EnvFilter::try_new(&config.filter).map_err(|err| Error::InvalidConfig {
+            name: "log_filter".to_owned(),
+            cause: Box::new(err),
+        })?;
+        let fmt_layer = match config.format {
+            LogFormat::Pretty => tracing_subscriber::fmt::layer()
+                .pretty()
+                .with_writer(std::io::stderr)
+                .boxed(),
+            LogFormat::Json => tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_writer(std::io::stderr)
+                .boxed(),
+        };
+        let tracer_provider = config
+            .otlp_endpoint
+            .as_deref()
+            .map(otlp_tracer_provider)
+            .transpose()?;
+        let otel_layer = tracer_provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME))
+        });
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(fmt_layer)
+            .with(otel_layer)
+            .try_init()?;
+        Ok(Telemetry { tracer_provider })
+    }
+
+    pub fn shutdown(self) {
+        if let Some(tracer_provider) = self.tracer_provider
+            && let Err(err) = tracer_provider.shutdown()
+        {
+            error!(?err, "Error flushing spans");
+        }
+    }
+}
+
+pub fn otlp_tracer_provider(endpoint: &str) -> Result<SdkTracerProvider, Error> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{Router, body::Bytes, routing::post};
+    use opentelemetry::trace::TracerProvider as _;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::otlp_tracer_provider;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_session_spans_to_collector() {
+        // A stand-in for an OTLP collector, which just keeps whatever is posted to it.
+        let received: Arc<Mutex<Vec<Bytes>>> = Arc::default();
+        let collector = Router::new().route(
+            "/v1/traces",
+            post({
+                let received = received.clone();
+                move |body: Bytes| async move {
+                    received.lock().unwrap().push(body);
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, collector).await });
+
+        // The exporter's blocking HTTP client must not be created or driven from an async context.
+        tokio::task::spawn_blocking(move || {
+            let provider = otlp_tracer_provider(&endpoint).unwrap();
+            let subscriber = tracing_subscriber::registry()
+                .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+            tracing::subscriber::with_default(subscriber, || {
+                let session = info_span!("session", session_id = "test-session-id");
+                info_span!(parent: &session, "ingest").in_scope(|| {});
+            });
+            provider.shutdown().unwrap();
+        })
+        .await
+        .unwrap();
+
+        let received = received.lock().unwrap();
+        let body: Vec<u8> = received.iter().flatten().copied().collect();
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"test-session-id"));
+        assert!(contains(b"ingest"));
+    }
+}
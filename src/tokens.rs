@@ -0,0 +1,12 @@
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// Generates an unguessable URL-safe token, using the operating system's secure random number generator via v4 UUIDs.
+pub fn random_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Compares a presented secret against the expected one without leaking how much of it matched through timing.
+pub fn tokens_match(presented: &str, expected: &str) -> bool {
+    presented.as_bytes().ct_eq(expected.as_bytes()).into()
+}
@@ -0,0 +1,37 @@
+use std::{collections::HashMap, time::Duration};
+
+use email_address::EmailAddress;
+use jiff::Timestamp;
+
+// When double opt-in is enabled, addresses must confirm they want our emails before a session goes live.
+// Addresses which have confirmed are remembered for a while, so someone running several tests doesn't have to
+// confirm every time.
+
+#[derive(Default)]
+pub struct VerifiedEmails {
+    verified_until: HashMap<EmailAddress, Timestamp>,
+}
+
+impl VerifiedEmails {
+    pub fn is_verified(&self, email: &EmailAddress) -> bool {
+        self.verified_until
+            .get(email)
+            .is_some_and(|until| *until > Timestamp::now())
+    }
+
+    pub fn remember(&mut self, email: EmailAddress, period: Duration) {
+        if period.is_zero() {
+            return;
+        }
+        let until = Timestamp::now()
+            .checked_add(period)
+            .unwrap_or(Timestamp::MAX);
+        self.verified_until.insert(email, until);
+        self.prune();
+    }
+
+    fn prune(&mut self) {
+        let now = Timestamp::now();
+        self.verified_until.retain(|_, until| *until > now);
+    }
+}
@@ -1,13 +1,15 @@
 use std::sync::{Arc, Mutex};
 
-use axum::extract::ws::{Message, Utf8Bytes, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, Utf8Bytes, WebSocket, close_code};
 use base64::{Engine, prelude::BASE64_STANDARD};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tracing::error;
 
 use crate::{
     Error,
     messages::{DetectionResult, SubgroupResult},
+    metrics::Metrics,
+    panels::Panel,
     state::State,
 };
 
@@ -27,10 +29,10 @@ impl SessionSockets {
         }
     }
 
-    pub fn notify(&self, state: &State) {
+    pub fn notify(&self, state: &State, metrics: &Metrics) {
         let websockets = self.websockets.lock().unwrap().clone();
         if !websockets.is_empty() {
-            let maybe_message_json = WebsocketMessage::try_from(state)
+            let maybe_message_json = WebsocketMessage::new(state, metrics)
                 .and_then(|message| serde_json::to_string(&message).map_err(Into::into));
             match maybe_message_json {
                 Ok(message) => {
@@ -45,28 +47,61 @@ impl SessionSockets {
         }
     }
 
-    pub fn push(&self, websocket: WebSocket) -> (SessionSocket, usize) {
+    pub fn count(&self) -> usize {
+        self.websockets.lock().unwrap().len()
+    }
+
+    pub fn push(&self, websocket: WebSocket, viewer_token: String) -> (SessionSocket, usize) {
         let mut websockets = self.websockets.lock().unwrap();
-        let websocket = SessionSocket::new(websocket);
+        let websocket = SessionSocket::new(websocket, viewer_token);
         websockets.push(websocket.clone());
         (websocket, websockets.len())
     }
+
+    /// Tells every viewer the server is going away, waiting until each has been sent a close frame.
+    pub async fn close_all(&self) {
+        let websockets: Vec<SessionSocket> = self.websockets.lock().unwrap().drain(..).collect();
+        for websocket in websockets {
+            let frame = CloseFrame {
+                code: close_code::AWAY,
+                reason: Utf8Bytes::from_static("Server shutting down"),
+            };
+            let _ = websocket
+                .socket
+                .lock()
+                .await
+                .send(Message::Close(Some(frame)))
+                .await;
+        }
+    }
+
+    /// Closes and forgets every websocket which was opened with this viewer token.
+    pub fn disconnect(&self, viewer_token: &str) {
+        let mut websockets = self.websockets.lock().unwrap();
+        for websocket in
+            websockets.extract_if(.., |websocket| websocket.viewer_token == viewer_token)
+        {
+            websocket.close();
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct SessionSocket {
     socket: Arc<tokio::sync::Mutex<WebSocket>>,
+    viewer_token: String,
 }
 
 impl SessionSocket {
-    fn new(websocket: WebSocket) -> SessionSocket {
+    fn new(websocket: WebSocket, viewer_token: String) -> SessionSocket {
         SessionSocket {
             socket: Arc::new(tokio::sync::Mutex::new(websocket)),
+            viewer_token,
         }
     }
 
-    pub fn notify(&self, state: &State) {
-        let maybe_message_json = WebsocketMessage::try_from(state)
+    pub fn notify(&self, state: &State, metrics: &Metrics) {
+        let maybe_message_json = WebsocketMessage::new(state, metrics)
             .and_then(|message| serde_json::to_string(&message).map_err(Into::into));
         match maybe_message_json {
             Ok(message) => {
@@ -91,51 +126,45 @@ impl SessionSocket {
             }
         });
     }
-}
 
-#[derive(Serialize)]
-struct WebsocketMessage {
-    graph_png_base64: Option<String>,
-    results: Option<Results>,
+    fn close(&self) {
+        let socket = self.socket.clone();
+        tokio::spawn(async move {
+            let _ = socket.lock().await.send(Message::Close(None)).await;
+        });
+    }
 }
 
-#[derive(Serialize)]
-struct Results {
-    overall: DetectionResult,
-    subgroup_results: Vec<SubgroupResult>,
+/// Sent to live graph viewers with the session's state whenever it changes.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WebsocketMessage {
+    pub graph_png_base64: Option<String>,
+    pub results: Option<Results>,
 }
 
-impl TryFrom<&State> for WebsocketMessage {
-    type Error = Error;
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Results {
+    pub overall: DetectionResult,
+    pub subgroup_results: Vec<SubgroupResult>,
+    /// Sent with every language's wording, as the page picks its own.
+    pub panel: Option<Box<Panel>>,
+}
 
-    fn try_from(state: &State) -> Result<Self, Self::Error> {
+impl WebsocketMessage {
+    fn new(state: &State, metrics: &Metrics) -> Result<WebsocketMessage, Error> {
         match state {
-            State::IncompleteTest(incomplete_test) => {
-                if incomplete_test.data.samples.is_empty() {
-                    Ok(WebsocketMessage {
-                        graph_png_base64: None,
-                        results: None,
-                    })
-                } else {
-                    Ok(WebsocketMessage {
-                        graph_png_base64: Some(
-                            BASE64_STANDARD.encode(
-                                &incomplete_test
-                                    .data
-                                    .to_graph()?
-                                    .normalise_values_to_zero()
-                                    .plot_to_buffer()?,
-                            ),
-                        ),
-                        results: None,
-                    })
-                }
-            }
+            State::IncompleteTest(_) => Ok(WebsocketMessage {
+                graph_png_base64: state
+                    .current_graph_png(metrics)?
+                    .map(|png| BASE64_STANDARD.encode(png)),
+                results: None,
+            }),
             State::CompletedTest(completed_test) => Ok(WebsocketMessage {
                 graph_png_base64: Some(BASE64_STANDARD.encode(&completed_test.graph_png)),
                 results: Some(Results {
                     overall: completed_test.overall,
                     subgroup_results: completed_test.subgroup_results.clone(),
+                    panel: completed_test.panel.clone(),
                 }),
             }),
         }